@@ -0,0 +1,80 @@
+//! Validation cost on large outputs, the hot path when batches or best_of
+//! validate many candidates. Run with `cargo bench --bench validate`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::{json, Value};
+use shape_runner::shape::{feature_design_output_typedef, formation_output_typedef};
+use shape_runner::types::{validate, validate_first};
+
+/// A FormationOutput with `n` coordinates.
+fn formation(n: usize) -> Value {
+    let coordinates: Vec<Value> = (0..n)
+        .map(|i| json!({ "x": (i % 100) as f64, "y": (i / 100) as f64 }))
+        .collect();
+    json!({ "coordinates": coordinates })
+}
+
+/// A FeatureDesignOutput with `n` components, each depending on the previous
+/// two.
+fn feature_design(n: usize) -> Value {
+    let components: Vec<Value> = (0..n)
+        .map(|i| {
+            let depends_on: Vec<String> = (i.saturating_sub(2)..i).map(|d| format!("component-{d}")).collect();
+            json!({
+                "id": format!("component-{i}"),
+                "responsibility": "Handles one part of the feature.",
+                "api": "fn handle(request: Request) -> Response",
+                "depends_on": depends_on,
+            })
+        })
+        .collect();
+    json!({
+        "name": "Large design",
+        "rationale": "Synthetic output for benchmarking.",
+        "components": components,
+        "risks": ["Too many components", "Deep dependency chains"],
+    })
+}
+
+fn bench_formation(c: &mut Criterion) {
+    let schema = formation_output_typedef();
+    let mut group = c.benchmark_group("validate/formation");
+    for n in [1_000, 10_000] {
+        let value = formation(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &value, |b, value| {
+            b.iter(|| validate(black_box(&schema), black_box(value)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_feature_design(c: &mut Criterion) {
+    let schema = feature_design_output_typedef();
+    let mut group = c.benchmark_group("validate/feature_design");
+    for n in [100, 1_000] {
+        let value = feature_design(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &value, |b, value| {
+            b.iter(|| validate(black_box(&schema), black_box(value)))
+        });
+    }
+    group.finish();
+}
+
+/// The retry path: every coordinate has a string `y`, so every element
+/// reports an error.
+fn bench_formation_errors(c: &mut Criterion) {
+    let schema = formation_output_typedef();
+    let coordinates: Vec<Value> = (0..10_000).map(|i| json!({ "x": i as f64, "y": "high" })).collect();
+    let value = json!({ "coordinates": coordinates });
+    c.bench_function("validate/formation_all_invalid/10000", |b| {
+        b.iter(|| validate(black_box(&schema), black_box(&value)))
+    });
+    c.bench_function("validate_first/formation_all_invalid/10000", |b| {
+        b.iter(|| validate_first(black_box(&schema), black_box(&value)))
+    });
+}
+
+criterion_group!(benches, bench_formation, bench_feature_design, bench_formation_errors);
+criterion_main!(benches);
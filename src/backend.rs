@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+/// Something that turns a prompt into raw model output.
+///
+/// `LlmClient` talks to Ollama, OpenAI-compatible servers and the mock server
+/// over HTTP itself; an `LlmBackend` set with `LlmClient::with_llm_backend`
+/// replaces those calls, e.g. with `MockLlmBackend` in tests. Retries,
+/// validation and feedback prompts work the same either way.
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    async fn complete(&self, prompt: &str) -> Result<String>;
+}
+
+/// In-process backend that returns scripted responses in order, for driving
+/// the retry loop deterministically without a server.
+///
+/// Each call takes the next response; once the script runs out, calls fail.
+/// The prompts received are kept so tests can check the retry feedback.
+#[derive(Debug, Default)]
+pub struct MockLlmBackend {
+    responses: Mutex<VecDeque<String>>,
+    prompts: Mutex<Vec<String>>,
+}
+
+impl MockLlmBackend {
+    pub fn new<I>(responses: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Self {
+            responses: Mutex::new(responses.into_iter().map(Into::into).collect()),
+            prompts: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Every prompt received so far, in order.
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Scripted responses not used yet.
+    pub fn remaining(&self) -> usize {
+        self.responses.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+#[async_trait]
+impl LlmBackend for MockLlmBackend {
+    async fn complete(&self, prompt: &str) -> Result<String> {
+        self.prompts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(prompt.to_string());
+        self.responses
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front()
+            .ok_or_else(|| anyhow!("MockLlmBackend has no scripted responses left"))
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 
@@ -13,23 +14,184 @@ struct LlmResponse {
     output: String,
 }
 
+// Idle connections that never send a request are dropped after this long
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The FeatureDesign answer: the shape's example output, so end-to-end tests
+// exercise the same design the typedef self-check covers
+const FEATURE_DESIGN: &str = include_str!("../../examples/feature-design-output.json");
+
+// The RiskAssessment answer, which scores exactly the risks of FEATURE_DESIGN,
+// so a FeatureDesign -> RiskAssessment pipeline passes end to end
+const RISK_ASSESSMENT: &str = include_str!("../../examples/risk-assessment-output.json");
+
+/// When the mock answers with invalid JSON (`MOCK_LLM_MODE`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    /// Valid JSON on every attempt
+    AlwaysOk,
+    /// Invalid JSON on every attempt
+    AlwaysFail,
+    /// Invalid JSON for the first `MOCK_LLM_FAIL_ATTEMPTS` attempts
+    FailN,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "always_ok" => Ok(Mode::AlwaysOk),
+            "always_fail" => Ok(Mode::AlwaysFail),
+            "fail_n" => Ok(Mode::FailN),
+            _ => Err(format!("unknown MOCK_LLM_MODE: {s} (expected always_ok, always_fail or fail_n)")),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     attempt_count: Arc<std::sync::atomic::AtomicUsize>,
     fail_attempts: usize,
+    mode: Mode,
+}
+
+/// Body of `GET /stats` and `POST /reset`.
+#[derive(Serialize)]
+struct Stats {
+    /// `/llm` requests since startup or the last reset
+    attempts: usize,
+    /// Attempts answered with invalid JSON before the valid response
+    fail_attempts: usize,
+    mode: Mode,
+}
+
+impl AppState {
+    fn stats(&self) -> Stats {
+        Stats {
+            attempts: self.attempt_count.load(std::sync::atomic::Ordering::SeqCst),
+            fail_attempts: self.fail_attempts,
+            mode: self.mode,
+        }
+    }
+}
+
+/// Write a complete JSON response and close the connection.
+async fn write_json(stream: &mut tokio::net::TcpStream, body: &impl Serialize) {
+    let body = serde_json::to_string(body).unwrap_or_default();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Whether the request headers declare `Transfer-Encoding: chunked`.
+fn is_chunked(data: &[u8]) -> bool {
+    let head_end = find(data, b"\r\n\r\n").unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..head_end]).lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    })
+}
+
+/// Decode the chunked body following the headers, or `None` while it is
+/// still incomplete. Chunk extensions and trailers are ignored.
+fn chunked_body(data: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = &data[find(data, b"\r\n\r\n")? + 4..];
+    let mut body = Vec::new();
+    loop {
+        let line_end = find(rest, b"\r\n")?;
+        let size_line = String::from_utf8_lossy(&rest[..line_end]);
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        // A size too large to add the CRLF to can never be complete
+        let chunk_end = size.checked_add(2)?;
+        rest = &rest[line_end + 2..];
+        if rest.len() < chunk_end {
+            return None;
+        }
+        body.extend_from_slice(&rest[..size]);
+        rest = &rest[chunk_end..];
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// For a Formation prompt, a circle with as many coordinates as the prompt's
+/// "Number of units" asks for; None for other prompts.
+fn formation_output(prompt: &str) -> Option<String> {
+    let units: usize = prompt
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("- Number of units:"))?
+        .trim()
+        .parse()
+        .ok()?;
+    let coordinates: Vec<serde_json::Value> = (0..units)
+        .map(|i| {
+            let angle = std::f64::consts::TAU * i as f64 / units as f64;
+            let x = (50.0 + 40.0 * angle.cos()).round();
+            let y = (50.0 + 40.0 * angle.sin()).round();
+            serde_json::json!({ "x": x, "y": y })
+        })
+        .collect();
+    Some(serde_json::json!({ "coordinates": coordinates }).to_string())
 }
 
 async fn handle_connection(mut stream: tokio::net::TcpStream, state: AppState) {
     let mut buffer = [0u8; 65536];
     
-    match stream.read(&mut buffer).await {
+    let read = match tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buffer)).await {
+        Ok(read) => read,
+        Err(_) => {
+            println!("No request received within {:?}, closing connection", READ_TIMEOUT);
+            let _ = stream.shutdown().await;
+            return;
+        }
+    };
+
+    match read {
         Ok(n) if n > 0 => {
-            let request = String::from_utf8_lossy(&buffer[..n]);
+            let mut data = buffer[..n].to_vec();
+            // A chunked body has no length up front and may span several reads:
+            // keep reading until the terminating zero-size chunk arrives
+            let chunked = is_chunked(&data);
+            while chunked && chunked_body(&data).is_none() {
+                match tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buffer)).await {
+                    Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buffer[..n]),
+                    _ => break,
+                }
+            }
+            let request = String::from_utf8_lossy(&data);
             
             // Debug: print first line of request
             let first_line = request.lines().next().unwrap_or("");
             println!("Received request: {}", first_line);
             
+            // Control endpoints for tests that reuse one mock across cases
+            let mut request_line = first_line.split_whitespace();
+            match (request_line.next(), request_line.next()) {
+                (Some("GET"), Some("/stats")) => {
+                    write_json(&mut stream, &state.stats()).await;
+                    return;
+                }
+                (Some("POST"), Some("/reset")) => {
+                    state.attempt_count.store(0, std::sync::atomic::Ordering::SeqCst);
+                    println!("Attempt counter reset");
+                    write_json(&mut stream, &state.stats()).await;
+                    return;
+                }
+                _ => {}
+            }
+
             // Simple HTTP/1.1 request parsing - be more lenient
             if !request.contains("POST") || !request.contains("/llm") {
                 println!("Not a POST to /llm, returning 404");
@@ -54,8 +216,15 @@ async fn handle_connection(mut stream: tokio::net::TcpStream, state: AppState) {
                 }
             }
             
-            // Extract body - use Content-Length if available, otherwise use everything after headers
-            let body = if content_length > 0 && body_str.len() >= content_length {
+            // Extract body - decode chunks, else use Content-Length if available,
+            // otherwise use everything after headers
+            let decoded;
+            let body = if chunked {
+                decoded = chunked_body(&data)
+                    .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                    .unwrap_or_default();
+                decoded.as_str()
+            } else if content_length > 0 && body_str.len() >= content_length {
                 &body_str[..content_length]
             } else {
                 body_str.trim_end_matches('\0').trim()
@@ -86,50 +255,25 @@ async fn handle_connection(mut stream: tokio::net::TcpStream, state: AppState) {
             println!("Mock LLM: Received request (attempt {})", attempt);
             println!("Prompt preview: {}...", &req.prompt[..req.prompt.len().min(200)]);
 
-            // Fail first N attempts to test retry logic
-            let output = if attempt <= state.fail_attempts {
+            // Fail first N attempts (or all, or none) to test retry logic
+            let fail = match state.mode {
+                Mode::AlwaysOk => false,
+                Mode::AlwaysFail => true,
+                Mode::FailN => attempt <= state.fail_attempts,
+            };
+            let formation = formation_output(&req.prompt);
+            let output = if fail {
                 println!("Mock LLM: Returning invalid JSON (testing retry logic)");
                 r#"{"invalid": "json", missing_fields: true}"#
+            } else if let Some(formation) = &formation {
+                println!("Mock LLM: Returning valid Formation JSON");
+                formation
+            } else if req.prompt.contains("Task: Score the risks of the feature design") {
+                println!("Mock LLM: Returning valid RiskAssessment JSON");
+                RISK_ASSESSMENT
             } else {
                 println!("Mock LLM: Returning valid JSON");
-                r#"{
-        "name": "Task Management & Collaboration System",
-        "rationale": "A comprehensive system for managing tasks and projects with real-time collaboration. Uses PostgreSQL for reliable data persistence, WebSockets for instant updates, and a RESTful API for clean integration. The responsive frontend ensures mobile compatibility, while authentication secures all operations.",
-        "components": [
-            {
-                "id": "task-service",
-                "responsibility": "Core task CRUD operations, task assignment, and status management",
-                "api": "POST /api/tasks - Create task\nGET /api/tasks - List tasks\nGET /api/tasks/:id - Get task\nPUT /api/tasks/:id - Update task\nDELETE /api/tasks/:id - Delete task"
-            },
-            {
-                "id": "project-service",
-                "responsibility": "Project management, project membership, and project-level settings",
-                "api": "POST /api/projects - Create project\nGET /api/projects - List projects\nGET /api/projects/:id - Get project\nPUT /api/projects/:id - Update project"
-            },
-            {
-                "id": "websocket-service",
-                "responsibility": "Real-time updates for task changes, project updates, and collaboration events",
-                "api": "WS /ws - WebSocket connection\nMessages: {type: 'task_updated', data: {...}}\n{type: 'project_updated', data: {...}}"
-            },
-            {
-                "id": "auth-service",
-                "responsibility": "User authentication, authorization, and session management",
-                "api": "POST /api/auth/login - Login\nPOST /api/auth/register - Register\nPOST /api/auth/logout - Logout\nGET /api/auth/me - Get current user"
-            },
-            {
-                "id": "postgres-db",
-                "responsibility": "Data persistence for tasks, projects, users, and relationships",
-                "api": "Database schema:\n- users(id, email, password_hash, name)\n- projects(id, name, owner_id, created_at)\n- tasks(id, project_id, title, description, status, assignee_id, created_at)\n- project_members(project_id, user_id, role)"
-            }
-        ],
-        "risks": [
-            "WebSocket connections need proper scaling strategy (consider Redis pub/sub for multi-server)",
-            "PostgreSQL connection pooling required for high concurrency",
-            "Real-time updates may overwhelm mobile clients - implement rate limiting",
-            "Authentication tokens must have proper expiration and refresh mechanism",
-            "Task assignment conflicts when multiple users assign simultaneously"
-        ]
-    }"#
+                FEATURE_DESIGN
             };
             
             // The output is already a JSON string, wrap it in LlmResponse
@@ -171,17 +315,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .parse::<usize>()
         .unwrap_or(1);
 
+    let mode = match std::env::var("MOCK_LLM_MODE") {
+        Ok(mode) => mode.parse::<Mode>()?,
+        Err(_) => Mode::FailN,
+    };
+
     let state = AppState {
         attempt_count: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
         fail_attempts,
+        mode,
     };
 
     let addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&addr).await?;
 
-    println!("Mock LLM server listening on http://{}", addr);
-    println!("Will fail first {} attempt(s) to test retry logic", fail_attempts);
+    println!("Mock LLM server listening on http://{}", listener.local_addr()?);
+    match mode {
+        Mode::AlwaysOk => println!("Will return valid JSON on every attempt"),
+        Mode::AlwaysFail => println!("Will return invalid JSON on every attempt"),
+        Mode::FailN => println!("Will fail first {} attempt(s) to test retry logic", fail_attempts),
+    }
     println!("Send POST requests to http://{}/llm", addr);
+    println!("POST /reset restarts the attempt count; GET /stats reports it");
     println!("Using simple HTTP/1.1 server (no HTTP/2, no upgrades)");
 
     loop {
@@ -1,9 +1,18 @@
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use serde_json;
+use clap::{Parser, Subcommand};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 use shape_runner::client::ShapeRunnerClientWrapper;
-use shape_runner::codec::ShapeCodec;
-use shape_runner::shape::{FeatureDesignInput, FeatureDesignOutput, FormationInput, FormationOutput};
+use shape_runner::codec::{Codec, ShapeCodec};
+use shape_runner::llm::{estimate_tokens, LlmClient, SchemaFormat};
+use shape_runner::registry::{ShapeRegistry, ShapeSpec};
+use shape_runner::types::{validate, validate_many, TypeDef};
+use shape_runner::shape::{
+    feature_design_to_mermaid, ApiDesignInput, ApiDesignOutput, FeatureDesignInput, FeatureDesignOutput, FormationGridInput,
+    FormationGridOutput, FormationInput, FormationOutput, RequirementsInput, RequirementsOutput, RiskAssessmentInput,
+    RiskAssessmentOutput,
+};
+use std::collections::BTreeMap;
 use std::io::{self, Read, Write};
 
 #[derive(Parser)]
@@ -15,26 +24,148 @@ struct Cli {
     shape: String,
 
     /// Server address (e.g., "http://localhost:50051")
-    #[arg(short, long, default_value = "http://localhost:50051")]
+    #[arg(short = 'S', long, default_value = "http://localhost:50051")]
     server: String,
 
     /// Input file path (use "-" for stdin)
     #[arg(short, long, default_value = "-")]
     input: String,
 
-    /// Output format: json or msgpack
+    /// Output format: json, jsonl, msgpack or mermaid (FeatureDesign only)
     #[arg(short, long, default_value = "json")]
     format: String,
 
+    /// With --format jsonl, write each line as {"index": N, "output": ...},
+    /// N counting runs from 0, so failed runs show up as gaps
+    #[arg(long)]
+    index: bool,
+
+    /// Print JSON output indented over several lines (the default)
+    #[arg(long, overrides_with = "compact")]
+    pretty: bool,
+
+    /// Print JSON output on a single line, e.g. for jq or one JSON value per
+    /// line with --repeat
+    #[arg(long, overrides_with = "pretty")]
+    compact: bool,
+
     /// Request timeout in seconds
     #[arg(short, long, default_value = "60")]
     timeout: u64,
+
+    /// Run the shape this many times with the same input, printing every output
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: u32,
+
+    /// Print the model's output to stderr as it is generated
+    #[arg(long)]
+    stream: bool,
+
+    /// Add FeatureDesign constraints from a file: a JSON array of strings, or
+    /// plain text with one constraint per line. Repeat to merge several files
+    #[arg(long = "constraints-file")]
+    constraints_files: Vec<String>,
+
+    /// Also write the FeatureDesign output into this directory: design.json
+    /// with name, rationale and risks, and one JSON file per component
+    #[arg(long, value_name = "DIR")]
+    split_output: Option<String>,
+
+    /// Print the output schema and the first prompt the server would send the
+    /// model for this input, without contacting the server or the model
+    #[arg(long, conflicts_with_all = ["repeat", "stream", "split_output"])]
+    explain: bool,
+
+    /// With --explain, how the prompt describes the schema, as the server's
+    /// LLM_SCHEMA_FORMAT: bullets or typescript
+    #[arg(long, default_value = "bullets", requires = "explain")]
+    schema_format: SchemaFormat,
+
+    /// With --explain, shape manifest to load data-driven shapes from
+    #[arg(long, requires = "explain")]
+    manifest: Option<String>,
+
+    /// Codec the server is configured with (SHAPE_RUNNER_CODEC): msgpack or json
+    #[arg(long, default_value = "msgpack")]
+    server_codec: Codec,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the server's request and latency metrics as JSON
+    Metrics,
+    /// Print a shape's JSON Schema, from the compiled-in shapes unless
+    /// --remote is given
+    Schema {
+        /// Shape ID (e.g., "FeatureDesign")
+        #[arg(short, long)]
+        shape: String,
+
+        /// Print the input schema instead of the output schema
+        #[arg(long)]
+        input: bool,
+
+        /// Shape manifest to load data-driven shapes from
+        #[arg(short, long)]
+        manifest: Option<String>,
+
+        /// Ask the server (see --server) instead, so shapes from its manifest
+        /// are included
+        #[arg(long, conflicts_with = "manifest")]
+        remote: bool,
+    },
+    /// Validate a JSONL file of candidate outputs against a shape's output
+    /// schema, without contacting the server
+    ValidateFile {
+        /// Shape ID (e.g., "FeatureDesign")
+        #[arg(short, long)]
+        shape: String,
+
+        /// JSONL file with one candidate output per line
+        file: String,
+
+        /// Shape manifest to load data-driven shapes from
+        #[arg(short, long)]
+        manifest: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    match &cli.command {
+        Some(Command::Schema { shape, input, remote: true, .. }) => {
+            return print_remote_schema(&cli.server, shape, *input).await;
+        }
+        Some(Command::Schema { shape, input, manifest, .. }) => {
+            return print_schema(shape, *input, manifest.as_deref());
+        }
+        Some(Command::ValidateFile { shape, file, manifest }) => {
+            return validate_file(shape, file, manifest.as_deref());
+        }
+        Some(Command::Metrics) => {
+            return print_metrics(&cli.server).await;
+        }
+        None => {}
+    }
+
+    if !cli.constraints_files.is_empty() && cli.shape != "FeatureDesign" {
+        return Err(anyhow!("--constraints-file only applies to FeatureDesign"));
+    }
+    if cli.split_output.is_some() && cli.shape != "FeatureDesign" {
+        return Err(anyhow!("--split-output only applies to FeatureDesign"));
+    }
+    if cli.split_output.is_some() && cli.repeat > 1 {
+        return Err(anyhow!("--split-output can't be combined with --repeat"));
+    }
+    if cli.index && cli.format != "jsonl" {
+        return Err(anyhow!("--index only applies to --format jsonl"));
+    }
+
     // Read input
     let input_json = if cli.input == "-" {
         let mut buffer = String::new();
@@ -47,81 +178,364 @@ async fn main() -> Result<()> {
             .map_err(|e| anyhow!("Failed to read input file {}: {e}", cli.input))?
     };
 
+    if cli.explain {
+        return explain(&cli, &input_json);
+    }
+
     // Connect to server
-    println!("Connecting to ShapeRunner server at {}...", cli.server);
-    let mut client = ShapeRunnerClientWrapper::connect(cli.server.clone())
+    eprintln!("Connecting to ShapeRunner server at {}...", cli.server);
+    let mut client = ShapeRunnerClientWrapper::connect_with_codec(cli.server.clone(), cli.server_codec)
         .await
         .map_err(|e| anyhow!("Failed to connect: {e}"))?;
 
-    println!("Running shape '{}'...", cli.shape);
+    eprintln!("Running shape '{}'...", cli.shape);
 
-    // Execute shape with timeout - handle different shape types
-    let timeout = std::time::Duration::from_secs(cli.timeout);
-    
     match cli.shape.as_str() {
         "FeatureDesign" => {
-            let input: FeatureDesignInput = serde_json::from_str(&input_json)
-                .map_err(|e| anyhow!("Failed to parse input JSON: {e}"))?;
-            
-            let output: FeatureDesignOutput = client
-                .run_shape_with_timeout(cli.shape.clone(), &input, timeout)
-                .await
-                .map_err(|e| anyhow!("Shape execution failed: {e}"))?;
-            
-            match cli.format.as_str() {
-                "json" => {
-                    let json = serde_json::to_string_pretty(&output)
-                        .map_err(|e| anyhow!("Failed to serialize output: {e}"))?;
-                    println!("{}", json);
-                }
-                "msgpack" => {
-                    let codec = shape_runner::codec::MsgPackCodec;
-                    let bytes = codec
-                        .encode(&output)
-                        .map_err(|e| anyhow!("Failed to encode output: {e}"))?;
-                    io::stdout()
-                        .write_all(&bytes)
-                        .map_err(|e| anyhow!("Failed to write output: {e}"))?;
-                }
-                _ => {
-                    return Err(anyhow!("Unknown output format: {}", cli.format));
+            let input = feature_design_input(&cli, &input_json)?;
+            let outputs = run_repeated(&mut client, &cli, &input, Some(FeatureDesignOutput::summary)).await?;
+            if let (Some(dir), Some(output)) = (&cli.split_output, outputs.first()) {
+                for path in output.write_split(std::path::Path::new(dir))? {
+                    eprintln!("Wrote {}", path.display());
                 }
             }
         }
         "Formation" => {
-            let input: FormationInput = serde_json::from_str(&input_json)
-                .map_err(|e| anyhow!("Failed to parse input JSON: {e}"))?;
-            
-            let output: FormationOutput = client
-                .run_shape_with_timeout(cli.shape.clone(), &input, timeout)
-                .await
-                .map_err(|e| anyhow!("Shape execution failed: {e}"))?;
-            
-            match cli.format.as_str() {
-                "json" => {
-                    let json = serde_json::to_string_pretty(&output)
-                        .map_err(|e| anyhow!("Failed to serialize output: {e}"))?;
-                    println!("{}", json);
-                }
-                "msgpack" => {
-                    let codec = shape_runner::codec::MsgPackCodec;
-                    let bytes = codec
-                        .encode(&output)
-                        .map_err(|e| anyhow!("Failed to encode output: {e}"))?;
-                    io::stdout()
-                        .write_all(&bytes)
-                        .map_err(|e| anyhow!("Failed to write output: {e}"))?;
-                }
-                _ => {
-                    return Err(anyhow!("Unknown output format: {}", cli.format));
-                }
-            }
+            let input: FormationInput = parse_input(&input_json)?;
+            let outputs = run_repeated(&mut client, &cli, &input, Some(FormationOutput::summary)).await?;
+            print_count_stats(outputs.iter().map(|o| o.coordinates.len()), cli.repeat);
+        }
+        "FormationGrid" => {
+            let input: FormationGridInput = parse_input(&input_json)?;
+            let outputs = run_repeated(&mut client, &cli, &input, Some(FormationGridOutput::summary)).await?;
+            print_count_stats(outputs.iter().map(|o| o.coordinates.len()), cli.repeat);
+        }
+        "Requirements" => {
+            let input: RequirementsInput = parse_input(&input_json)?;
+            run_repeated(&mut client, &cli, &input, Some(RequirementsOutput::summary)).await?;
+        }
+        "ApiDesign" => {
+            let input: ApiDesignInput = parse_input(&input_json)?;
+            run_repeated(&mut client, &cli, &input, Some(ApiDesignOutput::summary)).await?;
+        }
+        "RiskAssessment" => {
+            let input: RiskAssessmentInput = parse_input(&input_json)?;
+            run_repeated(&mut client, &cli, &input, Some(RiskAssessmentOutput::summary)).await?;
         }
         _ => {
-            return Err(anyhow!("Unknown shape: {}. Supported shapes: FeatureDesign, Formation", cli.shape));
+            // Data-driven shapes (e.g. loaded from a server manifest) have no
+            // compiled-in types, so pass JSON through untyped.
+            let input: Value = parse_input(&input_json)?;
+            run_repeated::<_, Value>(&mut client, &cli, &input, None).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the schema description and first prompt for `--explain`, built
+/// offline with the server's prompt builders.
+fn explain(cli: &Cli, input_json: &str) -> Result<()> {
+    let registry = local_registry(cli.manifest.as_deref())?;
+    let spec = lookup(&registry, &cli.shape)?;
+    let (input_typedef, _) = schemas(&registry, &cli.shape)?;
+    let input: Value = if cli.shape == "FeatureDesign" {
+        serde_json::to_value(feature_design_input(cli, input_json)?)?
+    } else {
+        parse_input(input_json)?
+    };
+    if let Err(errors) = input_typedef.map_or(Ok(()), |typedef| validate(&typedef, &input)) {
+        let details: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        return Err(anyhow!("Input does not match the {} input schema: {}", cli.shape, details.join("; ")));
+    }
+
+    let llm = LlmClient::new(String::new()).with_schema_format(cli.schema_format);
+    let prompt = llm.explain_prompt(spec, &input)?;
+    println!("=== Schema ===");
+    println!("{}", llm.schema_description(&spec.output_typedef).trim_end());
+    println!();
+    println!("=== Prompt (~{} tokens) ===", estimate_tokens(&prompt));
+    print!("{}", prompt);
+    Ok(())
+}
+
+/// FeatureDesign input from `input_json`, plus any `--constraints-file`s.
+fn feature_design_input(cli: &Cli, input_json: &str) -> Result<FeatureDesignInput> {
+    let input: FeatureDesignInput = parse_input(input_json)?;
+    if cli.constraints_files.is_empty() {
+        return Ok(input);
+    }
+    let mut builder = input.into_builder();
+    for path in &cli.constraints_files {
+        builder = builder.constraints(read_constraints(path)?);
+    }
+    Ok(builder.build())
+}
+
+/// Report pass/fail for every non-blank line of a JSONL file, failing if
+/// any line doesn't parse or doesn't validate.
+fn validate_file(shape_id: &str, file: &str, manifest: Option<&str>) -> Result<()> {
+    let registry = local_registry(manifest)?;
+    let spec = lookup(&registry, shape_id)?;
+    let contents =
+        std::fs::read_to_string(file).map_err(|e| anyhow!("Failed to read {file}: {e}"))?;
+
+    let lines: Vec<(usize, serde_json::Result<Value>)> = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| (i + 1, serde_json::from_str(line)))
+        .collect();
+    let values: Vec<Value> = lines.iter().filter_map(|(_, parsed)| parsed.as_ref().ok().cloned()).collect();
+    let mut results = validate_many(&spec.output_typedef, &values).into_iter();
+
+    let total = lines.len();
+    let mut failed = 0;
+    for (line, parsed) in &lines {
+        let outcome = match parsed {
+            Err(e) => Err(format!("invalid JSON: {e}")),
+            Ok(_) => results.next().unwrap_or(Ok(())).map_err(|errors| {
+                let details: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                details.join("; ")
+            }),
+        };
+        match outcome {
+            Ok(()) => println!("line {line}: ok"),
+            Err(details) => {
+                failed += 1;
+                println!("line {line}: FAIL: {details}");
+            }
         }
     }
 
+    println!("{} of {total} passed", total - failed);
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {total} lines failed validation"));
+    }
+    Ok(())
+}
+
+/// Constraints from a `--constraints-file`: a JSON array of strings, or else
+/// one per non-blank line.
+fn read_constraints(path: &str) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read constraints file {path}: {e}"))?;
+    if text.trim_start().starts_with('[') {
+        return serde_json::from_str(&text)
+            .map_err(|e| anyhow!("Constraints file {path} is not a JSON array of strings: {e}"));
+    }
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn parse_input<T: DeserializeOwned>(input_json: &str) -> Result<T> {
+    serde_json::from_str(input_json).map_err(|e| anyhow!("Failed to parse input JSON: {e}"))
+}
+
+/// Run the shape `--repeat` times with the same input, writing each output as
+/// it arrives. With more than one run, each output gets a header on stderr and
+/// a failed run doesn't stop the rest; the call fails at the end if any did.
+async fn run_repeated<I, O>(
+    client: &mut ShapeRunnerClientWrapper,
+    cli: &Cli,
+    input: &I,
+    summary: Option<fn(&O) -> String>,
+) -> Result<Vec<O>>
+where
+    I: Serialize,
+    O: DeserializeOwned + Serialize,
+{
+    let timeout = std::time::Duration::from_secs(cli.timeout);
+    let mut outputs = Vec::new();
+    let mut failed = 0;
+
+    for run in 1..=cli.repeat {
+        if cli.repeat > 1 {
+            eprintln!("--- run {run}/{} ---", cli.repeat);
+        }
+        let result: Result<O> = if cli.stream {
+            let mut attempt = 0;
+            let result = client
+                .run_shape_stream(cli.shape.clone(), input, timeout, |chunk| {
+                    if chunk.attempt != attempt {
+                        attempt = chunk.attempt;
+                        eprintln!("\n--- attempt {attempt} ---");
+                    }
+                    eprint!("{}", chunk.text);
+                })
+                .await;
+            eprintln!();
+            result
+        } else {
+            client.run_shape_with_timeout(cli.shape.clone(), input, timeout).await
+        }
+        .map_err(|e| anyhow!("Shape execution failed: {e}"));
+        let output = match result {
+            Ok(output) => output,
+            Err(e) if cli.repeat > 1 => {
+                eprintln!("{e}");
+                failed += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(summary) = summary {
+            eprintln!("{}", summary(&output));
+        }
+        let index = cli.index.then_some(run - 1);
+        write_output(&output, &cli.format, cli.compact, index)?;
+        outputs.push(output);
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} runs failed", cli.repeat));
+    }
+    Ok(outputs)
+}
+
+/// With `--repeat`, how often each coordinate count came back, e.g.
+/// "Coordinate counts over 5 runs: 15 (x4), 14 (x1)".
+fn print_count_stats(counts: impl Iterator<Item = usize>, repeat: u32) {
+    if repeat < 2 {
+        return;
+    }
+    let mut seen: BTreeMap<usize, u32> = BTreeMap::new();
+    for count in counts {
+        *seen.entry(count).or_default() += 1;
+    }
+    let mut by_frequency: Vec<(usize, u32)> = seen.into_iter().collect();
+    by_frequency.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let parts: Vec<String> = by_frequency.iter().map(|(count, n)| format!("{count} (x{n})")).collect();
+    eprintln!("Coordinate counts over {repeat} runs: {}", parts.join(", "));
+}
+
+/// Built-in shapes plus those in `manifest`, for the offline commands.
+fn local_registry(manifest: Option<&str>) -> Result<ShapeRegistry> {
+    let mut registry = ShapeRegistry::builtin();
+    if let Some(manifest) = manifest {
+        registry.load_manifest(std::path::Path::new(manifest))?;
+    }
+    Ok(registry)
+}
+
+fn lookup<'a>(registry: &'a ShapeRegistry, shape_id: &str) -> Result<&'a ShapeSpec> {
+    registry.get(shape_id).ok_or_else(|| unknown_shape(registry, shape_id))
+}
+
+/// `shape_id`'s input and output typedefs, see `ShapeRegistry::schemas_for`.
+fn schemas(registry: &ShapeRegistry, shape_id: &str) -> Result<(Option<TypeDef>, TypeDef)> {
+    registry.schemas_for(shape_id).ok_or_else(|| unknown_shape(registry, shape_id))
+}
+
+fn unknown_shape(registry: &ShapeRegistry, shape_id: &str) -> anyhow::Error {
+    let known: Vec<&str> = registry.ids().collect();
+    anyhow!("Unknown shape: {shape_id} (known shapes: {})", known.join(", "))
+}
+
+fn print_schema(shape_id: &str, input: bool, manifest: Option<&str>) -> Result<()> {
+    let registry = local_registry(manifest)?;
+    let (input_typedef, output_typedef) = schemas(&registry, shape_id)?;
+
+    let typedef = if input {
+        input_typedef.ok_or_else(|| anyhow!("Shape {shape_id} has no input schema"))?
+    } else {
+        output_typedef
+    };
+    let json = serde_json::to_string_pretty(&typedef.to_json_schema())
+        .map_err(|e| anyhow!("Failed to serialize schema: {e}"))?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Like `print_schema`, but with the schema the server at `server` reports.
+async fn print_remote_schema(server: &str, shape_id: &str, input: bool) -> Result<()> {
+    let mut client = ShapeRunnerClientWrapper::connect(server.to_string()).await?;
+    let description = client.describe_shape(shape_id.to_string()).await?;
+
+    let schema = if input {
+        if description.input_schema.is_empty() {
+            return Err(anyhow!("Shape {shape_id} has no input schema"));
+        }
+        description.input_schema
+    } else {
+        description.output_schema
+    };
+    let schema: Value = serde_json::from_str(&schema).map_err(|e| anyhow!("Server sent an invalid schema: {e}"))?;
+    let json = serde_json::to_string_pretty(&schema)
+        .map_err(|e| anyhow!("Failed to serialize schema: {e}"))?;
+    println!("{}", json);
+    Ok(())
+}
+
+async fn print_metrics(server: &str) -> Result<()> {
+    let mut client = ShapeRunnerClientWrapper::connect(server.to_string()).await?;
+    let metrics = client.metrics().await?;
+
+    let attempts: BTreeMap<String, u64> = metrics
+        .attempts
+        .iter()
+        .map(|bucket| (bucket.attempts.to_string(), bucket.count))
+        .collect();
+    let by_shape: BTreeMap<&String, &u64> = metrics.requests_by_shape.iter().collect();
+    let json = serde_json::json!({
+        "requests_total": metrics.requests_total,
+        "requests_failed": metrics.requests_failed,
+        "requests_by_shape": by_shape,
+        "attempts": attempts,
+        "avg_request_seconds": metrics.avg_request_seconds,
+        "avg_llm_call_seconds": metrics.avg_llm_call_seconds,
+    });
+    println!("{}", serde_json::to_string_pretty(&json)?);
+    Ok(())
+}
+
+/// Write one output to stdout in `format`. `index` only applies to `jsonl`,
+/// wrapping the output as `{"index": N, "output": ...}`.
+fn write_output<T: Serialize>(output: &T, format: &str, compact: bool, index: Option<u32>) -> Result<()> {
+    match format {
+        "json" => {
+            let json = if compact {
+                serde_json::to_string(output)
+            } else {
+                serde_json::to_string_pretty(output)
+            }
+            .map_err(|e| anyhow!("Failed to serialize output: {e}"))?;
+            println!("{}", json);
+        }
+        "jsonl" => {
+            let line = match index {
+                Some(index) => serde_json::to_string(&serde_json::json!({ "index": index, "output": output })),
+                None => serde_json::to_string(output),
+            }
+            .map_err(|e| anyhow!("Failed to serialize output: {e}"))?;
+            let mut stdout = io::stdout().lock();
+            writeln!(stdout, "{line}")
+                .and_then(|()| stdout.flush())
+                .map_err(|e| anyhow!("Failed to write output: {e}"))?;
+        }
+        "msgpack" => {
+            let codec = shape_runner::codec::MsgPackCodec;
+            let bytes = codec
+                .encode(output)
+                .map_err(|e| anyhow!("Failed to encode output: {e}"))?;
+            io::stdout()
+                .write_all(&bytes)
+                .map_err(|e| anyhow!("Failed to write output: {e}"))?;
+        }
+        "mermaid" => {
+            let design: FeatureDesignOutput = serde_json::to_value(output)
+                .and_then(serde_json::from_value)
+                .map_err(|_| anyhow!("mermaid format is only supported for FeatureDesign output"))?;
+            print!("{}", feature_design_to_mermaid(&design));
+        }
+        _ => {
+            return Err(anyhow!("Unknown output format: {}", format));
+        }
+    }
     Ok(())
 }
 
@@ -0,0 +1,363 @@
+use std::collections::{hash_map, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+
+// How often expired idempotency entries are dropped from the map
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Results keyed by a caller-supplied idempotency key, kept for a TTL.
+///
+/// The first request for a key runs; concurrent and later requests with the
+/// same key wait for and share its result. Errors are not cached, so a
+/// failed run can be retried under the same key. At most `max_entries` keys
+/// are held: once full, requests with a new key run without being cached
+/// until expired entries are dropped, at most once per `PRUNE_INTERVAL`.
+pub struct IdempotencyCache<V> {
+    ttl: Duration,
+    max_entries: usize,
+    state: Mutex<IdempotencyState<V>>,
+}
+
+struct IdempotencyState<V> {
+    entries: HashMap<String, Entry<V>>,
+    last_prune: Instant,
+}
+
+struct Entry<V> {
+    created: Instant,
+    result: Arc<OnceCell<V>>,
+}
+
+impl<V: Clone> IdempotencyCache<V> {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            state: Mutex::new(IdempotencyState {
+                entries: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    /// The cached result for `key`, or the result of `run` (cached on
+    /// success, and only while the cache has room).
+    pub async fn get_or_run<F, Fut, E>(&self, key: String, run: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let result = {
+            let mut guard = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let state = &mut *guard;
+            let now = Instant::now();
+            if now.duration_since(state.last_prune) >= PRUNE_INTERVAL {
+                state.entries.retain(|_, entry| now.duration_since(entry.created) < self.ttl);
+                state.last_prune = now;
+            }
+
+            let full = state.entries.len() >= self.max_entries;
+            let fresh = || Entry {
+                created: now,
+                result: Arc::new(OnceCell::new()),
+            };
+            match state.entries.entry(key) {
+                hash_map::Entry::Occupied(mut entry) => {
+                    // Expired but not yet pruned: start over
+                    if now.duration_since(entry.get().created) >= self.ttl {
+                        entry.insert(fresh());
+                    }
+                    Some(entry.get().result.clone())
+                }
+                hash_map::Entry::Vacant(_) if full => None,
+                hash_map::Entry::Vacant(entry) => Some(entry.insert(fresh()).result.clone()),
+            }
+        };
+
+        match result {
+            Some(result) => result.get_or_try_init(run).await.cloned(),
+            None => run().await,
+        }
+    }
+}
+
+/// Stable hash of `parts` for cache and replay keys: FNV-1a over the parts
+/// with a zero byte between them, as 16 hex digits. Unlike `DefaultHasher`
+/// it is the same across runs and builds.
+pub fn stable_hash(parts: &[&[u8]]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for (i, part) in parts.iter().enumerate() {
+        let separator: &[u8] = if i == 0 { &[] } else { &[0] };
+        for byte in separator.iter().chain(part.iter()) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+/// Model responses that passed validation, keyed by scope and prompt, so a
+/// repeated request skips the LLM. Meant for development against slow models;
+/// off unless configured.
+///
+/// The scope is everything besides the prompt that shapes the response:
+/// backend, endpoints, model and sampling settings (see
+/// `LlmClient::with_prompt_cache`). Entries expire after `ttl`. Each layer
+/// holds at most `max_bytes` of responses and drops its oldest entries beyond
+/// that. With `with_disk`, entries are also written to a directory, one JSON
+/// file per prompt, and survive restarts: reads check memory, then disk;
+/// writes go to both. Disk I/O runs on the blocking thread pool.
+pub struct PromptCache {
+    ttl: Duration,
+    max_bytes: u64,
+    memory: Mutex<MemoryLayer>,
+    disk: Option<DiskLayer>,
+}
+
+#[derive(Default)]
+struct MemoryLayer {
+    entries: HashMap<String, CachedResponse>,
+    bytes: u64,
+}
+
+/// The cache directory and the files in it, tracked in memory so a write
+/// doesn't have to list the directory to enforce `max_bytes`.
+struct DiskLayer {
+    dir: PathBuf,
+    files: Mutex<DiskFiles>,
+}
+
+#[derive(Default)]
+struct DiskFiles {
+    /// Written time (seconds since the Unix epoch), insertion order to break
+    /// ties within a second, and size, by key
+    files: HashMap<String, ((u64, u64), u64)>,
+    inserted: u64,
+    bytes: u64,
+}
+
+impl DiskFiles {
+    fn insert(&mut self, key: String, written: u64, len: u64) {
+        self.inserted += 1;
+        self.bytes += len;
+        if let Some((_, old)) = self.files.insert(key, ((written, self.inserted), len)) {
+            self.bytes -= old;
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, len)) = self.files.remove(key) {
+            self.bytes -= len;
+        }
+    }
+
+    /// Forget the oldest files until at most `max_bytes` remain, returning
+    /// their keys for deletion.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.bytes > max_bytes {
+            let Some(oldest) = self
+                .files
+                .iter()
+                .min_by_key(|(_, (age, _))| *age)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedResponse {
+    /// Seconds since the Unix epoch.
+    created: u64,
+    scope: String,
+    prompt: String,
+    response: String,
+}
+
+impl CachedResponse {
+    fn size(&self) -> u64 {
+        (self.prompt.len() + self.response.len()) as u64
+    }
+}
+
+impl PromptCache {
+    /// An in-memory cache.
+    pub fn new(ttl: Duration, max_bytes: u64) -> Self {
+        Self {
+            ttl,
+            max_bytes,
+            memory: Mutex::new(MemoryLayer::default()),
+            disk: None,
+        }
+    }
+
+    /// Also keep entries in `dir`, created if needed. Entries already there
+    /// are used as long as they haven't expired; the directory is listed
+    /// once, here, to learn their sizes.
+    pub fn with_disk(mut self, dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create cache directory {}", dir.display()))?;
+        let mut found = Vec::new();
+        let listing = std::fs::read_dir(dir)
+            .with_context(|| format!("failed to list cache directory {}", dir.display()))?;
+        for file in listing {
+            let file = file?;
+            let path = file.path();
+            let (Some(key), true) = (path.file_stem(), path.extension().is_some_and(|ext| ext == "json")) else {
+                continue;
+            };
+            let metadata = file.metadata()?;
+            let written = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default();
+            found.push((written, key.to_string_lossy().into_owned(), metadata.len()));
+        }
+        found.sort();
+        let mut files = DiskFiles::default();
+        for (written, key, len) in found {
+            files.insert(key, written, len);
+        }
+        self.disk = Some(DiskLayer {
+            dir: dir.to_path_buf(),
+            files: Mutex::new(files),
+        });
+        Ok(self)
+    }
+
+    /// The cached response to `prompt` sent in `scope`, if any.
+    pub async fn get(&self, scope: &str, prompt: &str) -> Option<String> {
+        let key = stable_hash(&[scope.as_bytes(), prompt.as_bytes()]);
+        let now = unix_now();
+        {
+            let memory = self.memory.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(entry) = memory.entries.get(&key) {
+                if self.fresh(entry, now) && entry.scope == scope && entry.prompt == prompt {
+                    return Some(entry.response.clone());
+                }
+            }
+        }
+
+        let disk = self.disk.as_ref()?;
+        let path = disk.dir.join(format!("{key}.json"));
+        let bytes = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || std::fs::read(path)
+        })
+        .await
+        .ok()?
+        .ok()?;
+        let entry: CachedResponse = serde_json::from_slice(&bytes).ok()?;
+        if !self.fresh(&entry, now) {
+            disk.files.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+            let _ = tokio::task::spawn_blocking(move || std::fs::remove_file(path)).await;
+            return None;
+        }
+        if entry.scope != scope || entry.prompt != prompt {
+            return None;
+        }
+        let response = entry.response.clone();
+        self.insert_memory(key, entry);
+        Some(response)
+    }
+
+    /// Cache `response` as the answer to `prompt` sent in `scope`. Disk
+    /// failures are logged, not returned: the cache is an optimization.
+    pub async fn put(&self, scope: &str, prompt: &str, response: &str) {
+        let key = stable_hash(&[scope.as_bytes(), prompt.as_bytes()]);
+        let entry = CachedResponse {
+            created: unix_now(),
+            scope: scope.to_string(),
+            prompt: prompt.to_string(),
+            response: response.to_string(),
+        };
+        if let Some(disk) = &self.disk {
+            if let Err(e) = self.write_disk(disk, &key, &entry).await {
+                disk.files.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+                tracing::warn!(dir = %disk.dir.display(), error = %e, "failed to write prompt cache entry");
+            }
+        }
+        self.insert_memory(key, entry);
+    }
+
+    fn fresh(&self, entry: &CachedResponse, now: u64) -> bool {
+        now.saturating_sub(entry.created) < self.ttl.as_secs()
+    }
+
+    fn insert_memory(&self, key: String, entry: CachedResponse) {
+        let now = unix_now();
+        let mut memory = self.memory.lock().unwrap_or_else(|e| e.into_inner());
+        let memory = &mut *memory;
+        memory.bytes += entry.size();
+        if let Some(old) = memory.entries.insert(key, entry) {
+            memory.bytes -= old.size();
+        }
+
+        memory.entries.retain(|_, entry| {
+            let keep = now.saturating_sub(entry.created) < self.ttl.as_secs();
+            if !keep {
+                memory.bytes -= entry.size();
+            }
+            keep
+        });
+        while memory.bytes > self.max_bytes {
+            let Some(oldest) = memory
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.created)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(old) = memory.entries.remove(&oldest) {
+                memory.bytes -= old.size();
+            }
+        }
+    }
+
+    /// Write `entry` atomically (temp file, then rename), then delete the
+    /// oldest files while the directory holds more than `max_bytes`.
+    async fn write_disk(&self, disk: &DiskLayer, key: &str, entry: &CachedResponse) -> Result<()> {
+        let bytes = serde_json::to_vec(entry)?;
+        let evicted = {
+            let mut files = disk.files.lock().unwrap_or_else(|e| e.into_inner());
+            files.insert(key.to_string(), entry.created, bytes.len() as u64);
+            files.evict(self.max_bytes)
+        };
+
+        let dir = disk.dir.clone();
+        let key = key.to_string();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let path = dir.join(format!("{key}.json"));
+            let tmp = dir.join(format!("{key}.json.tmp"));
+            std::fs::write(&tmp, bytes)?;
+            std::fs::rename(&tmp, &path)?;
+            for key in evicted {
+                let _ = std::fs::remove_file(dir.join(format!("{key}.json")));
+            }
+            Ok(())
+        })
+        .await?
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
@@ -1,25 +1,39 @@
 use anyhow::{anyhow, Result};
-use crate::codec::{MsgPackCodec, ShapeCodec};
+use crate::codec::{Codec, JsonCodec, ShapeCodec};
 use crate::rpc::shaperunner::shape_runner_client::ShapeRunnerClient;
-use crate::rpc::shaperunner::{RunRequest, RunResponse};
+use crate::rpc::shaperunner::run_stream_event::Event;
+use crate::rpc::shaperunner::{
+    BatchRunItem, BatchRunRequest, DescribeShapeRequest, DescribeShapeResponse, MetricsRequest, MetricsResponse,
+    PipelineStage, RunPipelineRequest, RunPipelineResponse, RunRequest, RunResponse, TokenChunk,
+};
 use serde::{de::DeserializeOwned, Serialize};
 use std::time::Duration;
 use tonic::transport::Channel;
+use tonic::Streaming;
 
 pub struct ShapeRunnerClientWrapper {
     client: ShapeRunnerClient<Channel>,
-    codec: MsgPackCodec,
+    codec: Box<dyn ShapeCodec>,
+    // Which codec `codec` is, for mismatch hints
+    codec_kind: Codec,
 }
 
 impl ShapeRunnerClientWrapper {
     pub async fn connect(addr: String) -> Result<Self> {
+        Self::connect_with_codec(addr, Codec::MsgPack).await
+    }
+
+    /// Connect using a specific codec. It must match the server's codec,
+    /// e.g. `Codec::Json` when debugging against a JSON-configured server.
+    pub async fn connect_with_codec(addr: String, codec: Codec) -> Result<Self> {
         let client = ShapeRunnerClient::connect(addr)
             .await
             .map_err(|e| anyhow!("Failed to connect to ShapeRunner server: {e}"))?;
 
         Ok(Self {
             client,
-            codec: MsgPackCodec,
+            codec: codec.boxed(),
+            codec_kind: codec,
         })
     }
 
@@ -31,34 +45,24 @@ impl ShapeRunnerClientWrapper {
         // Encode input
         let input_bytes = self
             .codec
-            .encode(input)
+            .encode_value(&serde_json::to_value(input)?)
             .map_err(|e| anyhow!("Failed to encode input: {e}"))?;
 
         // Make gRPC call
         let request = tonic::Request::new(RunRequest {
             shape_id,
             input: input_bytes,
+            ..Default::default()
         });
 
         let response = self
             .client
             .run(request)
             .await
-            .map_err(|e| anyhow!("gRPC call failed: {e}"))?;
-
-        let RunResponse { output, ok, error } = response.into_inner();
-
-        if !ok {
-            return Err(anyhow!("Shape execution failed: {}", error));
-        }
+            .map_err(|e| call_failed(e, self.codec_kind))?;
 
         // Decode output
-        let result: O = self
-            .codec
-            .decode(&output)
-            .map_err(|e| anyhow!("Failed to decode output: {e}"))?;
-
-        Ok(result)
+        self.decode_output(&response.into_inner())
     }
 
     pub async fn run_shape_with_timeout<I, O>(
@@ -74,33 +78,259 @@ impl ShapeRunnerClientWrapper {
         // Encode input
         let input_bytes = self
             .codec
-            .encode(input)
+            .encode_value(&serde_json::to_value(input)?)
             .map_err(|e| anyhow!("Failed to encode input: {e}"))?;
 
-        // Make gRPC call with timeout
-        let request = tonic::Request::new(RunRequest {
+        // Make gRPC call with timeout. The deadline also goes to the server
+        // as `grpc-timeout`, so it stops retrying once there's no time left.
+        let mut request = tonic::Request::new(RunRequest {
+            shape_id,
+            input: input_bytes,
+            ..Default::default()
+        });
+        request.set_timeout(timeout);
+
+        let response = tokio::time::timeout(timeout, self.client.run(request))
+            .await
+            .map_err(|_| anyhow!("Request timed out after {:?}", timeout))?
+            .map_err(|e| call_failed(e, self.codec_kind))?;
+
+        // Decode output
+        self.decode_output(&response.into_inner())
+    }
+
+    /// Like `run_shape_with_timeout`, tagged with `idempotency_key`: if the
+    /// server already ran this request with this key (within its TTL), it
+    /// returns that response instead of generating again. Reuse the key when
+    /// retrying after a timeout; a different input under the same key runs
+    /// on its own.
+    pub async fn run_shape_idempotent<I, O>(
+        &mut self,
+        shape_id: String,
+        input: &I,
+        idempotency_key: &str,
+        timeout: Duration,
+    ) -> Result<O>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let input_bytes = self
+            .codec
+            .encode_value(&serde_json::to_value(input)?)
+            .map_err(|e| anyhow!("Failed to encode input: {e}"))?;
+
+        let mut request = tonic::Request::new(RunRequest {
             shape_id,
             input: input_bytes,
+            ..Default::default()
         });
+        let key = idempotency_key
+            .parse()
+            .map_err(|e| anyhow!("Invalid idempotency key: {e}"))?;
+        request.metadata_mut().insert("idempotency-key", key);
+        request.set_timeout(timeout);
 
         let response = tokio::time::timeout(timeout, self.client.run(request))
             .await
             .map_err(|_| anyhow!("Request timed out after {:?}", timeout))?
-            .map_err(|e| anyhow!("gRPC call failed: {e}"))?;
+            .map_err(|e| call_failed(e, self.codec_kind))?;
 
-        let RunResponse { output, ok, error } = response.into_inner();
+        self.decode_output(&response.into_inner())
+    }
+
+    /// Like `run_shape_with_timeout`, passing the model's output to
+    /// `on_chunk` as it is generated. A chunk with a higher `attempt` than
+    /// the last means the server rejected the earlier output and retried.
+    /// The returned value is the final, validated result.
+    pub async fn run_shape_stream<I, O>(
+        &mut self,
+        shape_id: String,
+        input: &I,
+        timeout: Duration,
+        mut on_chunk: impl FnMut(&TokenChunk),
+    ) -> Result<O>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let input_bytes = self
+            .codec
+            .encode_value(&serde_json::to_value(input)?)
+            .map_err(|e| anyhow!("Failed to encode input: {e}"))?;
+
+        let mut request = tonic::Request::new(RunRequest {
+            shape_id,
+            input: input_bytes,
+            ..Default::default()
+        });
+        request.set_timeout(timeout);
+
+        let response = tokio::time::timeout(timeout, async {
+            let mut events = self
+                .client
+                .run_stream(request)
+                .await
+                .map_err(|e| call_failed(e, self.codec_kind))?
+                .into_inner();
+            while let Some(event) = events.message().await.map_err(|e| anyhow!("gRPC stream failed: {e}"))? {
+                match event.event {
+                    Some(Event::Chunk(chunk)) => on_chunk(&chunk),
+                    Some(Event::Result(response)) => return Ok(response),
+                    None => {}
+                }
+            }
+            Err(anyhow!("Stream ended without a result"))
+        })
+        .await
+        .map_err(|_| anyhow!("Request timed out after {:?}", timeout))??;
 
-        if !ok {
-            return Err(anyhow!("Shape execution failed: {}", error));
+        self.decode_output(&response)
+    }
+
+    /// Run one shape over many inputs, streaming each result as it completes.
+    ///
+    /// Results may arrive out of order; `BatchRunItem::index` is the position of
+    /// the input in `inputs`. Use `decode_output` on each item's response.
+    /// Dropping the stream stops the server from scheduling remaining items.
+    pub async fn run_batch_stream<I>(
+        &mut self,
+        shape_id: String,
+        inputs: &[I],
+        max_concurrency: u32,
+    ) -> Result<Streaming<BatchRunItem>>
+    where
+        I: Serialize,
+    {
+        let mut items = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let input_bytes = self
+                .codec
+                .encode_value(&serde_json::to_value(input)?)
+                .map_err(|e| anyhow!("Failed to encode input: {e}"))?;
+            items.push(RunRequest {
+                shape_id: shape_id.clone(),
+                input: input_bytes,
+                ..Default::default()
+            });
         }
 
-        // Decode output
-        let result: O = self
+        let request = tonic::Request::new(BatchRunRequest {
+            items,
+            max_concurrency,
+        });
+
+        let response = self
+            .client
+            .run_batch_stream(request)
+            .await
+            .map_err(|e| call_failed(e, self.codec_kind))?;
+
+        Ok(response.into_inner())
+    }
+
+    /// Run `stages` in order on the server, each stage's output feeding the
+    /// next stage's input at its `input_field`. `input` is encoded as the
+    /// first stage's input. The response holds one `RunResponse` per stage
+    /// that ran; decode them with `decode_output`. When a stage fails, the
+    /// call still succeeds: `ok` is false, `error` names the stage, and the
+    /// responses end with the failed one.
+    pub async fn run_pipeline<I>(
+        &mut self,
+        input: &I,
+        mut stages: Vec<PipelineStage>,
+        timeout: Duration,
+    ) -> Result<RunPipelineResponse>
+    where
+        I: Serialize,
+    {
+        let first = stages.first_mut().ok_or_else(|| anyhow!("A pipeline needs at least one stage"))?;
+        first.input = self
             .codec
-            .decode(&output)
+            .encode_value(&serde_json::to_value(input)?)
+            .map_err(|e| anyhow!("Failed to encode input: {e}"))?;
+
+        let mut request = tonic::Request::new(RunPipelineRequest {
+            stages,
+            output_as_json: false,
+        });
+        request.set_timeout(timeout);
+
+        let response = tokio::time::timeout(timeout, self.client.run_pipeline(request))
+            .await
+            .map_err(|_| anyhow!("Request timed out after {:?}", timeout))?
+            .map_err(|e| call_failed(e, self.codec_kind))?;
+
+        Ok(response.into_inner())
+    }
+
+    /// Input and output JSON Schemas of a shape as the server knows it,
+    /// including data-driven shapes loaded from its manifest.
+    pub async fn describe_shape(&mut self, shape_id: String) -> Result<DescribeShapeResponse> {
+        let response = self
+            .client
+            .describe_shape(tonic::Request::new(DescribeShapeRequest { shape_id }))
+            .await
+            .map_err(|e| call_failed(e, self.codec_kind))?;
+
+        Ok(response.into_inner())
+    }
+
+    /// Request counts, attempt counts and average latencies since the
+    /// server started.
+    pub async fn metrics(&mut self) -> Result<MetricsResponse> {
+        let response = self
+            .client
+            .metrics(tonic::Request::new(MetricsRequest {}))
+            .await
+            .map_err(|e| call_failed(e, self.codec_kind))?;
+
+        Ok(response.into_inner())
+    }
+
+    /// Decode a single `RunResponse`, e.g. one item of a batch. Outputs the
+    /// server flagged as JSON are decoded as JSON regardless of our codec.
+    /// Degraded outputs decode like any other; check `response.degraded`.
+    pub fn decode_output<O>(&self, response: &RunResponse) -> Result<O>
+    where
+        O: DeserializeOwned,
+    {
+        if !response.ok {
+            return Err(anyhow!(
+                "Shape execution failed: {}{}",
+                response.error,
+                codec_hint(&response.error, self.codec_kind)
+            ));
+        }
+
+        let value = if response.output_is_json {
+            JsonCodec.decode(&response.output)
+        } else {
+            self.codec.decode_value(&response.output).map_err(|e| {
+                anyhow!(
+                    "{e}. The server probably uses a different codec than this client ({})",
+                    self.codec_kind
+                )
+            })
+        }
+        .map_err(|e| anyhow!("Failed to decode output: {e}"))?;
+        let result: O = serde_json::from_value(value)
             .map_err(|e| anyhow!("Failed to decode output: {e}"))?;
 
         Ok(result)
     }
 }
 
+/// A failed call as an error, with a hint when the server could not decode
+/// our input, which usually means its codec differs from ours.
+fn call_failed(status: tonic::Status, codec: Codec) -> anyhow::Error {
+    anyhow!("gRPC call failed: {status}{}", codec_hint(status.message(), codec))
+}
+
+fn codec_hint(error: &str, codec: Codec) -> String {
+    if error.starts_with("decode input failed") {
+        format!(" (this client encodes input as {codec}; the server probably uses a different codec)")
+    } else {
+        String::new()
+    }
+}
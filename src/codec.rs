@@ -1,15 +1,101 @@
-use anyhow::Result;
-use serde::{de::DeserializeOwned, Serialize};
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
 
-pub trait ShapeCodec {
-    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
-    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+/// Serialization format for shape inputs and outputs.
+///
+/// The generic `encode`/`decode` methods are the fast path for concrete codecs.
+/// `encode_value`/`decode_value` keep the trait object-safe so a codec can be
+/// chosen at runtime and stored as `Box<dyn ShapeCodec>`.
+pub trait ShapeCodec: Send + Sync {
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>>;
+    fn decode_value(&self, data: &[u8]) -> Result<Value>;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>
+    where
+        Self: Sized;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>
+    where
+        Self: Sized;
+}
+
+/// Runtime-selectable codec, e.g. from a CLI flag or config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    MsgPack,
+    Json,
+}
+
+impl Codec {
+    pub fn boxed(self) -> Box<dyn ShapeCodec> {
+        match self {
+            Codec::MsgPack => Box::new(MsgPackCodec),
+            Codec::Json => Box::new(JsonCodec),
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Codec::MsgPack => "msgpack",
+            Codec::Json => "json",
+        })
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "msgpack" => Ok(Codec::MsgPack),
+            "json" => Ok(Codec::Json),
+            _ => Err(anyhow!("unknown codec: {s} (expected msgpack or json)")),
+        }
+    }
+}
+
+// Dispatches to the selected codec, for servers that choose one at startup
+impl ShapeCodec for Codec {
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>> {
+        self.encode(value)
+    }
+
+    fn decode_value(&self, data: &[u8]) -> Result<Value> {
+        self.decode(data)
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::MsgPack => MsgPackCodec.encode(value),
+            Codec::Json => JsonCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match self {
+            Codec::MsgPack => MsgPackCodec.decode(data),
+            Codec::Json => JsonCodec.decode(data),
+        }
+    }
 }
 
 // MessagePack codec (fast internal format)
+#[derive(Clone, Copy)]
 pub struct MsgPackCodec;
 
 impl ShapeCodec for MsgPackCodec {
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>> {
+        self.encode(value)
+    }
+
+    fn decode_value(&self, data: &[u8]) -> Result<Value> {
+        self.decode(data)
+    }
+
     fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
         let buf = rmp_serde::to_vec_named(value)?;
         Ok(buf)
@@ -22,9 +108,18 @@ impl ShapeCodec for MsgPackCodec {
 }
 
 // Optional JSON codec for debugging
+#[derive(Clone, Copy)]
 pub struct JsonCodec;
 
 impl ShapeCodec for JsonCodec {
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>> {
+        self.encode(value)
+    }
+
+    fn decode_value(&self, data: &[u8]) -> Result<Value> {
+        self.decode(data)
+    }
+
     fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
         Ok(serde_json::to_vec(value)?)
     }
@@ -33,3 +128,22 @@ impl ShapeCodec for JsonCodec {
         Ok(serde_json::from_slice(data)?)
     }
 }
+
+/// `value` as a JSON value with the keys of every object sorted, so that
+/// encoding it gives the same bytes for equal outputs regardless of struct
+/// field order or map insertion order.
+pub fn canonicalize<T: Serialize>(value: &T) -> Result<Value> {
+    Ok(sort_keys(serde_json::to_value(value)?))
+}
+
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(entries.into_iter().map(|(k, v)| (k, sort_keys(v))).collect())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        other => other,
+    }
+}
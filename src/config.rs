@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::codec::Codec;
+use crate::llm::{BackendKind, FeedbackVerbosity, Jitter, SchemaFormat};
+
+/// Server settings from an optional TOML file. Each setting can also be given
+/// as an environment variable, which overrides the file:
+///
+/// ```toml
+/// addr = "0.0.0.0:50051"                     # SHAPE_RUNNER_ADDR
+/// codec = "msgpack"                          # SHAPE_RUNNER_CODEC
+/// llm_base_url = "http://localhost:11434"    # LLM_BASE_URL
+/// backend = "ollama"                         # LLM_BACKEND
+/// model = "llama3.2:3b"                      # OLLAMA_MODEL
+/// api_key = "sk-..."                         # LLM_API_KEY
+/// max_attempts = 3                           # LLM_MAX_ATTEMPTS
+/// seed = 42                                  # LLM_SEED
+/// temperature = 0.2                          # LLM_TEMPERATURE
+/// circuit_threshold = 5                      # LLM_CIRCUIT_THRESHOLD
+/// cache = true                               # LLM_CACHE
+/// rate_limit_per_minute = 60                 # RATE_LIMIT_PER_MINUTE
+/// grpc_web_allowed_origins = ["https://app.example.com"]  # GRPC_WEB_ALLOWED_ORIGINS
+///
+/// [llm_headers]                              # LLM_HEADERS
+/// X-Tenant = "acme"
+/// ```
+///
+/// Every field is listed with its variable below. Lists are TOML arrays in
+/// the file and comma-separated in the environment; flags are `1` or `true`
+/// in the environment. Settings for one shape, such as `MODEL_<shape_id>`,
+/// are environment-only.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // Server
+
+    /// `SHAPE_RUNNER_ADDR`
+    pub addr: Option<SocketAddr>,
+    /// `SHAPE_RUNNER_CODEC`
+    pub codec: Option<Codec>,
+    /// `SHAPE_MANIFEST`
+    pub shape_manifest: Option<PathBuf>,
+    /// `ENABLED_SHAPES`
+    pub enabled_shapes: Option<Vec<String>>,
+    /// `DEGRADED_SHAPES`
+    pub degraded_shapes: Option<Vec<String>>,
+    /// `LOG_REDACT_FIELDS`
+    pub log_redact_fields: Option<Vec<String>>,
+    /// `RECORD_FILE`
+    pub record_file: Option<PathBuf>,
+    /// `REPLAY_FILE`
+    pub replay_file: Option<PathBuf>,
+    /// `IDEMPOTENCY_TTL_SECS`
+    pub idempotency_ttl_secs: Option<u64>,
+    /// `IDEMPOTENCY_MAX_ENTRIES`
+    pub idempotency_max_entries: Option<usize>,
+    /// `MAX_INPUT_BYTES`
+    pub max_input_bytes: Option<usize>,
+    /// `MAX_INPUT_STRING_BYTES`
+    pub max_input_string_bytes: Option<usize>,
+    /// `MAX_BATCH_ITEMS`
+    pub max_batch_items: Option<usize>,
+    /// `MAX_BATCH_CONCURRENCY`
+    pub max_batch_concurrency: Option<usize>,
+    /// `RATE_LIMIT_PER_MINUTE`
+    pub rate_limit_per_minute: Option<u32>,
+    /// `GRPC_WEB_ALLOWED_ORIGINS`
+    pub grpc_web_allowed_origins: Option<Vec<String>>,
+
+    // LLM
+
+    /// `LLM_BASE_URL`: one URL, or several comma-separated for round-robin.
+    pub llm_base_url: Option<String>,
+    /// `LLM_BACKEND`
+    pub backend: Option<BackendKind>,
+    /// `OLLAMA_MODEL`
+    pub model: Option<String>,
+    /// `LLM_API_KEY`
+    pub api_key: Option<String>,
+    /// `LLM_HEADERS`: extra headers sent on every LLM request, by name.
+    pub llm_headers: Option<BTreeMap<String, String>>,
+    /// `LLM_MAX_ATTEMPTS`
+    pub max_attempts: Option<u32>,
+    /// `LLM_SEED`
+    pub seed: Option<u64>,
+    /// `LLM_TEMPERATURE`
+    pub temperature: Option<f32>,
+    /// `LLM_STOP`, a JSON array of strings in the environment.
+    pub stop: Option<Vec<String>>,
+    /// `OLLAMA_STREAM`
+    pub stream: Option<bool>,
+    /// `OLLAMA_KEEP_ALIVE`
+    pub keep_alive: Option<String>,
+    /// `LLM_CONNECTION_CLOSE`
+    pub connection_close: Option<bool>,
+    /// `LLM_CONNECT_TIMEOUT_SECS`
+    pub connect_timeout_secs: Option<u64>,
+    /// `LLM_REQUEST_TIMEOUT_SECS`
+    pub request_timeout_secs: Option<u64>,
+    /// `LLM_TOTAL_DEADLINE_MS`
+    pub total_deadline_ms: Option<u64>,
+    /// `LLM_CIRCUIT_THRESHOLD`
+    pub circuit_threshold: Option<u32>,
+    /// `LLM_CIRCUIT_COOLDOWN_SECS`
+    pub circuit_cooldown_secs: Option<u64>,
+    /// `LLM_BACKOFF_JITTER`
+    pub backoff_jitter: Option<Jitter>,
+    /// `LLM_FEEDBACK_VERBOSITY`
+    pub feedback_verbosity: Option<FeedbackVerbosity>,
+    /// `LLM_FEEDBACK_HISTORY`
+    pub feedback_history: Option<usize>,
+    /// `LLM_SCHEMA_FORMAT`
+    pub schema_format: Option<SchemaFormat>,
+    /// `FEATURE_DESIGN_MIN_RISKS`
+    pub feature_design_min_risks: Option<u32>,
+    /// `LLM_COERCE_BOOLS`
+    pub coerce_bools: Option<bool>,
+    /// `LLM_LOCAL_REPAIR`
+    pub local_repair: Option<bool>,
+    /// `LLM_PROMPT_LOG`
+    pub prompt_log: Option<PathBuf>,
+    /// `LLM_PROMPT_TOKEN_BUDGET`
+    pub prompt_token_budget: Option<usize>,
+    /// `LLM_CACHE`
+    pub cache: Option<bool>,
+    /// `LLM_CACHE_DIR`
+    pub cache_dir: Option<PathBuf>,
+    /// `LLM_CACHE_TTL_SECS`
+    pub cache_ttl_secs: Option<u64>,
+    /// `LLM_CACHE_MAX_BYTES`
+    pub cache_max_bytes: Option<usize>,
+}
+
+impl Config {
+    /// Read a config file. Unknown keys are an error, to catch typos.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// The file at `path`, if any, with environment variables applied on top.
+    pub fn load_with_env(path: Option<&Path>) -> Result<Config> {
+        let mut config = match path {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Override settings from their environment variables, where set.
+    pub fn apply_env(&mut self) -> Result<()> {
+        self.addr = env_parse("SHAPE_RUNNER_ADDR")?.or(self.addr);
+        self.codec = env_parse("SHAPE_RUNNER_CODEC")?.or(self.codec);
+        self.shape_manifest = env_parse("SHAPE_MANIFEST")?.or(self.shape_manifest.take());
+        self.enabled_shapes = env_list("ENABLED_SHAPES").or(self.enabled_shapes.take());
+        self.degraded_shapes = env_list("DEGRADED_SHAPES").or(self.degraded_shapes.take());
+        self.log_redact_fields = env_list("LOG_REDACT_FIELDS").or(self.log_redact_fields.take());
+        self.record_file = env_parse("RECORD_FILE")?.or(self.record_file.take());
+        self.replay_file = env_parse("REPLAY_FILE")?.or(self.replay_file.take());
+        self.idempotency_ttl_secs = env_parse("IDEMPOTENCY_TTL_SECS")?.or(self.idempotency_ttl_secs);
+        self.idempotency_max_entries = env_parse("IDEMPOTENCY_MAX_ENTRIES")?.or(self.idempotency_max_entries);
+        self.max_input_bytes = env_parse("MAX_INPUT_BYTES")?.or(self.max_input_bytes);
+        self.max_input_string_bytes = env_parse("MAX_INPUT_STRING_BYTES")?.or(self.max_input_string_bytes);
+        self.max_batch_items = env_parse("MAX_BATCH_ITEMS")?.or(self.max_batch_items);
+        self.max_batch_concurrency = env_parse("MAX_BATCH_CONCURRENCY")?.or(self.max_batch_concurrency);
+        self.rate_limit_per_minute = env_parse("RATE_LIMIT_PER_MINUTE")?.or(self.rate_limit_per_minute);
+        self.grpc_web_allowed_origins = env_list("GRPC_WEB_ALLOWED_ORIGINS").or(self.grpc_web_allowed_origins.take());
+
+        self.llm_base_url = env_parse("LLM_BASE_URL")?.or(self.llm_base_url.take());
+        self.backend = env_parse("LLM_BACKEND")?.or(self.backend);
+        self.model = env_parse("OLLAMA_MODEL")?.or(self.model.take());
+        self.api_key = env_parse("LLM_API_KEY")?.or(self.api_key.take());
+        if let Ok(headers) = std::env::var("LLM_HEADERS") {
+            self.llm_headers = Some(parse_headers(&headers)?);
+        }
+        self.max_attempts = env_parse("LLM_MAX_ATTEMPTS")?.or(self.max_attempts);
+        self.seed = env_parse("LLM_SEED")?.or(self.seed);
+        self.temperature = env_parse("LLM_TEMPERATURE")?.or(self.temperature);
+        if let Ok(stop) = std::env::var("LLM_STOP") {
+            self.stop = Some(
+                serde_json::from_str(&stop)
+                    .map_err(|e| anyhow!("invalid LLM_STOP: {e} (expected a JSON array of strings)"))?,
+            );
+        }
+        self.stream = env_flag("OLLAMA_STREAM").or(self.stream);
+        self.keep_alive = env_parse("OLLAMA_KEEP_ALIVE")?.or(self.keep_alive.take());
+        self.connection_close = env_flag("LLM_CONNECTION_CLOSE").or(self.connection_close);
+        self.connect_timeout_secs = env_parse("LLM_CONNECT_TIMEOUT_SECS")?.or(self.connect_timeout_secs);
+        self.request_timeout_secs = env_parse("LLM_REQUEST_TIMEOUT_SECS")?.or(self.request_timeout_secs);
+        self.total_deadline_ms = env_parse("LLM_TOTAL_DEADLINE_MS")?.or(self.total_deadline_ms);
+        self.circuit_threshold = env_parse("LLM_CIRCUIT_THRESHOLD")?.or(self.circuit_threshold);
+        self.circuit_cooldown_secs = env_parse("LLM_CIRCUIT_COOLDOWN_SECS")?.or(self.circuit_cooldown_secs);
+        self.backoff_jitter = env_parse("LLM_BACKOFF_JITTER")?.or(self.backoff_jitter);
+        self.feedback_verbosity = env_parse("LLM_FEEDBACK_VERBOSITY")?.or(self.feedback_verbosity);
+        self.feedback_history = env_parse("LLM_FEEDBACK_HISTORY")?.or(self.feedback_history);
+        self.schema_format = env_parse("LLM_SCHEMA_FORMAT")?.or(self.schema_format);
+        self.feature_design_min_risks = env_parse("FEATURE_DESIGN_MIN_RISKS")?.or(self.feature_design_min_risks);
+        self.coerce_bools = env_flag("LLM_COERCE_BOOLS").or(self.coerce_bools);
+        self.local_repair = env_flag("LLM_LOCAL_REPAIR").or(self.local_repair);
+        self.prompt_log = env_parse("LLM_PROMPT_LOG")?.or(self.prompt_log.take());
+        self.prompt_token_budget = env_parse("LLM_PROMPT_TOKEN_BUDGET")?.or(self.prompt_token_budget);
+        self.cache = env_flag("LLM_CACHE").or(self.cache);
+        self.cache_dir = env_parse("LLM_CACHE_DIR")?.or(self.cache_dir.take());
+        self.cache_ttl_secs = env_parse("LLM_CACHE_TTL_SECS")?.or(self.cache_ttl_secs);
+        self.cache_max_bytes = env_parse("LLM_CACHE_MAX_BYTES")?.or(self.cache_max_bytes);
+        Ok(())
+    }
+}
+
+/// Env var `name` parsed as `T`, or None when unset.
+fn env_parse<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|e| anyhow!("invalid {name}: {e}")),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Env var `name` as a flag: on for `1` or `true` (any case), off for
+/// anything else, None when unset.
+fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+/// Env var `name` as a comma-separated list, trimmed and without empty
+/// entries, or None when unset.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    std::env::var(name).ok().map(|list| {
+        list.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+/// Headers from `LLM_HEADERS`: `Name: value` pairs separated by `;`, e.g.
+/// `X-Tenant: acme; X-Team: search`. Names and values are trimmed.
+fn parse_headers(list: &str) -> Result<BTreeMap<String, String>> {
+    let mut headers = BTreeMap::new();
+    for pair in list.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid LLM_HEADERS entry {pair:?}: expected Name: value"))?;
+        headers.insert(name.trim().to_string(), value.trim().to_string());
+    }
+    Ok(headers)
+}
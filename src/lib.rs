@@ -1,7 +1,17 @@
+pub mod backend;
+pub mod cache;
 pub mod client;
 pub mod codec;
+pub mod config;
 pub mod llm;
+pub mod metrics;
+pub mod postprocess;
+pub mod ratelimit;
+pub mod registry;
+pub mod recording;
 pub mod rpc;
+pub mod scoring;
 pub mod shape;
 pub mod types;
 
+pub mod web;
@@ -1,27 +1,474 @@
 use anyhow::{anyhow, Result};
+use reqwest::header::HeaderMap;
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 
-use crate::shape::{FeatureDesignInput, FeatureDesignOutput, FormationInput, FormationOutput};
-use crate::types::{validate, TypeDef, ValidationError};
+use crate::metrics::Metrics;
+use crate::shape::{
+    ApiDesignInput, ApiDesignOutput, FeatureDesignInput, FeatureDesignOutput, FormationGridInput, FormationGridOutput,
+    FormationInput, FormationOutput, HttpMethod, RequirementsInput, RequirementsOutput, RiskAssessmentInput,
+    RiskAssessmentOutput, RISK_RATING_RANGE,
+};
+use crate::backend::LlmBackend;
+use crate::cache::PromptCache;
+use crate::recording::Transcript;
+use crate::postprocess::formation_input_pipeline;
+use crate::registry::ShapeSpec;
+use crate::types::{
+    coerce_bools, enum_values, is_valid, merge_errors, repair, schema_to_typescript, tuple_type_names, validate, TypeDef,
+    ValidationError,
+};
+
+/// Randomization applied to the exponential backoff between retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Jitter {
+    /// Sleep exactly the exponential backoff (deterministic).
+    #[default]
+    None,
+    /// Sleep a random duration between zero and the exponential backoff.
+    Full,
+    /// Sleep a random duration between the base delay and three times the
+    /// previous sleep, capped at the maximum backoff.
+    Decorrelated,
+}
+
+impl std::str::FromStr for Jitter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Jitter::None),
+            "full" => Ok(Jitter::Full),
+            "decorrelated" => Ok(Jitter::Decorrelated),
+            _ => Err(anyhow!("unknown jitter mode: {s} (expected none, full or decorrelated)")),
+        }
+    }
+}
+
+/// How much of the previous attempt's validation errors goes into the retry
+/// prompt. Large outputs can produce more feedback than a small model's
+/// context holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedbackVerbosity {
+    /// Every error, one per line.
+    #[default]
+    Full,
+    /// Error counts by kind, plus the first few errors.
+    Summary,
+    /// Error counts by kind only.
+    Minimal,
+}
+
+impl std::str::FromStr for FeedbackVerbosity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(FeedbackVerbosity::Full),
+            "summary" => Ok(FeedbackVerbosity::Summary),
+            "minimal" => Ok(FeedbackVerbosity::Minimal),
+            _ => Err(anyhow!("unknown feedback verbosity: {s} (expected full, summary or minimal)")),
+        }
+    }
+}
+
+/// How the output schema is described in prompts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaFormat {
+    /// Indented bullet list of fields and types.
+    #[default]
+    Bullets,
+    /// A TypeScript interface (see `types::schema_to_typescript`), which some
+    /// models follow more readily and which takes fewer tokens.
+    #[serde(alias = "ts")]
+    TypeScript,
+}
+
+impl std::str::FromStr for SchemaFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "bullets" => Ok(SchemaFormat::Bullets),
+            "typescript" | "ts" => Ok(SchemaFormat::TypeScript),
+            _ => Err(anyhow!("unknown schema format: {s} (expected bullets or typescript)")),
+        }
+    }
+}
+
+/// Which API the LLM endpoint speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// Ollama `/api/generate`
+    Ollama,
+    /// The bundled mock server (`{"prompt"}` → `{"output"}`)
+    Mock,
+    /// OpenAI-compatible `/v1/chat/completions`
+    OpenAi,
+}
+
+impl BackendKind {
+    /// Guess the backend from the URL when it isn't configured explicitly.
+    pub fn detect(base_url: &str) -> Self {
+        if base_url.contains("11434") || base_url.contains("/api/generate") {
+            BackendKind::Ollama
+        } else if base_url.contains("/chat/completions") {
+            BackendKind::OpenAi
+        } else {
+            BackendKind::Mock
+        }
+    }
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ollama" => Ok(BackendKind::Ollama),
+            "mock" => Ok(BackendKind::Mock),
+            "openai" => Ok(BackendKind::OpenAi),
+            _ => Err(anyhow!("unknown LLM backend: {s} (expected ollama, mock or openai)")),
+        }
+    }
+}
+
+/// A semantic check run on a typed output after schema validation. Errors it
+/// returns are fed back into the retry loop like schema errors.
+pub type ValidationHook<O, I> = Arc<dyn Fn(&O, &I) -> Vec<ValidationError> + Send + Sync>;
+
+/// Receives each prompt exactly as sent: `(shape_id, attempt, prompt)`, with
+/// `attempt` starting at 1.
+pub type PromptSink = Arc<dyn Fn(&str, u32, &str) + Send + Sync>;
+
+/// Receives model output as it arrives: `(shape_id, attempt, text)`. With
+/// Ollama streaming the text comes in pieces; other backends deliver the
+/// whole response as one piece.
+pub type TokenSink = Arc<dyn Fn(&str, u32, &str) + Send + Sync>;
+
+/// Post-validation hooks registered on an `LlmClient`, per shape.
+#[derive(Clone, Default)]
+struct ValidationHooks {
+    feature_design: Vec<ValidationHook<FeatureDesignOutput, FeatureDesignInput>>,
+    formation: Vec<ValidationHook<FormationOutput, FormationInput>>,
+    formation_grid: Vec<ValidationHook<FormationGridOutput, FormationGridInput>>,
+    requirements: Vec<ValidationHook<RequirementsOutput, RequirementsInput>>,
+    api_design: Vec<ValidationHook<ApiDesignOutput, ApiDesignInput>>,
+    risk_assessment: Vec<ValidationHook<RiskAssessmentOutput, RiskAssessmentInput>>,
+    // Data-driven shapes, keyed by shape ID
+    template: HashMap<String, Vec<ValidationHook<Value, Value>>>,
+}
+
+impl ValidationHooks {
+    /// The hooks every client starts with: Formation's coordinate count.
+    fn builtin() -> Self {
+        Self {
+            formation: vec![Arc::new(check_formation_unit_count)],
+            ..Self::default()
+        }
+    }
+}
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Parse error reported, and fed back to the model, for an empty or
+// whitespace-only response
+const NO_CONTENT_ERROR: &str = "model returned no content (the response was empty); reply with the JSON object";
+
+// Fewest risks a FeatureDesign must list unless configured otherwise
+const DEFAULT_MIN_RISKS: u32 = 2;
+
+/// HTTP/1.1-only reqwest client with no upgrade, as every backend expects.
+/// Advertises gzip and brotli, and decodes compressed responses from
+/// gateways that use them.
+fn build_http(connect_timeout: Duration, request_timeout: Duration) -> Client {
+    Client::builder()
+        .http1_only()
+        .no_proxy()
+        .gzip(true)
+        .brotli(true)
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .build()
+        .unwrap_or_else(|_| Client::new())
+}
+
+/// How long an endpoint that failed at the transport level is passed over.
+const ENDPOINT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Base URLs of interchangeable LLM instances, used round-robin. An endpoint
+/// with a transport failure is marked down for `ENDPOINT_COOLDOWN` and only
+/// tried after the healthy ones.
+#[derive(Debug)]
+struct EndpointPool {
+    urls: Vec<String>,
+    next: AtomicUsize,
+    down_until: Mutex<Vec<Option<Instant>>>,
+}
+
+impl EndpointPool {
+    fn new(urls: Vec<String>) -> Self {
+        let down_until = Mutex::new(vec![None; urls.len()]);
+        Self {
+            urls,
+            next: AtomicUsize::new(0),
+            down_until,
+        }
+    }
+
+    /// Endpoint indices to try for one call: healthy endpoints in round-robin
+    /// order, then endpoints that are down.
+    fn order(&self) -> Vec<usize> {
+        let n = self.urls.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % n;
+        let now = Instant::now();
+        let down_until = self.down_until.lock().unwrap_or_else(|e| e.into_inner());
+        let (healthy, down): (Vec<usize>, Vec<usize>) = (0..n)
+            .map(|i| (start + i) % n)
+            .partition(|&i| down_until[i].is_none_or(|until| until <= now));
+        healthy.into_iter().chain(down).collect()
+    }
+
+    fn set_down(&self, idx: usize, down: bool) {
+        let mut down_until = self.down_until.lock().unwrap_or_else(|e| e.into_inner());
+        down_until[idx] = down.then(|| Instant::now() + ENDPOINT_COOLDOWN);
+    }
+}
+
+/// Stops calling the LLM after `threshold` consecutive transport failures:
+/// calls fail with `CircuitOpen` until `cooldown` has passed, then one call
+/// goes through as a probe. A probe that reaches the backend closes the
+/// circuit; one that fails opens it for another `cooldown`.
+#[derive(Debug)]
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    // Set while the circuit is open: calls before this instant fail fast
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Fail fast while the circuit is open. Once the cooldown has passed, the
+    /// caller is let through as the probe and later callers wait another
+    /// cooldown, so a probe that never finishes can't hold the circuit open.
+    fn check(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < open_until {
+            return Err(CircuitOpen {
+                consecutive_failures: state.consecutive_failures,
+                retry_after: open_until - now,
+            });
+        }
+        state.open_until = Some(now + self.cooldown);
+        tracing::info!("LLM circuit half-open, probing the backend");
+        Ok(())
+    }
+
+    /// Count a call that reached the backend or failed to.
+    fn record(&self, transport_failure: bool) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !transport_failure {
+            if state.open_until.is_some() {
+                tracing::info!("LLM circuit closed");
+            }
+            *state = BreakerState::default();
+            return;
+        }
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.threshold {
+            if state.open_until.is_none() {
+                tracing::warn!(
+                    consecutive_failures = state.consecutive_failures,
+                    cooldown = ?self.cooldown,
+                    "LLM circuit opened"
+                );
+            }
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct LlmClient {
     http: Client,
-    base_url: String,
+    endpoints: Arc<EndpointPool>,
     model: String,
-    is_ollama: bool,
+    backend: BackendKind,
+    // Backend was set explicitly: use the base URL verbatim as the endpoint
+    backend_explicit: bool,
+    api_key: Option<String>,
+    // Extra headers on every backend request (see `with_headers`)
+    headers: HeaderMap,
+    // Send `Connection: close` instead of reusing pooled connections
+    connection_close: bool,
+    // Ollama's `keep_alive`, how long the model stays loaded after a call
+    ollama_keep_alive: Option<Value>,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    jitter: Jitter,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
+    feedback_history: usize,
+    local_repair: bool,
+    coerce_bools: bool,
+    metrics: Option<Arc<Metrics>>,
+    max_attempts: u32,
+    // Fewest risks a FeatureDesign must list (see `with_min_risks`)
+    min_risks: u32,
+    // Prompt size in estimated tokens above which a warning is logged
+    prompt_token_budget: Option<usize>,
+    fail_fast: bool,
+    total_deadline: Option<Duration>,
+    // The caller's deadline for this generation (see `with_deadline`)
+    deadline: Option<Instant>,
+    stream: bool,
+    seed: Option<u64>,
+    // Added to `seed` so best_of candidates sample differently (see `best_of`)
+    seed_offset: u64,
+    temperature: Option<f32>,
+    // Sequences that end generation (see `with_stop_sequences`)
+    stop: Vec<String>,
+    hooks: ValidationHooks,
+    prompt_sink: Option<PromptSink>,
+    token_sink: Option<TokenSink>,
+    cancel: Option<CancellationToken>,
+    // Replaces the HTTP backends when set (see `with_llm_backend`)
+    llm_backend: Option<Arc<dyn LlmBackend>>,
+    // Receives every prompt and raw response (see `with_transcript`)
+    transcript: Option<Arc<Transcript>>,
+    // Responses to first prompts that passed validation (see `with_prompt_cache`)
+    prompt_cache: Option<Arc<PromptCache>>,
+    // Receives the response that passed validation (see `with_raw_output`)
+    raw_output: Option<Arc<RawOutput>>,
+    // Shared by clones, like `endpoints`, and replaced with them (see
+    // `with_circuit_breaker` and `with_endpoints`)
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+/// The cleaned text of the response that passed validation, before repair
+/// or post-processing, e.g. to return it alongside the typed output for
+/// auditing (see `LlmClient::with_raw_output`).
+#[derive(Debug, Default)]
+pub struct RawOutput {
+    text: Mutex<Option<String>>,
+}
+
+impl RawOutput {
+    pub fn set(&self, text: &str) {
+        *self.text.lock().unwrap_or_else(|e| e.into_inner()) = Some(text.to_string());
+    }
+
+    /// The text, if a response has passed validation, leaving the slot empty.
+    pub fn take(&self) -> Option<String> {
+        self.text.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+/// The output still failed validation when the retry loop gave up.
+///
+/// Returned (inside `anyhow::Error`) so callers can `downcast_ref` to get the
+/// individual errors from the final attempt.
+#[derive(Debug)]
+pub struct ValidationFailed {
+    pub attempts: u32,
+    pub errors: Vec<ValidationError>,
+    /// The last output that parsed as JSON, for best-effort use with
+    /// `types::coerce`.
+    pub last_value: Option<Value>,
+}
+
+impl std::fmt::Display for ValidationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LLM failed to produce valid output after {} attempts", self.attempts)?;
+        for (i, err) in self.errors.iter().enumerate() {
+            write!(f, "{} {err}", if i == 0 { ":" } else { ";" })?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationFailed {}
+
+/// The caller's deadline (see `LlmClient::with_deadline`) passed, or too
+/// little of it was left for another attempt.
+#[derive(Debug)]
+pub struct DeadlineExceeded {
+    /// LLM calls made before giving up, including one cut off mid-call.
+    pub attempts: u32,
 }
 
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadline exceeded after {} attempts", self.attempts)
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// The LLM call was not made because the circuit breaker is open after
+/// repeated transport failures (see `LlmClient::with_circuit_breaker`).
+///
+/// Returned (inside `anyhow::Error`) so callers can `downcast_ref` it.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub consecutive_failures: u32,
+    /// Time until the next probe is let through.
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "LLM backend unavailable: circuit open after {} consecutive transport failures, retry in {}s",
+            self.consecutive_failures,
+            self.retry_after.as_secs_f64().ceil()
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
 impl LlmClient {
     pub fn new(base_url: String) -> Self {
         Self::new_with_model(base_url, None)
     }
 
     pub fn new_with_model(base_url: String, model: Option<String>) -> Self {
-        // Detect if this is an Ollama endpoint
-        let is_ollama = base_url.contains("11434") || base_url.contains("/api/generate");
+        // Guess the backend from the URL; `with_backend` overrides this
+        let backend = BackendKind::detect(&base_url);
         
         // Determine the model name
         let model = model.unwrap_or_else(|| {
@@ -29,18 +476,481 @@ impl LlmClient {
                 .unwrap_or_else(|_| "llama3.2:3b".to_string())
         });
 
-        // Create reqwest client with HTTP/1.1 only and no upgrade
-        let http = Client::builder()
-            .http1_only()
-            .no_proxy()
-            .build()
-            .unwrap_or_else(|_| Client::new());
-        
         Self {
-            http,
-            base_url,
+            http: build_http(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT),
+            endpoints: Arc::new(EndpointPool::new(vec![base_url])),
             model,
-            is_ollama,
+            backend,
+            backend_explicit: false,
+            api_key: None,
+            headers: HeaderMap::new(),
+            connection_close: false,
+            ollama_keep_alive: None,
+            backoff_base: Duration::from_millis(250),
+            backoff_max: Duration::from_secs(4),
+            jitter: Jitter::None,
+            feedback: FeedbackVerbosity::Full,
+            schema_format: SchemaFormat::Bullets,
+            feedback_history: 1,
+            local_repair: false,
+            coerce_bools: false,
+            metrics: None,
+            max_attempts: 3,
+            min_risks: DEFAULT_MIN_RISKS,
+            prompt_token_budget: None,
+            fail_fast: false,
+            total_deadline: None,
+            deadline: None,
+            stream: false,
+            seed: None,
+            seed_offset: 0,
+            temperature: None,
+            stop: Vec::new(),
+            hooks: ValidationHooks::builtin(),
+            prompt_sink: None,
+            token_sink: None,
+            cancel: None,
+            llm_backend: None,
+            transcript: None,
+            prompt_cache: None,
+            raw_output: None,
+            breaker: None,
+        }
+    }
+
+    /// Send prompts to `backend` instead of an HTTP endpoint, e.g. a
+    /// `MockLlmBackend` in tests. The base URL, endpoints and `BackendKind`
+    /// are then unused.
+    pub fn with_llm_backend(mut self, backend: Arc<dyn LlmBackend>) -> Self {
+        self.llm_backend = Some(backend);
+        self
+    }
+
+    /// Add every prompt and the model's raw response to it to `transcript`,
+    /// e.g. to write them out with a `Recorder`.
+    pub fn with_transcript(mut self, transcript: Arc<Transcript>) -> Self {
+        self.transcript = Some(transcript);
+        self
+    }
+
+    /// Answer a generation's first prompt from `cache` when it holds a
+    /// response for it, and cache the response that passes validation under
+    /// the first prompt. A repeated request then returns without calling the
+    /// model. Retry prompts are never looked up. Entries are scoped to the
+    /// backend, endpoints, model, temperature, seed and stop sequences, so
+    /// changing any of them misses. A hit is not an LLM call: it isn't
+    /// timed in `llm_call_seconds` or pushed to the transcript.
+    pub fn with_prompt_cache(mut self, cache: Arc<PromptCache>) -> Self {
+        self.prompt_cache = Some(cache);
+        self
+    }
+
+    /// Put the cleaned text of the response that passes validation in `slot`,
+    /// as the model wrote it: fields the validator ignores are kept, and
+    /// local repair and post-processing are not applied. `best_of` keeps the
+    /// winning candidate's text.
+    pub fn with_raw_output(mut self, slot: Arc<RawOutput>) -> Self {
+        self.raw_output = Some(slot);
+        self
+    }
+
+    /// After `threshold` consecutive LLM calls fail at the transport level
+    /// (every endpoint unreachable), fail calls with `CircuitOpen` for
+    /// `cooldown` instead of trying the backend, then let one call through to
+    /// probe it. Answers the backend gives, even errors, count as reaching it.
+    /// Clones of this client share the breaker, unless `with_endpoints` gives
+    /// them endpoints of their own. A `threshold` of 0 disables it.
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.breaker = (threshold > 0).then(|| Arc::new(CircuitBreaker::new(threshold, cooldown)));
+        self
+    }
+
+    /// Use `model` instead of the one given at construction.
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Spread LLM calls round-robin over `urls`, replacing the base URL. All
+    /// must speak the same backend API, which is guessed again from the first
+    /// URL unless `with_backend` set it. A call that fails to reach its
+    /// endpoint moves on to the next one without using up a retry; the
+    /// failed endpoint is skipped for 30 seconds. A circuit breaker gets a
+    /// fresh state with the same settings, so these endpoints' failures don't
+    /// open the circuit of the client this one was cloned from, or the
+    /// reverse.
+    pub fn with_endpoints(mut self, urls: Vec<String>) -> Self {
+        if let Some(first) = urls.first() {
+            if !self.backend_explicit {
+                self.backend = BackendKind::detect(first);
+            }
+            self.endpoints = Arc::new(EndpointPool::new(urls));
+            self.breaker = self
+                .breaker
+                .as_ref()
+                .map(|breaker| Arc::new(CircuitBreaker::new(breaker.threshold, breaker.cooldown)));
+        }
+        self
+    }
+
+    /// What besides the prompt decides the response, as the `PromptCache`
+    /// scope. A custom `LlmBackend` is one scope, whatever it is.
+    fn cache_scope(&self) -> String {
+        let backend = match &self.llm_backend {
+            Some(_) => "custom".to_string(),
+            None => format!("{:?} {}", self.backend, self.endpoints.urls.join(",")),
+        };
+        format!(
+            "{backend} model={} temperature={:?} seed={:?} stop={:?}",
+            self.model,
+            self.temperature,
+            self.sampling_seed(),
+            self.stop
+        )
+    }
+
+    /// The seed sent to the backend: `seed`, shifted for best_of candidates.
+    fn sampling_seed(&self) -> Option<u64> {
+        self.seed.map(|seed| seed.wrapping_add(self.seed_offset))
+    }
+
+    /// The endpoint URLs calls are spread over.
+    pub fn endpoint_urls(&self) -> &[String] {
+        &self.endpoints.urls
+    }
+
+    /// Timeouts on the underlying HTTP client: `connect` for establishing a
+    /// connection, `request` for a whole request including the response body
+    /// (default 10s and 120s). These catch stalled sockets; use
+    /// `with_total_deadline` to bound a whole generation.
+    pub fn with_http_timeouts(mut self, connect: Duration, request: Duration) -> Self {
+        self.http = build_http(connect, request);
+        self
+    }
+
+    /// Sampling seed sent to the backend. Reproducible output also needs
+    /// `with_temperature(0.0)`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sampling temperature sent to the backend (backend default when unset).
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Stop sequences: the backend ends generation when the model outputs
+    /// one, and leaves it out of the response. Sent as Ollama's
+    /// `options.stop` and OpenAI's `stop`. Use them to cut off prose after
+    /// the JSON, e.g. `"\n\n"`. A sequence the JSON itself can contain ends
+    /// it early, so `"```"` only suits models that don't open with a fence.
+    /// None by default.
+    pub fn with_stop_sequences(mut self, stop: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.stop = stop.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Use `backend` instead of guessing from the URL. `base_url` is then used
+    /// verbatim as the endpoint, e.g. an Ollama behind a reverse proxy at
+    /// `https://llm.example.com/ollama/generate`.
+    pub fn with_backend(mut self, backend: BackendKind) -> Self {
+        self.backend = backend;
+        self.backend_explicit = true;
+        self
+    }
+
+    /// Bearer token sent as `Authorization` on backend requests.
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+
+    /// Attach `headers` to every backend request, e.g. a tenant header or
+    /// trace context for a gateway in front of the model (default none).
+    /// Adds to headers set by earlier calls; a name given again replaces its
+    /// value. They are applied last, so they override the built-in ones,
+    /// including the `Authorization` set by `with_api_key`.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        for (name, value) in headers.iter() {
+            self.headers.insert(name.clone(), value.clone());
+        }
+        self
+    }
+
+    /// Send `Connection: close` on every backend request, so each call opens
+    /// a new connection (default off: connections are pooled and reused).
+    /// For proxies or load balancers that mishandle reused connections.
+    pub fn with_connection_close(mut self, close: bool) -> Self {
+        self.connection_close = close;
+        self
+    }
+
+    /// Ollama's `keep_alive`: how long the model stays loaded after each
+    /// call, as a duration such as `"30m"` or in seconds, `-1` keeping it
+    /// loaded indefinitely. Ollama's own default (5 minutes) applies when
+    /// unset. Ignored by the other backends.
+    pub fn with_ollama_keep_alive(mut self, keep_alive: &str) -> Self {
+        self.ollama_keep_alive = Some(match keep_alive.parse::<i64>() {
+            Ok(secs) => Value::from(secs),
+            Err(_) => Value::from(keep_alive),
+        });
+        self
+    }
+
+    /// Stream Ollama responses instead of waiting for the whole body, so a
+    /// finished JSON object or an obviously off-track (prose) generation can
+    /// be cut off early. See `read_ollama_stream`.
+    pub fn with_streaming(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Total LLM calls per generation, including the first (default 3).
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Warn when a prompt is estimated (see `estimate_tokens`) to exceed
+    /// `tokens`, e.g. the model's context window less room for the output.
+    /// Retry prompts grow with error feedback, and a prompt that overflows the
+    /// context usually just comes back as bad JSON. Off by default.
+    pub fn with_prompt_token_budget(mut self, tokens: usize) -> Self {
+        self.prompt_token_budget = Some(tokens);
+        self
+    }
+
+    /// Fewest risks a FeatureDesign must list (default 2); 0 turns the check
+    /// off. A design with fewer is retried with feedback asking for more. A
+    /// request's `min_risks` overrides it.
+    pub fn with_min_risks(mut self, min: u32) -> Self {
+        self.min_risks = min;
+        self
+    }
+
+    /// Make exactly one attempt with no backoff or retry feedback, returning
+    /// its validation errors immediately as `ValidationFailed`.
+    pub fn with_fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Bound a whole generation, retries and backoff included, by wall-clock
+    /// time. A retry is skipped when the time left is less than the backoff
+    /// plus the duration of the previous call, and an in-flight call is cut
+    /// off at the deadline. `max_attempts` still applies.
+    pub fn with_total_deadline(mut self, deadline: Duration) -> Self {
+        self.total_deadline = Some(deadline);
+        self
+    }
+
+    /// Stop generating at `deadline`, e.g. the caller's gRPC deadline. Works
+    /// like `with_total_deadline`, and whichever comes first applies; running
+    /// out of this one is a `DeadlineExceeded` error rather than the last
+    /// attempt's errors, as no one is waiting for the result any more.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Run `hook` on every FeatureDesign output that passes schema validation.
+    /// Hooks run in registration order and all their errors are reported.
+    pub fn with_feature_design_hook(
+        mut self,
+        hook: impl Fn(&FeatureDesignOutput, &FeatureDesignInput) -> Vec<ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.feature_design.push(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` on every Formation output that passes schema validation.
+    /// It runs after the built-in coordinate-count hook, which every client
+    /// has registered first.
+    pub fn with_formation_hook(
+        mut self,
+        hook: impl Fn(&FormationOutput, &FormationInput) -> Vec<ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.formation.push(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` on every FormationGrid output that passes schema validation
+    /// and the built-in count, bounds and overlap checks.
+    pub fn with_formation_grid_hook(
+        mut self,
+        hook: impl Fn(&FormationGridOutput, &FormationGridInput) -> Vec<ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.formation_grid.push(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` on every Requirements output that passes schema validation
+    /// and the built-in unique-id check.
+    pub fn with_requirements_hook(
+        mut self,
+        hook: impl Fn(&RequirementsOutput, &RequirementsInput) -> Vec<ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.requirements.push(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` on every ApiDesign output that passes schema validation and
+    /// the built-in unique `(path, method)` check.
+    pub fn with_api_design_hook(
+        mut self,
+        hook: impl Fn(&ApiDesignOutput, &ApiDesignInput) -> Vec<ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.api_design.push(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` on every RiskAssessment output that passes schema
+    /// validation and the built-in rating, score and coverage checks.
+    pub fn with_risk_assessment_hook(
+        mut self,
+        hook: impl Fn(&RiskAssessmentOutput, &RiskAssessmentInput) -> Vec<ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.risk_assessment.push(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` on every output of the data-driven shape `shape_id` that
+    /// passes schema validation.
+    pub fn with_shape_hook(
+        mut self,
+        shape_id: &str,
+        hook: impl Fn(&Value, &Value) -> Vec<ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks
+            .template
+            .entry(shape_id.to_string())
+            .or_default()
+            .push(Arc::new(hook));
+        self
+    }
+
+    /// Pass every prompt, including accumulated retry feedback, to `sink`
+    /// before it is sent.
+    pub fn with_prompt_sink(
+        mut self,
+        sink: impl Fn(&str, u32, &str) + Send + Sync + 'static,
+    ) -> Self {
+        self.prompt_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Pass model output to `sink` as it is received, before it is parsed or
+    /// validated. Combine with `with_streaming(true)` to get Ollama's output
+    /// piece by piece.
+    pub fn with_token_sink(
+        mut self,
+        sink: impl Fn(&str, u32, &str) + Send + Sync + 'static,
+    ) -> Self {
+        self.token_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Append every prompt to the file at `path`, each under a
+    /// `=== {shape_id} attempt {n} ===` header.
+    pub fn with_prompt_log(self, path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("failed to open prompt log {}: {e}", path.display()))?;
+        let file = Mutex::new(file);
+        Ok(self.with_prompt_sink(move |shape_id, attempt, prompt| {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = writeln!(file, "=== {shape_id} attempt {attempt} ===\n{prompt}\n") {
+                tracing::warn!(error = %e, "failed to write prompt log");
+            }
+        }))
+    }
+
+    /// Abort generation as soon as `cancel` fires: an in-flight LLM call or
+    /// backoff sleep is dropped and `generate_*` returns an error. Used to
+    /// stop burning tokens for a client that has gone away.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Record LLM call and validation latencies into `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Set the exponential backoff between retries: `base` after the first
+    /// failure, doubling each time, never more than `max`.
+    pub fn with_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.backoff_base = base;
+        self.backoff_max = max;
+        self
+    }
+
+    /// Randomize the backoff so many clients retrying against one backend
+    /// don't retry in lockstep. Off by default.
+    pub fn with_backoff_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// How much validation feedback retry prompts include (default: every error).
+    pub fn with_feedback_verbosity(mut self, feedback: FeedbackVerbosity) -> Self {
+        self.feedback = feedback;
+        self
+    }
+
+    /// How prompts describe the output schema (default: bullet list).
+    pub fn with_schema_format(mut self, format: SchemaFormat) -> Self {
+        self.schema_format = format;
+        self
+    }
+
+    /// How many failed attempts' feedback retry prompts include, most recent
+    /// last (default: 1, only the previous attempt). Deeper history shows a
+    /// stubborn model its pattern of mistakes at the cost of longer prompts.
+    pub fn with_feedback_history(mut self, depth: usize) -> Self {
+        self.feedback_history = depth.max(1);
+        self
+    }
+
+    /// Try conservative local fixes (see `types::repair`) on output that fails
+    /// the schema before spending a retry on it. Each fix is logged. Off by
+    /// default.
+    pub fn with_local_repair(mut self, enabled: bool) -> Self {
+        self.local_repair = enabled;
+        self
+    }
+
+    /// Accept `"true"`, `"no"`, `1` and similar where the schema wants a
+    /// boolean, rewriting them to real booleans before validation (see
+    /// `types::coerce_bools`). Off by default: strict schemas accept only
+    /// `true` and `false`.
+    pub fn with_bool_coercion(mut self, enabled: bool) -> Self {
+        self.coerce_bools = enabled;
+        self
+    }
+
+    /// Delay before retry number `retry` (1-based). `prev` is the previous
+    /// delay, used by decorrelated jitter.
+    fn backoff_delay(&self, retry: u32, prev: Duration) -> Duration {
+        let exp = self
+            .backoff_base
+            .saturating_mul(1u32 << retry.saturating_sub(1).min(16))
+            .min(self.backoff_max);
+
+        match self.jitter {
+            Jitter::None => exp,
+            Jitter::Full => exp.mul_f64(random_unit()),
+            Jitter::Decorrelated => {
+                let upper = prev.saturating_mul(3).max(self.backoff_base);
+                let span = upper.saturating_sub(self.backoff_base);
+                (self.backoff_base + span.mul_f64(random_unit())).min(self.backoff_max)
+            }
         }
     }
 
@@ -49,99 +959,362 @@ impl LlmClient {
         input: &FeatureDesignInput,
         output_schema: &TypeDef,
     ) -> Result<FeatureDesignOutput> {
-        let max_retries = 3;
-        let mut last_errors: Option<Vec<ValidationError>> = None;
-        let mut last_json_error: Option<String> = None;
+        let min_risks = input.min_risks.unwrap_or(self.min_risks);
+        self.generate(
+            "FeatureDesign",
+            output_schema,
+            |last_errors, last_json_error| {
+                build_prompt(input, output_schema, last_errors, last_json_error, min_risks, self.feedback, self.schema_format)
+            },
+            |typed: &FeatureDesignOutput| {
+                let mut errors: Vec<ValidationError> = check_component_count(input, typed).into_iter().collect();
+                errors.extend(check_risk_count(min_risks, typed));
+                errors.extend(check_dependencies(typed));
+                errors.extend(run_hooks(&self.hooks.feature_design, typed, input));
+                errors
+            },
+        )
+        .await
+    }
 
-        for attempt in 0..max_retries {
-            eprintln!("[DEMO] Attempt {} of {}", attempt + 1, max_retries);
-            if let Some(ref errors) = last_errors {
-                eprintln!("[DEMO] Previous validation errors:");
-                for err in errors {
-                    eprintln!("[DEMO]   - {}", err);
-                }
-            }
-            if let Some(ref json_err) = last_json_error {
-                eprintln!("[DEMO] Previous JSON parse error: {}", json_err);
+    pub async fn generate_formation(
+        &self,
+        input: &FormationInput,
+        output_schema: &TypeDef,
+    ) -> Result<FormationOutput> {
+        // A per-formation seed overrides the client default
+        let seeded;
+        let llm = match input.seed {
+            Some(seed) => {
+                seeded = self.clone().with_seed(seed);
+                &seeded
             }
-            
-            let prompt = build_prompt(input, output_schema, last_errors.as_ref(), last_json_error.as_deref());
+            None => self,
+        };
 
-            let llm_json_text = self.call_llm(&prompt).await?;
-            
-            // Log the raw response for debugging (first 500 chars)
-            if attempt == 0 {
-                let preview = if llm_json_text.len() > 500 {
-                    format!("{}...", &llm_json_text[..500])
+        let canonical = input
+            .canonical
+            .as_deref()
+            .and_then(|name| FormationOutput::canonical(name, input.unit_count))
+            .and_then(|output| {
+                // Built-in output still goes through the same checks as the LLM's
+                let value = serde_json::to_value(&output).unwrap_or(Value::Null);
+                let mut errors = validate(output_schema, &value).err().unwrap_or_default();
+                if errors.is_empty() {
+                    errors.extend(run_hooks(&self.hooks.formation, &output, input));
+                }
+                if errors.is_empty() {
+                    Some(output)
                 } else {
-                    llm_json_text.clone()
-                };
-                eprintln!("[DEMO] LLM raw response (first 500 chars):\n{}", preview);
+                    eprintln!("[DEMO] Canonical formation failed validation, asking the LLM: {:?}", errors);
+                    None
+                }
+            });
+
+        // Number of attempts so far that returned the wrong coordinate count
+        let count_mismatches = AtomicU32::new(0);
+
+        let output = match canonical {
+            Some(output) => {
+                eprintln!(
+                    "[DEMO] Formation generated canonically ({}), skipping the LLM",
+                    input.canonical.as_deref().unwrap_or_default()
+                );
+                output
             }
+            None => llm
+                .generate(
+                    "Formation",
+                    output_schema,
+                    |last_errors, last_json_error| {
+                        build_formation_prompt(
+                            input,
+                            output_schema,
+                            last_errors,
+                            last_json_error,
+                            count_mismatches.load(Ordering::Relaxed) >= 2,
+                            self.feedback,
+                            self.schema_format,
+                        )
+                    },
+                    |typed: &FormationOutput| {
+                        if input
+                            .accepted_counts()
+                            .is_some_and(|accepted| !count_accepted(&accepted, typed.coordinates.len()))
+                        {
+                            count_mismatches.fetch_add(1, Ordering::Relaxed);
+                        }
+                        run_hooks(&self.hooks.formation, typed, input)
+                    },
+                )
+                .await?,
+        };
+        Ok(formation_input_pipeline(input).apply(output))
+    }
 
-            // Try to parse JSON - retry if it fails
-            let value: Value = match serde_json::from_str(&llm_json_text) {
-                Ok(v) => {
-                    v
-                }
-                Err(e) => {
-                    let error_msg = format!("{}", e);
-                    eprintln!("[DEMO] JSON parse error: {}", error_msg);
-                    eprintln!("[DEMO] Response length: {}, First 200 chars: {}", 
-                        llm_json_text.len(),
-                        if llm_json_text.len() > 200 { &llm_json_text[..200] } else { &llm_json_text }
-                    );
-                    
-                    // If this is the last attempt, return error
-                    if attempt == max_retries - 1 {
-                        return Err(anyhow!("LLM did not return valid JSON after {} attempts. Last error: {}", max_retries, error_msg));
-                    }
-                    
-                    // Otherwise, save error and retry
-                    last_json_error = Some(error_msg);
-                    last_errors = None; // Clear validation errors since we didn't get that far
-                    if attempt < max_retries - 1 {
-                        eprintln!("[DEMO] Retrying with JSON error feedback...\n");
-                    }
-                    continue;
-                }
-            };
+    /// Like `generate_formation`, but on a discrete grid: every unit gets its
+    /// own in-bounds cell.
+    pub async fn generate_formation_grid(
+        &self,
+        input: &FormationGridInput,
+        output_schema: &TypeDef,
+    ) -> Result<FormationGridOutput> {
+        let count_mismatches = AtomicU32::new(0);
 
-            match validate(output_schema, &value) {
-                Ok(()) => {
-                    eprintln!("[DEMO] ✓ Validation passed! Returning result.");
-                    let typed: FeatureDesignOutput = serde_json::from_value(value)?;
-                    return Ok(typed);
-                }
-                Err(errors) => {
-                    eprintln!("[DEMO] ✗ Validation failed with {} error(s)", errors.len());
-                    last_errors = Some(errors);
-                    last_json_error = None; // Clear JSON error since JSON was valid
-                    if attempt < max_retries - 1 {
-                        eprintln!("[DEMO] Retrying...\n");
-                    }
-                    continue;
+        self.generate(
+            "FormationGrid",
+            output_schema,
+            |last_errors, last_json_error| {
+                build_formation_grid_prompt(
+                    input,
+                    output_schema,
+                    last_errors,
+                    last_json_error,
+                    count_mismatches.load(Ordering::Relaxed) >= 2,
+                    self.feedback,
+                    self.schema_format,
+                )
+            },
+            |typed: &FormationGridOutput| {
+                let accepted = input.unit_count..=input.unit_count;
+                if let Some(err) = check_unit_count(&accepted, typed.coordinates.len()) {
+                    count_mismatches.fetch_add(1, Ordering::Relaxed);
+                    return vec![err];
                 }
-            }
+                let mut errors = check_grid_cells(input, typed);
+                errors.extend(run_hooks(&self.hooks.formation_grid, typed, input));
+                errors
+            },
+        )
+        .await
+    }
+
+    /// Extract requirements from `input.document`. Requirement ids must be
+    /// unique; duplicates are sent back to the model like schema errors.
+    pub async fn generate_requirements(
+        &self,
+        input: &RequirementsInput,
+        output_schema: &TypeDef,
+    ) -> Result<RequirementsOutput> {
+        self.generate(
+            "Requirements",
+            output_schema,
+            |last_errors, last_json_error| {
+                build_requirements_prompt(
+                    input,
+                    output_schema,
+                    last_errors,
+                    last_json_error,
+                    self.feedback,
+                    self.schema_format,
+                )
+            },
+            |typed: &RequirementsOutput| {
+                let mut errors = check_unique_requirement_ids(typed);
+                errors.extend(run_hooks(&self.hooks.requirements, typed, input));
+                errors
+            },
+        )
+        .await
+    }
+
+    /// Design REST paths for `input.operations` on `input.resource`. Each
+    /// `(path, method)` pair must be unique; duplicates are sent back to the
+    /// model like schema errors.
+    pub async fn generate_api_design(&self, input: &ApiDesignInput, output_schema: &TypeDef) -> Result<ApiDesignOutput> {
+        self.generate(
+            "ApiDesign",
+            output_schema,
+            |last_errors, last_json_error| {
+                build_api_design_prompt(
+                    input,
+                    output_schema,
+                    last_errors,
+                    last_json_error,
+                    self.feedback,
+                    self.schema_format,
+                )
+            },
+            |typed: &ApiDesignOutput| {
+                let mut errors = check_unique_operations(typed);
+                errors.extend(run_hooks(&self.hooks.api_design, typed, input));
+                errors
+            },
+        )
+        .await
+    }
+
+    /// Score every risk of `input.design` by likelihood and impact, each from
+    /// 1 to 5. Ratings out of range, a `score` that isn't `likelihood *
+    /// impact` and risks left unscored are sent back to the model like schema
+    /// errors.
+    pub async fn generate_risk_assessment(
+        &self,
+        input: &RiskAssessmentInput,
+        output_schema: &TypeDef,
+    ) -> Result<RiskAssessmentOutput> {
+        self.generate(
+            "RiskAssessment",
+            output_schema,
+            |last_errors, last_json_error| {
+                build_risk_assessment_prompt(
+                    input,
+                    output_schema,
+                    last_errors,
+                    last_json_error,
+                    self.feedback,
+                    self.schema_format,
+                )
+            },
+            |typed: &RiskAssessmentOutput| {
+                let mut errors = check_risk_scores(input, typed);
+                errors.extend(run_hooks(&self.hooks.risk_assessment, typed, input));
+                errors
+            },
+        )
+        .await
+    }
+
+    /// Generate output for a data-driven shape: the prompt is `template`
+    /// rendered with `input` (see `render_template`) and the result is
+    /// validated against `output_schema` only.
+    pub async fn generate_from_template(
+        &self,
+        shape_id: &str,
+        template: &str,
+        input: &Value,
+        output_schema: &TypeDef,
+    ) -> Result<Value> {
+        let task = render_template(template, input);
+        let hooks = self.hooks.template.get(shape_id).map(Vec::as_slice).unwrap_or_default();
+        self.generate(
+            shape_id,
+            output_schema,
+            |last_errors, last_json_error| {
+                build_template_prompt(&task, output_schema, last_errors, last_json_error, self.feedback, self.schema_format)
+            },
+            |typed: &Value| run_hooks(hooks, typed, input),
+        )
+        .await
+    }
+
+    /// The schema as this client's prompts describe it (see
+    /// `with_schema_format`).
+    pub fn schema_description(&self, output_schema: &TypeDef) -> String {
+        render_schema(output_schema, self.schema_format)
+    }
+
+    /// The first prompt a generation of `spec` would send for `input`, built
+    /// by the same builder as `generate_*`, without calling the model. Retry
+    /// prompts add feedback on the previous attempt to it. Built-in shapes
+    /// decode `input` into their input type; other shapes render their
+    /// `prompt_template`.
+    pub fn explain_prompt(&self, spec: &ShapeSpec, input: &Value) -> Result<String> {
+        fn typed<T: DeserializeOwned>(shape_id: &str, input: &Value) -> Result<T> {
+            T::deserialize(input).map_err(|e| anyhow!("invalid {shape_id} input: {e}"))
         }
 
-        Err(anyhow!(
-            "LLM failed to produce valid output after {} attempts",
-            max_retries
-        ))
+        let schema = &spec.output_typedef;
+        let (feedback, format) = (self.feedback, self.schema_format);
+        Ok(match spec.id.as_str() {
+            "FeatureDesign" => {
+                let input: FeatureDesignInput = typed(&spec.id, input)?;
+                let min_risks = input.min_risks.unwrap_or(self.min_risks);
+                build_prompt(&input, schema, None, None, min_risks, feedback, format)
+            }
+            "Formation" => build_formation_prompt(&typed(&spec.id, input)?, schema, None, None, false, feedback, format),
+            "FormationGrid" => {
+                build_formation_grid_prompt(&typed(&spec.id, input)?, schema, None, None, false, feedback, format)
+            }
+            "Requirements" => build_requirements_prompt(&typed(&spec.id, input)?, schema, None, None, feedback, format),
+            "ApiDesign" => build_api_design_prompt(&typed(&spec.id, input)?, schema, None, None, feedback, format),
+            "RiskAssessment" => {
+                build_risk_assessment_prompt(&typed(&spec.id, input)?, schema, None, None, feedback, format)
+            }
+            _ => {
+                let template = spec
+                    .prompt_template
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("shape {} has no prompt template", spec.id))?;
+                build_template_prompt(&render_template(template, input), schema, None, None, feedback, format)
+            }
+        })
     }
 
-    pub async fn generate_formation(
+    /// The retry loop shared by all shapes: build prompt, call the LLM, parse,
+    /// validate against `output_schema`, then run the shape's own `check` on
+    /// the typed output. Parse errors and validation errors (schema or `check`)
+    /// are fed back into the next prompt via `build_prompt`.
+    async fn generate<O, P, C>(
         &self,
-        input: &FormationInput,
+        shape_id: &str,
         output_schema: &TypeDef,
-    ) -> Result<FormationOutput> {
-        let max_retries = 3;
+        build_prompt: P,
+        check: C,
+    ) -> Result<O>
+    where
+        O: DeserializeOwned,
+        P: Fn(Option<&Vec<ValidationError>>, Option<&str>) -> String,
+        C: Fn(&O) -> Vec<ValidationError>,
+    {
+        let max_retries = if self.fail_fast { 1 } else { self.max_attempts };
         let mut last_errors: Option<Vec<ValidationError>> = None;
         let mut last_json_error: Option<String> = None;
+        let mut last_value: Option<Value> = None;
+        // Feedback from the last `feedback_history` failed attempts, oldest first
+        let mut history: VecDeque<PastAttempt> = VecDeque::new();
+        let mut delay = Duration::ZERO;
+        let total_deadline = self.total_deadline.map(|budget| Instant::now() + budget);
+        // Whichever deadline comes first; `caller` when it is the caller's
+        let (deadline, caller) = match (total_deadline, self.deadline) {
+            (Some(total), Some(caller)) if total < caller => (Some(total), false),
+            (_, Some(caller)) => (Some(caller), true),
+            (total, None) => (total, false),
+        };
+        // Records how many attempts this generation took, however it ends
+        let mut attempts = AttemptsGuard {
+            metrics: self.metrics.as_deref(),
+            attempts: 0,
+        };
+        let mut last_call = Duration::ZERO;
+        // The first prompt, which a successful response is cached under
+        let mut first_prompt: Option<String> = None;
 
         for attempt in 0..max_retries {
-            eprintln!("[DEMO] Formation attempt {} of {}", attempt + 1, max_retries);
+            if caller && deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                eprintln!("[DEMO] {} deadline passed before attempt {}", shape_id, attempt + 1);
+                return Err(DeadlineExceeded { attempts: attempt }.into());
+            }
+            if attempt > 0 {
+                delay = self.backoff_delay(attempt, delay);
+                if let Some(deadline) = deadline {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining < delay + last_call {
+                        eprintln!(
+                            "[DEMO] {} deadline: {:?} left, not enough for another attempt",
+                            shape_id, remaining
+                        );
+                        if caller {
+                            return Err(DeadlineExceeded { attempts: attempt }.into());
+                        }
+                        return Err(match last_errors {
+                            Some(errors) => ValidationFailed {
+                                attempts: attempt,
+                                errors,
+                                last_value,
+                            }
+                            .into(),
+                            None => anyhow!(
+                                "LLM did not return valid JSON within the total deadline ({} attempts). Last error: {}",
+                                attempt,
+                                last_json_error.unwrap_or_default()
+                            ),
+                        });
+                    }
+                }
+                self.cancellable(shape_id, tokio::time::sleep(delay)).await?;
+            }
+            eprintln!("[DEMO] {} attempt {} of {}", shape_id, attempt + 1, max_retries);
             if let Some(ref errors) = last_errors {
                 eprintln!("[DEMO] Previous validation errors:");
                 for err in errors {
@@ -152,18 +1325,97 @@ impl LlmClient {
                 eprintln!("[DEMO] Previous JSON parse error: {}", json_err);
             }
             
-            let prompt = build_formation_prompt(input, output_schema, last_errors.as_ref(), last_json_error.as_deref());
+            let mut prompt = build_prompt(last_errors.as_ref(), last_json_error.as_deref());
+            // The previous attempt is already in the prompt; add the ones before it
+            if history.len() > 1 {
+                push_earlier_feedback(&mut prompt, history.range(..history.len() - 1), self.feedback);
+            }
+            if let Some(sink) = &self.prompt_sink {
+                sink(shape_id, attempt + 1, &prompt);
+            }
+            let prompt_tokens = estimate_tokens(&prompt);
+            if let Some(budget) = self.prompt_token_budget.filter(|budget| prompt_tokens > *budget) {
+                tracing::warn!(shape_id, attempt = attempt + 1, prompt_tokens, budget, "prompt exceeds token budget");
+            }
+
+            let on_text = |text: &str| {
+                if let Some(sink) = &self.token_sink {
+                    sink(shape_id, attempt + 1, text);
+                }
+            };
+
+            let cached = match &self.prompt_cache {
+                Some(cache) if attempt == 0 => {
+                    first_prompt = Some(prompt.clone());
+                    cache.get(&self.cache_scope(), &prompt).await
+                }
+                _ => None,
+            };
+            let cache_hit = cached.is_some();
 
-            let llm_json_text = self.call_llm(&prompt).await?;
+            attempts.attempts = attempt + 1;
+            let call_started = Instant::now();
+            let llm_json_text = if let Some(cached) = cached {
+                tracing::info!(shape_id, "prompt cache hit");
+                cached
+            } else {
+                match deadline {
+                    Some(deadline) => {
+                        let deadline = tokio::time::Instant::from_std(deadline);
+                        self.cancellable(shape_id, tokio::time::timeout_at(deadline, self.call_llm(&prompt, &on_text)))
+                            .await?
+                            .map_err(|_| {
+                                if caller {
+                                    DeadlineExceeded { attempts: attempt + 1 }.into()
+                                } else {
+                                    anyhow!("{} exceeded its total deadline during attempt {}", shape_id, attempt + 1)
+                                }
+                            })??
+                    }
+                    None => self.cancellable(shape_id, self.call_llm(&prompt, &on_text)).await??,
+                }
+            };
+            // A cache hit made no LLM call: keep it out of the call latency
+            // and the transcript, which record what the model was asked
+            if !cache_hit {
+                let call_elapsed = call_started.elapsed();
+                if let Some(transcript) = &self.transcript {
+                    transcript.push(&prompt, &llm_json_text);
+                }
+                last_call = call_elapsed;
+                tracing::info!(shape_id, attempt = attempt + 1, prompt_tokens, elapsed = ?call_elapsed, "LLM call finished");
+                if let Some(metrics) = &self.metrics {
+                    metrics.llm_call_seconds.observe(call_elapsed);
+                }
+            }
             
-            // Try to parse JSON - retry if it fails
-            let value: Value = match serde_json::from_str(&llm_json_text) {
+            // Log the raw response for debugging (first 500 chars)
+            if attempt == 0 {
+                let mut preview: String = llm_json_text.chars().take(500).collect();
+                if preview.len() < llm_json_text.len() {
+                    preview.push_str("...");
+                }
+                eprintln!("[DEMO] LLM raw response (first 500 chars):\n{}", preview);
+            }
+
+            // Try to parse JSON - retry if it fails. An empty response would
+            // only get serde's "EOF while parsing", so it gets its own error
+            let parsed = if llm_json_text.trim().is_empty() {
+                tracing::warn!(shape_id, attempt = attempt + 1, "model returned no content");
+                Err(NO_CONTENT_ERROR.to_string())
+            } else {
+                serde_json::from_str(&llm_json_text).map_err(|e| e.to_string())
+            };
+            let value: Value = match parsed {
                 Ok(v) => {
                     v
                 }
-                Err(e) => {
-                    let error_msg = format!("{}", e);
+                Err(error_msg) => {
                     eprintln!("[DEMO] JSON parse error: {}", error_msg);
+                    eprintln!("[DEMO] Response length: {}, First 200 chars: {}", 
+                        llm_json_text.len(),
+                        llm_json_text.chars().take(200).collect::<String>()
+                    );
                     
                     // If this is the last attempt, return error
                     if attempt == max_retries - 1 {
@@ -171,6 +1423,10 @@ impl LlmClient {
                     }
                     
                     // Otherwise, save error and retry
+                    self.remember(&mut history, PastAttempt {
+                        attempt: attempt + 1,
+                        feedback: PastFeedback::Json(error_msg.clone()),
+                    });
                     last_json_error = Some(error_msg);
                     last_errors = None; // Clear validation errors since we didn't get that far
                     if attempt < max_retries - 1 {
@@ -180,35 +1436,69 @@ impl LlmClient {
                 }
             };
 
-            match validate(output_schema, &value) {
-                Ok(()) => {
-                    eprintln!("[DEMO] ✓ Schema validation passed!");
-                    let typed: FormationOutput = serde_json::from_value(value)?;
-                    
-                    // Validate that we have the correct number of coordinates
-                    if typed.coordinates.len() != input.unit_count as usize {
-                        eprintln!("[DEMO] ✗ Coordinate count mismatch: expected {}, got {}", 
-                            input.unit_count, typed.coordinates.len());
-                        // Create a validation-like error to trigger retry
-                        let mut errors = Vec::new();
-                        errors.push(ValidationError::TypeMismatch {
-                            path: "$.coordinates".to_string(),
-                            expected: format!("array with exactly {} items", input.unit_count),
-                            found: format!("array with {} items", typed.coordinates.len()),
-                        });
-                        last_errors = Some(errors);
-                        last_json_error = None;
-                        if attempt < max_retries - 1 {
-                            eprintln!("[DEMO] Retrying with coordinate count feedback...\n");
+            let validation_started = Instant::now();
+            let value = if self.coerce_bools {
+                coerce_bools_logged(shape_id, output_schema, value)
+            } else {
+                value
+            };
+            let value = if self.local_repair {
+                repair_locally(shape_id, output_schema, value)
+            } else {
+                value
+            };
+            let outcome = match validate(output_schema, &value) {
+                // Schema-valid output can still fail to deserialize, e.g. when
+                // a number overflows its field; retry it like any other error
+                Ok(()) => match O::deserialize(&value) {
+                    Ok(typed) => {
+                        let errors = check(&typed);
+                        if errors.is_empty() {
+                            Ok(typed)
+                        } else {
+                            Err(errors)
                         }
-                        continue;
                     }
-                    
-                    eprintln!("[DEMO] ✓ All validation passed! Returning result.");
+                    Err(e) => Err(vec![ValidationError::Deserialize { message: e.to_string() }]),
+                },
+                Err(errors) => Err(errors),
+            };
+            let validation_elapsed = validation_started.elapsed();
+            tracing::info!(shape_id, attempt = attempt + 1, elapsed = ?validation_elapsed, "validation finished");
+            if let Some(metrics) = &self.metrics {
+                metrics.validation_seconds.observe(validation_elapsed);
+            }
+
+            match outcome {
+                Ok(typed) => {
+                    eprintln!("[DEMO] ✓ Validation passed! Returning result.");
+                    // A hit is already cached: writing it again would restart
+                    // its TTL and rewrite its file on every use
+                    if let (Some(cache), Some(first_prompt)) = (&self.prompt_cache, &first_prompt) {
+                        if !cache_hit {
+                            cache.put(&self.cache_scope(), first_prompt, &llm_json_text).await;
+                        }
+                    }
+                    if let Some(slot) = &self.raw_output {
+                        slot.set(&llm_json_text);
+                    }
                     return Ok(typed);
                 }
                 Err(errors) => {
                     eprintln!("[DEMO] ✗ Validation failed with {} error(s)", errors.len());
+                    if attempt == max_retries - 1 {
+                        return Err(ValidationFailed {
+                            attempts: max_retries,
+                            errors,
+                            last_value: Some(value),
+                        }
+                        .into());
+                    }
+                    last_value = Some(value);
+                    self.remember(&mut history, PastAttempt {
+                        attempt: attempt + 1,
+                        feedback: PastFeedback::Validation(errors.clone()),
+                    });
                     last_errors = Some(errors);
                     last_json_error = None; // Clear JSON error since JSON was valid
                     if attempt < max_retries - 1 {
@@ -225,93 +1515,698 @@ impl LlmClient {
         ))
     }
 
-    async fn call_llm(&self, prompt: &str) -> Result<String> {
-        if self.is_ollama {
-            self.call_ollama(prompt).await
+    /// Add a failed attempt to `history`, dropping the oldest beyond
+    /// `feedback_history`.
+    fn remember(&self, history: &mut VecDeque<PastAttempt>, past: PastAttempt) {
+        history.push_back(past);
+        while history.len() > self.feedback_history {
+            history.pop_front();
+        }
+    }
+
+    /// Run `fut` unless the cancellation token fires first.
+    async fn cancellable<F: std::future::Future>(&self, shape_id: &str, fut: F) -> Result<F::Output> {
+        let Some(cancel) = &self.cancel else {
+            return Ok(fut.await);
+        };
+        tokio::select! {
+            output = fut => Ok(output),
+            _ = cancel.cancelled() => {
+                eprintln!("[DEMO] {} cancelled", shape_id);
+                Err(anyhow!("{} cancelled", shape_id))
+            }
+        }
+    }
+
+    /// Call the LLM through the circuit breaker, when there is one.
+    async fn call_llm(&self, prompt: &str, on_text: &(dyn Fn(&str) + Sync)) -> Result<String> {
+        let Some(breaker) = &self.breaker else {
+            return self.call_endpoints(prompt, on_text).await;
+        };
+        if let Err(open) = breaker.check() {
+            eprintln!("[DEMO] {}", open);
+            return Err(open.into());
+        }
+        let result = self.call_endpoints(prompt, on_text).await;
+        breaker.record(result.as_ref().is_err_and(is_transport_error));
+        result
+    }
+
+    /// Call the next endpoint, moving on to the others if it can't be reached.
+    /// `on_text` gets the model output as it arrives (see `TokenSink`).
+    async fn call_endpoints(&self, prompt: &str, on_text: &(dyn Fn(&str) + Sync)) -> Result<String> {
+        if let Some(backend) = &self.llm_backend {
+            return backend.complete(prompt).await.inspect(|text| on_text(text));
+        }
+
+        let mut last_err = None;
+        for idx in self.endpoints.order() {
+            let base_url = &self.endpoints.urls[idx];
+            let result = match self.backend {
+                BackendKind::Ollama => self.call_ollama(base_url, prompt, on_text).await,
+                BackendKind::Mock => self.call_mock_server(base_url, prompt).await.inspect(|text| on_text(text)),
+                BackendKind::OpenAi => self.call_openai(base_url, prompt).await.inspect(|text| on_text(text)),
+            };
+            match result {
+                Err(e) if is_transport_error(&e) => {
+                    tracing::warn!(endpoint = %base_url, error = %e, "LLM endpoint unreachable");
+                    self.endpoints.set_down(idx, true);
+                    last_err = Some(e);
+                }
+                result => {
+                    self.endpoints.set_down(idx, false);
+                    return result;
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("no LLM endpoints configured")))
+    }
+
+    async fn call_ollama(&self, base_url: &str, prompt: &str, on_text: &(dyn Fn(&str) + Sync)) -> Result<String> {
+        #[derive(Serialize)]
+        struct OllamaRequest<'a> {
+            model: &'a str,
+            prompt: &'a str,
+            stream: bool,
+            #[serde(skip_serializing_if = "OllamaOptions::is_empty")]
+            options: OllamaOptions<'a>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            keep_alive: Option<&'a Value>,
+        }
+
+        #[derive(Serialize)]
+        struct OllamaOptions<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            seed: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            temperature: Option<f32>,
+            #[serde(skip_serializing_if = "<[String]>::is_empty")]
+            stop: &'a [String],
+        }
+
+        impl OllamaOptions<'_> {
+            fn is_empty(&self) -> bool {
+                self.seed.is_none() && self.temperature.is_none() && self.stop.is_empty()
+            }
+        }
+
+        // Use Ollama's /api/generate endpoint
+        let url = if self.backend_explicit || base_url.ends_with("/api/generate") {
+            base_url.to_string()
+        } else {
+            format!("{}/api/generate", base_url.trim_end_matches('/'))
+        };
+
+        let resp = self
+            .request(&url)
+            .json(&OllamaRequest {
+                model: &self.model,
+                prompt,
+                stream: self.stream,
+                options: OllamaOptions {
+                    seed: self.sampling_seed(),
+                    temperature: self.temperature,
+                    stop: &self.stop,
+                },
+                keep_alive: self.ollama_keep_alive.as_ref(),
+            })
+            .send()
+            .await
+            .map_err(|e| transport_error("Ollama", &url, e))?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let error_text = resp.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(anyhow!("Ollama HTTP error {}: {}", status, error_text));
+        }
+
+        let response = if self.stream {
+            read_ollama_stream(resp, on_text).await?
+        } else {
+            let body: OllamaResponse = resp.json().await?;
+            on_text(&body.response);
+            body.response
+        };
+        // Clean the response - remove markdown code fences if present
+        let cleaned = clean_json_response(&response);
+        Ok(cleaned)
+    }
+
+    async fn call_openai(&self, base_url: &str, prompt: &str) -> Result<String> {
+        #[derive(Serialize)]
+        struct ChatMessage<'a> {
+            role: &'a str,
+            content: &'a str,
+        }
+
+        #[derive(Serialize)]
+        struct ChatRequest<'a> {
+            model: &'a str,
+            messages: [ChatMessage<'a>; 1],
+            #[serde(skip_serializing_if = "Option::is_none")]
+            seed: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            temperature: Option<f32>,
+            #[serde(skip_serializing_if = "<[String]>::is_empty")]
+            stop: &'a [String],
+        }
+
+        #[derive(Deserialize)]
+        struct ChatResponse {
+            choices: Vec<ChatChoice>,
+        }
+
+        #[derive(Deserialize)]
+        struct ChatChoice {
+            message: ChatResponseMessage,
+        }
+
+        #[derive(Deserialize)]
+        struct ChatResponseMessage {
+            content: String,
+        }
+
+        let url = if self.backend_explicit || base_url.contains("/chat/completions") {
+            base_url.to_string()
+        } else {
+            format!("{}/v1/chat/completions", base_url.trim_end_matches('/'))
+        };
+
+        let resp = self
+            .request(&url)
+            .json(&ChatRequest {
+                model: &self.model,
+                messages: [ChatMessage {
+                    role: "user",
+                    content: prompt,
+                }],
+                seed: self.sampling_seed(),
+                temperature: self.temperature,
+                stop: &self.stop,
+            })
+            .send()
+            .await
+            .map_err(|e| transport_error("OpenAI", &url, e))?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let error_text = resp.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(anyhow!("OpenAI HTTP error {}: {}", status, error_text));
+        }
+
+        let body: ChatResponse = resp.json().await?;
+        let content = body
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .ok_or_else(|| anyhow!("OpenAI response had no choices"))?;
+        Ok(clean_json_response(&content))
+    }
+
+    /// POST builder with the headers every backend request carries.
+    fn request(&self, url: &str) -> reqwest::RequestBuilder {
+        let mut req = self.http.post(url);
+        if self.connection_close {
+            req = req.header("Connection", "close");
+        }
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        if !self.headers.is_empty() {
+            req = req.headers(self.headers.clone());
+        }
+        req
+    }
+
+    async fn call_mock_server(&self, base_url: &str, prompt: &str) -> Result<String> {
+        #[derive(Serialize)]
+        struct LlmRequest<'a> {
+            prompt: &'a str,
+        }
+
+        #[derive(Deserialize)]
+        struct LlmResponse {
+            output: String,
+        }
+
+        // Make request with reqwest (configured for HTTP/1.1 only)
+        let resp = self
+            .request(base_url)
+            .json(&LlmRequest { prompt })
+            .send()
+            .await
+            .map_err(|e| transport_error("LLM", base_url, e))?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let error_text = resp.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(anyhow!("LLM HTTP error {}: {}", status, error_text));
+        }
+
+        let body: LlmResponse = resp.json().await?;
+        Ok(body.output)
+    }
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    response: String,
+    done: bool,
+}
+
+// Streamed output with this many characters and no opening brace is treated
+// as prose and cut off early.
+const STREAM_PROSE_LIMIT: usize = 200;
+
+/// Read a streamed (`stream: true`) Ollama response: newline-delimited JSON
+/// chunks, each carrying a piece of `response`.
+///
+/// Stops reading, which drops the connection and aborts the generation, as
+/// soon as a balanced top-level JSON object has been received, or when the
+/// model has produced `STREAM_PROSE_LIMIT` characters without starting one.
+/// Whatever was accumulated is returned for the normal parse/validate step.
+/// Each piece is passed to `on_text` as it arrives.
+async fn read_ollama_stream(mut resp: reqwest::Response, on_text: &(dyn Fn(&str) + Sync)) -> Result<String> {
+    let mut pending = Vec::new();
+    let mut text = String::new();
+    // Characters in `text`, which STREAM_PROSE_LIMIT is counted in
+    let mut chars = 0;
+    let mut scanner = JsonScanner::default();
+
+    while let Some(chunk) = resp.chunk().await? {
+        pending.extend_from_slice(&chunk);
+
+        while let Some(newline) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=newline).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let part: OllamaResponse = serde_json::from_slice(&line)
+                .map_err(|e| anyhow!("invalid Ollama stream chunk: {e}"))?;
+            scanner.feed(&part.response);
+            text.push_str(&part.response);
+            chars += part.response.chars().count();
+            if !part.response.is_empty() {
+                on_text(&part.response);
+            }
+
+            if part.done {
+                return Ok(text);
+            }
+            if scanner.end.is_some() {
+                tracing::debug!(chars, "streamed JSON object complete, stopping early");
+                return Ok(text);
+            }
+            if !scanner.started && chars >= STREAM_PROSE_LIMIT {
+                tracing::warn!(chars, "model is emitting prose instead of JSON, aborting stream");
+                return Ok(text);
+            }
+        }
+    }
+
+    Ok(text)
+}
+
+/// Incremental brace matcher that ignores braces inside JSON strings, so a
+/// `{` in a value such as `"/items/{id"` doesn't throw off the match.
+#[derive(Default)]
+struct JsonScanner {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Bytes fed so far
+    offset: usize,
+    /// Saw the first `{`
+    started: bool,
+    /// Byte offset, within everything fed, of the `}` closing the first
+    /// top-level object once it has closed
+    end: Option<usize>,
+}
+
+impl JsonScanner {
+    fn feed(&mut self, text: &str) {
+        let base = self.offset;
+        self.offset += text.len();
+        for (i, c) in text.char_indices() {
+            if self.end.is_some() {
+                return;
+            }
+            if self.in_string {
+                match c {
+                    _ if self.escaped => self.escaped = false,
+                    '\\' => self.escaped = true,
+                    '"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' if self.started => self.in_string = true,
+                '{' => {
+                    self.started = true;
+                    self.depth += 1;
+                }
+                '}' if self.started => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        self.end = Some(base + i);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Whether `found` units is a count in `accepted`.
+fn count_accepted(accepted: &RangeInclusive<u32>, found: usize) -> bool {
+    u32::try_from(found).is_ok_and(|found| accepted.contains(&found))
+}
+
+/// `accepted` as prompts and errors word it: `exactly 3` or `between 2 and 4`.
+fn describe_counts(accepted: &RangeInclusive<u32>) -> String {
+    if accepted.start() == accepted.end() {
+        format!("exactly {}", accepted.start())
+    } else {
+        format!("between {} and {}", accepted.start(), accepted.end())
+    }
+}
+
+/// Error for a formation whose number of units is outside `accepted`.
+fn check_unit_count(accepted: &RangeInclusive<u32>, found: usize) -> Option<ValidationError> {
+    if count_accepted(accepted, found) {
+        return None;
+    }
+    let expected = describe_counts(accepted);
+    eprintln!("[DEMO] ✗ Coordinate count mismatch: expected {}, got {}", expected, found);
+    Some(ValidationError::Constraint {
+        path: "$.coordinates".to_string(),
+        message: format!("expected array with {expected} items, found array with {found} items"),
+    })
+}
+
+/// The built-in Formation hook: the number of coordinates must be one
+/// `input.accepted_counts()` allows. `generate_formation` also counts these
+/// mismatches to escalate the prompt.
+fn check_formation_unit_count(output: &FormationOutput, input: &FormationInput) -> Vec<ValidationError> {
+    input
+        .accepted_counts()
+        .and_then(|accepted| check_unit_count(&accepted, output.coordinates.len()))
+        .into_iter()
+        .collect()
+}
+
+/// A component count outside the input's `min_components`/`max_components`.
+fn check_component_count(input: &FeatureDesignInput, output: &FeatureDesignOutput) -> Option<ValidationError> {
+    let found = output.components.len();
+    let too_few = input.min_components.is_some_and(|min| found < min as usize);
+    let too_many = input.max_components.is_some_and(|max| found > max as usize);
+    if !too_few && !too_many {
+        return None;
+    }
+    let range = input.component_range()?;
+    eprintln!("[DEMO] ✗ Component count {} is not {}", found, range);
+    Some(ValidationError::Constraint {
+        path: "$.components".to_string(),
+        message: format!("expected array with {range} items, found array with {found} items"),
+    })
+}
+
+/// Fewer risks than `min`. A design with no risks listed is usually a
+/// low-effort generation.
+fn check_risk_count(min: u32, output: &FeatureDesignOutput) -> Option<ValidationError> {
+    let found = output.risks.len();
+    if found >= min as usize {
+        return None;
+    }
+    eprintln!("[DEMO] ✗ {} risks listed, need at least {}", found, min);
+    Some(ValidationError::Constraint {
+        path: "$.risks".to_string(),
+        message: format!("expected at least {min} concrete risks, found {found} risks"),
+    })
+}
+
+/// `depends_on` entries that don't name a component in the design.
+fn check_dependencies(output: &FeatureDesignOutput) -> Vec<ValidationError> {
+    let ids: HashSet<&str> = output.components.iter().map(|c| c.id.as_str()).collect();
+    let mut errors = Vec::new();
+
+    for (i, component) in output.components.iter().enumerate() {
+        for (j, dependency) in component.depends_on.iter().enumerate() {
+            if !ids.contains(dependency.as_str()) {
+                eprintln!("[DEMO] ✗ {} depends on unknown component {}", component.id, dependency);
+                errors.push(ValidationError::Constraint {
+                    path: format!("$.components[{i}].depends_on[{j}]"),
+                    message: format!("expected id of a component in components, found {dependency:?}"),
+                });
+            }
+        }
+    }
+
+    errors
+}
+
+/// Requirement ids already used by an earlier requirement.
+fn check_unique_requirement_ids(output: &RequirementsOutput) -> Vec<ValidationError> {
+    let mut first_use: HashMap<&str, usize> = HashMap::new();
+    let mut errors = Vec::new();
+
+    for (i, requirement) in output.requirements.iter().enumerate() {
+        match first_use.get(requirement.id.as_str()) {
+            Some(first) => {
+                eprintln!("[DEMO] ✗ duplicate requirement id {}", requirement.id);
+                errors.push(ValidationError::Constraint {
+                    path: format!("$.requirements[{i}].id"),
+                    message: format!(
+                        "expected an id not already used by requirements[{first}], found {:?}",
+                        requirement.id
+                    ),
+                });
+            }
+            None => {
+                first_use.insert(&requirement.id, i);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Paths whose `(path, method)` pair an earlier path already uses.
+fn check_unique_operations(output: &ApiDesignOutput) -> Vec<ValidationError> {
+    let mut first_use: HashMap<(&str, HttpMethod), usize> = HashMap::new();
+    let mut errors = Vec::new();
+
+    for (i, spec) in output.paths.iter().enumerate() {
+        match first_use.get(&(spec.path.as_str(), spec.method)) {
+            Some(first) => {
+                eprintln!("[DEMO] ✗ duplicate operation {} {}", spec.method.as_str(), spec.path);
+                errors.push(ValidationError::Constraint {
+                    path: format!("$.paths[{i}]"),
+                    message: format!(
+                        "expected a (path, method) pair not already used by paths[{first}], found {} {}",
+                        spec.method.as_str(),
+                        spec.path
+                    ),
+                });
+            }
+            None => {
+                first_use.insert((&spec.path, spec.method), i);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Scores that aren't `likelihood * impact`, entries for risks the design
+/// doesn't list or already scored, and risks of the design with no score.
+/// Risks are matched ignoring case and surrounding whitespace. The rating
+/// range is part of the typedef.
+fn check_risk_scores(input: &RiskAssessmentInput, output: &RiskAssessmentOutput) -> Vec<ValidationError> {
+    let key = |risk: &str| risk.trim().to_lowercase();
+    // each risk once, in the design's order, so a repeated listing is reported once
+    let mut design: HashSet<String> = HashSet::new();
+    let design_risks: Vec<&String> = input.design.risks.iter().filter(|risk| design.insert(key(risk))).collect();
+    let mut first_use: HashMap<String, usize> = HashMap::new();
+    let mut errors = Vec::new();
+
+    for (i, scored) in output.scored_risks.iter().enumerate() {
+        let risk = key(&scored.risk);
+        if !design.contains(&risk) {
+            eprintln!("[DEMO] ✗ scored risk {:?} is not in the design", scored.risk);
+            errors.push(ValidationError::Constraint {
+                path: format!("$.scored_risks[{i}].risk"),
+                message: format!("expected one of the design's risks, found {:?}", scored.risk),
+            });
+        } else if let Some(first) = first_use.get(&risk) {
+            eprintln!("[DEMO] ✗ risk {:?} scored twice", scored.risk);
+            errors.push(ValidationError::Constraint {
+                path: format!("$.scored_risks[{i}].risk"),
+                message: format!("expected a risk not already scored by scored_risks[{first}], found {:?}", scored.risk),
+            });
+        } else {
+            first_use.insert(risk, i);
+        }
+
+        let product = scored.likelihood.saturating_mul(scored.impact);
+        if scored.score != product {
+            eprintln!("[DEMO] ✗ risk score {} is not {} * {}", scored.score, scored.likelihood, scored.impact);
+            errors.push(ValidationError::Constraint {
+                path: format!("$.scored_risks[{i}].score"),
+                message: format!(
+                    "expected likelihood * impact = {} * {} = {product}, found {}",
+                    scored.likelihood, scored.impact, scored.score
+                ),
+            });
+        }
+    }
+
+    for risk in design_risks {
+        if !first_use.contains_key(&key(risk)) {
+            errors.push(ValidationError::Constraint {
+                path: "$.scored_risks".to_string(),
+                message: format!("expected an entry for the risk {:?}, found none", risk.trim()),
+            });
+        }
+    }
+
+    errors
+}
+
+/// Cells outside the grid, and cells already taken by an earlier unit.
+fn check_grid_cells(input: &FormationGridInput, output: &FormationGridOutput) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let mut taken = HashMap::new();
+    // A grid of size 0 on an axis has no valid index there at all
+    let expected = |size: u32, axis: &str| match size {
+        0 => format!("no {axis}, as the grid has 0 {axis}s"),
+        size => format!("integer from 0 to {}", size - 1),
+    };
+
+    for (i, cell) in output.coordinates.iter().enumerate() {
+        if cell.col >= input.grid_width {
+            errors.push(ValidationError::Constraint {
+                path: format!("$.coordinates[{i}].col"),
+                message: format!("expected {}, found {}", expected(input.grid_width, "column"), cell.col),
+            });
+        }
+        if cell.row >= input.grid_height {
+            errors.push(ValidationError::Constraint {
+                path: format!("$.coordinates[{i}].row"),
+                message: format!("expected {}, found {}", expected(input.grid_height, "row"), cell.row),
+            });
+        }
+        if let Some(first) = taken.get(cell) {
+            errors.push(ValidationError::Constraint {
+                path: format!("$.coordinates[{i}]"),
+                message: format!(
+                    "expected a cell not already used by coordinates[{first}], found col {}, row {}",
+                    cell.col, cell.row
+                ),
+            });
         } else {
-            self.call_mock_server(prompt).await
+            taken.insert(*cell, i);
         }
     }
 
-    async fn call_ollama(&self, prompt: &str) -> Result<String> {
-        #[derive(Serialize)]
-        struct OllamaRequest<'a> {
-            model: &'a str,
-            prompt: &'a str,
-            stream: bool,
-        }
-
-        #[derive(Deserialize)]
-        struct OllamaResponse {
-            response: String,
-            #[allow(dead_code)]
-            done: bool,
-        }
+    errors
+}
 
-        // Use Ollama's /api/generate endpoint
-        let url = if self.base_url.ends_with("/api/generate") {
-            self.base_url.clone()
-        } else if self.base_url.contains("11434") {
-            format!("http://localhost:11434/api/generate")
-        } else {
-            format!("{}/api/generate", self.base_url.trim_end_matches('/'))
-        };
+/// Error for a request that failed to send, keeping the `reqwest::Error`
+/// reachable for `is_transport_error`.
+fn transport_error(backend: &str, url: &str, e: reqwest::Error) -> anyhow::Error {
+    let message = format!("{backend} HTTP error: {e}. URL: {url}");
+    anyhow::Error::new(e).context(message)
+}
 
-        let resp = self
-            .http
-            .post(&url)
-            .header("Connection", "close")
-            .json(&OllamaRequest {
-                model: &self.model,
-                prompt,
-                stream: false,
-            })
-            .send()
-            .await
-            .map_err(|e| anyhow!("Ollama HTTP error: {}. URL: {}", e, url))?;
+/// The endpoint could not be reached (connect, timeout or send failure), as
+/// opposed to answering with an error or an unusable body.
+fn is_transport_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect() || e.is_timeout() || e.is_request())
+}
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let error_text = resp.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(anyhow!("Ollama HTTP error {}: {}", status, error_text));
-        }
+/// Run every hook on `output`, collecting all of their errors.
+fn run_hooks<O, I>(hooks: &[ValidationHook<O, I>], output: &O, input: &I) -> Vec<ValidationError> {
+    hooks.iter().flat_map(|hook| hook(output, input)).collect()
+}
 
-            let body: OllamaResponse = resp.json().await?;
-        // Clean the response - remove markdown code fences if present
-        let cleaned = clean_json_response(&body.response);
-        Ok(cleaned)
+/// Run `generate` `k` times concurrently and return the valid output `score`
+/// ranks highest, with ties going to the earliest candidate. Candidates that
+/// fail are skipped, so any valid one is returned over an error; the first
+/// candidate's error is returned only when all of them fail. A `k` of 0 or 1
+/// runs a single generation.
+///
+/// `generate` gets the client to generate with: `llm` itself, or for several
+/// candidates a clone each, so that when `llm` has a raw output slot (see
+/// `LlmClient::with_raw_output`) it ends up with the winner's text. So that
+/// candidates can differ, each clone skips the prompt cache and candidate
+/// `i` samples with the configured seed plus `i`, including a seed set later
+/// from the input such as `FormationInput::seed`.
+pub async fn best_of<O, F, Fut>(llm: &LlmClient, k: u32, generate: F, score: impl Fn(&O) -> f64) -> Result<O>
+where
+    F: Fn(LlmClient) -> Fut,
+    Fut: std::future::Future<Output = Result<O>>,
+{
+    if k <= 1 {
+        return generate(llm.clone()).await;
     }
 
-    async fn call_mock_server(&self, prompt: &str) -> Result<String> {
-        #[derive(Serialize)]
-        struct LlmRequest<'a> {
-            prompt: &'a str,
+    let slots: Vec<Option<Arc<RawOutput>>> = (0..k)
+        .map(|_| llm.raw_output.as_ref().map(|_| Arc::new(RawOutput::default())))
+        .collect();
+    let candidates = futures_util::future::join_all(slots.iter().enumerate().map(|(index, slot)| {
+        let mut llm = llm.clone();
+        llm.raw_output = slot.clone();
+        llm.seed_offset = index as u64;
+        llm.prompt_cache = None;
+        generate(llm)
+    }))
+    .await;
+    let mut best: Option<(f64, usize, O)> = None;
+    let mut first_error = None;
+    for (index, candidate) in candidates.into_iter().enumerate() {
+        match candidate {
+            Ok(output) => {
+                let candidate_score = score(&output);
+                eprintln!("[DEMO] Candidate {}/{} is valid, score {}", index + 1, k, candidate_score);
+                if best.as_ref().is_none_or(|(best_score, _, _)| candidate_score > *best_score) {
+                    best = Some((candidate_score, index, output));
+                }
+            }
+            Err(e) => {
+                eprintln!("[DEMO] Candidate {}/{} failed: {}", index + 1, k, e);
+                first_error.get_or_insert(e);
+            }
         }
-
-        #[derive(Deserialize)]
-        struct LlmResponse {
-            output: String,
+    }
+    match best {
+        Some((best_score, index, output)) => {
+            tracing::info!(candidates = k, best_score, "picked best candidate");
+            if let (Some(slot), Some(text)) = (&llm.raw_output, slots[index].as_ref().and_then(|s| s.take())) {
+                slot.set(&text);
+            }
+            Ok(output)
         }
+        None => Err(first_error.unwrap_or_else(|| anyhow!("no candidates ran"))),
+    }
+}
 
-        // Make request with reqwest (configured for HTTP/1.1 only)
-        let resp = self
-            .http
-            .post(&self.base_url)
-            .header("Connection", "close")
-            .json(&LlmRequest { prompt })
-            .send()
-            .await
-            .map_err(|e| anyhow!("LLM HTTP error: {}. URL: {}", e, self.base_url))?;
-
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let error_text = resp.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(anyhow!("LLM HTTP error {}: {}", status, error_text));
-        }
+/// Uniform random number in `[0, 1)`, seeded from the std hasher's random keys.
+fn random_unit() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
 
-        let body: LlmResponse = resp.json().await?;
-        Ok(body.output)
-    }
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
 }
 
 /// Clean JSON response from Ollama - removes markdown code fences and extracts JSON
@@ -336,30 +2231,21 @@ fn clean_json_response(response: &str) -> String {
     
     cleaned = cleaned.trim();
     
-    // Try to find JSON object boundaries if there's extra text
-    // Look for the first { and last } - be more aggressive about finding complete JSON
+    // Keep the first complete top-level object and drop any text around it,
+    // such as a sign-off after the JSON
     if let Some(first_brace) = cleaned.find('{') {
-        // Find the matching closing brace by counting braces
-        let mut brace_count = 0;
-        let mut last_brace = None;
-        for (i, c) in cleaned[first_brace..].char_indices() {
-            match c {
-                '{' => brace_count += 1,
-                '}' => {
-                    brace_count -= 1;
-                    if brace_count == 0 {
-                        last_brace = Some(first_brace + i);
-                        break;
-                    }
-                }
-                _ => {}
+        let mut scanner = JsonScanner::default();
+        scanner.feed(&cleaned[first_brace..]);
+        if let Some(end_pos) = scanner.end {
+            let tail = cleaned[first_brace + end_pos + 1..].trim();
+            if !tail.is_empty() {
+                let preview: String = tail.chars().take(200).collect();
+                tracing::debug!(discarded_chars = tail.chars().count(), tail = %preview, "discarded text after the JSON object");
             }
-        }
-        
-        if let Some(end_pos) = last_brace {
-            cleaned = &cleaned[first_brace..=end_pos];
+            cleaned = &cleaned[first_brace..=first_brace + end_pos];
         } else if let Some(fallback_brace) = cleaned.rfind('}') {
-            // Fallback to simple rfind if brace counting fails
+            // Unbalanced, e.g. truncated output: take everything up to the
+            // last brace and let the parser report what is wrong
             if fallback_brace > first_brace {
                 cleaned = &cleaned[first_brace..=fallback_brace];
             }
@@ -399,21 +2285,50 @@ fn clean_json_response(response: &str) -> String {
     result.trim().to_string()
 }
 
+/// The opening every built-in prompt shares: the output schema in `format`
+/// and the rules for writing bare JSON.
+fn push_preamble(s: &mut String, output_schema: &TypeDef, format: SchemaFormat) {
+    s.push_str("You are a system that strictly outputs JSON.\n");
+    s.push_str("You must produce a JSON object that matches this schema:\n\n");
+    s.push_str(&render_schema(output_schema, format));
+    s.push_str("\n\nThe JSON must be parseable and not contain comments or explanations.\n");
+    s.push_str("Do not wrap it in markdown code fences.\n");
+    s.push_str("Do not include control characters (null bytes, etc.) in your output.\n");
+    s.push_str("Escape special characters properly in JSON strings (use \\n for newlines, etc.).\n");
+}
+
+/// Append what was wrong with the previous attempt: its JSON parse error,
+/// then its validation errors, whichever it had.
+fn push_retry_feedback(
+    s: &mut String,
+    last_json_error: Option<&str>,
+    last_errors: Option<&Vec<ValidationError>>,
+    feedback: FeedbackVerbosity,
+) {
+    if let Some(json_err) = last_json_error {
+        s.push_str("\nYour previous response was not valid JSON. The error was:\n");
+        s.push_str(json_err);
+        s.push_str("\n\nPlease output ONLY valid, parseable JSON without any control characters or formatting issues.\n");
+    }
+
+    if let Some(errors) = last_errors {
+        push_validation_feedback(s, errors, feedback);
+    }
+}
+
 fn build_prompt(
     input: &FeatureDesignInput,
     output_schema: &TypeDef,
     last_errors: Option<&Vec<ValidationError>>,
     last_json_error: Option<&str>,
+    min_risks: u32,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
 ) -> String {
     let mut s = String::new();
 
-    s.push_str("You are a system that strictly outputs JSON.\n");
-    s.push_str("You must produce a JSON object that matches this schema:\n\n");
-    s.push_str(&describe_schema(output_schema, 0));
-    s.push_str("\n\nThe JSON must be parseable and not contain comments or explanations.\n");
-    s.push_str("Do not wrap it in markdown code fences.\n");
-    s.push_str("Do not include control characters (null bytes, etc.) in your output.\n");
-    s.push_str("Escape special characters properly in JSON strings (use \\n for newlines, etc.).\n\n");
+    push_preamble(&mut s, output_schema, schema_format);
+    s.push_str("Each component's depends_on lists the ids of other components in this design that it needs.\n\n");
 
     s.push_str("Context:\n");
     s.push_str("- Repo summary: ");
@@ -424,22 +2339,76 @@ fn build_prompt(
         s.push_str(c);
         s.push('\n');
     }
-
-    if let Some(json_err) = last_json_error {
-        s.push_str("\nYour previous response was not valid JSON. The error was:\n");
-        s.push_str(json_err);
-        s.push_str("\n\nPlease output ONLY valid, parseable JSON without any control characters or formatting issues.\n");
+    if let Some(range) = input.component_range() {
+        s.push_str(&format!("- The design must have {range} components.\n"));
+    }
+    if min_risks > 0 {
+        s.push_str(&format!("- List at least {min_risks} concrete risks.\n"));
     }
 
-    if let Some(errors) = last_errors {
-        s.push_str("\nYour previous JSON had these validation problems:\n");
-        for e in errors {
-            s.push_str("- ");
-            s.push_str(&e.to_string());
-            s.push('\n');
+    push_retry_feedback(&mut s, last_json_error, last_errors, feedback);
+
+    s
+}
+
+/// Rough token count of `text` for budgeting: one token per four
+/// characters, rounded up. Real tokenizers differ by model, typically within
+/// about 25% for English prose and JSON; code and non-Latin scripts use more
+/// tokens per character, so leave headroom.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Substitute `{{field}}` placeholders with top-level fields of `input`.
+///
+/// String fields are inserted verbatim; other values as compact JSON.
+/// Placeholders that don't match an input field are left untouched. The
+/// template is scanned once, so placeholders inside inserted values are not
+/// expanded.
+pub fn render_template(template: &str, input: &Value) -> String {
+    let fields = input.as_object();
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        if name.contains("{{") {
+            // `{{` with no closing braces before the next `{{`: keep it as text
+            out.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
         }
-        s.push_str("\nFix these issues and output ONLY corrected JSON.\n");
+        out.push_str(&rest[..start]);
+        match fields.and_then(|fields| fields.get(name)) {
+            Some(Value::String(s)) => out.push_str(s),
+            Some(other) => out.push_str(&other.to_string()),
+            None => out.push_str(&rest[start..start + len + 4]),
+        }
+        rest = &rest[start + len + 4..];
     }
+    out.push_str(rest);
+    out
+}
+
+fn build_template_prompt(
+    task: &str,
+    output_schema: &TypeDef,
+    last_errors: Option<&Vec<ValidationError>>,
+    last_json_error: Option<&str>,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
+) -> String {
+    let mut s = String::new();
+
+    push_preamble(&mut s, output_schema, schema_format);
+    s.push('\n');
+
+    s.push_str(task.trim_end());
+    s.push('\n');
+
+    push_retry_feedback(&mut s, last_json_error, last_errors, feedback);
 
     s
 }
@@ -449,33 +2418,50 @@ fn build_formation_prompt(
     output_schema: &TypeDef,
     last_errors: Option<&Vec<ValidationError>>,
     last_json_error: Option<&str>,
+    enumerate_positions: bool,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
 ) -> String {
     let mut s = String::new();
 
-    s.push_str("You are a system that strictly outputs JSON.\n");
-    s.push_str("You must produce a JSON object that matches this schema:\n\n");
-    s.push_str(&describe_schema(output_schema, 0));
-    s.push_str("\n\nThe JSON must be parseable and not contain comments or explanations.\n");
-    s.push_str("Do not wrap it in markdown code fences.\n");
-    s.push_str("Do not include control characters (null bytes, etc.) in your output.\n");
-    s.push_str("Escape special characters properly in JSON strings (use \\n for newlines, etc.).\n\n");
+    push_preamble(&mut s, output_schema, schema_format);
+    s.push('\n');
 
     s.push_str("Task: Generate 2D coordinates for unit formation.\n");
     s.push_str(&format!("- Formation description: {}\n", input.formation_description));
     s.push_str(&format!("- Number of units: {}\n", input.unit_count));
-    s.push_str("\n");
-    s.push_str("CRITICAL: You MUST generate EXACTLY ");
-    s.push_str(&input.unit_count.to_string());
-    s.push_str(" coordinates (x, y pairs), no more, no less.\n");
-    s.push_str("The coordinates array must contain exactly ");
-    s.push_str(&input.unit_count.to_string());
-    s.push_str(" items.\n");
+    s.push('\n');
+    let accepted = input.accepted_counts();
+    match &accepted {
+        Some(accepted) if accepted.start() == accepted.end() => {
+            s.push_str("CRITICAL: You MUST generate EXACTLY ");
+            s.push_str(&input.unit_count.to_string());
+            s.push_str(" coordinates (x, y pairs), no more, no less.\n");
+            s.push_str("The coordinates array must contain exactly ");
+            s.push_str(&input.unit_count.to_string());
+            s.push_str(" items.\n");
+        }
+        Some(accepted) => {
+            s.push_str(&format!(
+                "CRITICAL: You MUST generate {} coordinates (x, y pairs), ideally {}.\n",
+                describe_counts(accepted),
+                input.unit_count
+            ));
+            s.push_str(&format!("The coordinates array must contain {} items.\n", describe_counts(accepted)));
+        }
+        None => {
+            s.push_str(&format!(
+                "Aim for about {} coordinates (x, y pairs); any number is accepted.\n",
+                input.unit_count
+            ));
+        }
+    }
     s.push_str("Coordinates should be reasonable 2D positions (typically between 0-100 for x and y).\n");
     s.push_str("The formation should be visually recognizable as the requested shape.\n");
-    s.push_str("\n");
+    s.push('\n');
     s.push_str("Example output format (for 3 units):\n");
     s.push_str("{\"coordinates\":[{\"x\":0.0,\"y\":0.0},{\"x\":10.0,\"y\":0.0},{\"x\":5.0,\"y\":10.0}]}\n");
-    s.push_str("\n");
+    s.push('\n');
     s.push_str("CRITICAL: Output ONLY the JSON object, nothing else. No text before or after. No markdown. No explanations.\n");
     s.push_str("The JSON must be valid and parseable. Do NOT include:\n");
     s.push_str("- Control characters (null bytes, etc.)\n");
@@ -483,25 +2469,364 @@ fn build_formation_prompt(
     s.push_str("- Any characters outside the JSON structure\n");
     s.push_str("- Trailing commas\n");
 
-    if let Some(json_err) = last_json_error {
-        s.push_str("\nYour previous response was not valid JSON. The error was:\n");
-        s.push_str(json_err);
-        s.push_str("\n\nPlease output ONLY valid, parseable JSON without any control characters or formatting issues.\n");
+    push_retry_feedback(&mut s, last_json_error, last_errors, feedback);
+
+    // The count was wrong more than once: plain feedback isn't working, so make
+    // the model enumerate every position explicitly.
+    if let (true, Some(accepted)) = (enumerate_positions, &accepted) {
+        s.push_str("\nYou have returned the wrong number of coordinates more than once.\n");
+        s.push_str("Fill in every one of these ");
+        s.push_str(&input.unit_count.to_string());
+        s.push_str(" positions, then output them in order as the coordinates array:\n");
+        for i in 1..=input.unit_count {
+            s.push_str(&format!("{i}. {{\"x\": ?, \"y\": ?}}\n"));
+        }
+        if accepted.start() != accepted.end() {
+            s.push_str(&format!(
+                "You may leave out or add positions, as long as the array has {} items.\n",
+                describe_counts(accepted)
+            ));
+        }
     }
 
-    if let Some(errors) = last_errors {
-        s.push_str("\nYour previous JSON had these validation problems:\n");
-        for e in errors {
-            s.push_str("- ");
-            s.push_str(&e.to_string());
-            s.push('\n');
+    s
+}
+
+fn build_formation_grid_prompt(
+    input: &FormationGridInput,
+    output_schema: &TypeDef,
+    last_errors: Option<&Vec<ValidationError>>,
+    last_json_error: Option<&str>,
+    enumerate_positions: bool,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
+) -> String {
+    let mut s = String::new();
+
+    push_preamble(&mut s, output_schema, schema_format);
+    s.push('\n');
+
+    s.push_str("Task: Place units for a formation on a tile grid.\n");
+    s.push_str(&format!("- Formation description: {}\n", input.formation_description));
+    s.push_str(&format!("- Number of units: {}\n", input.unit_count));
+    s.push_str(&format!(
+        "- Grid size: {} columns by {} rows\n",
+        input.grid_width, input.grid_height
+    ));
+    s.push('\n');
+    s.push_str(&format!(
+        "CRITICAL: You MUST output EXACTLY {} cells, no more, no less.\n",
+        input.unit_count
+    ));
+    s.push_str(&format!(
+        "Each cell is {{\"col\": integer, \"row\": integer}} with col from 0 to {} and row from 0 to {}.\n",
+        input.grid_width.saturating_sub(1),
+        input.grid_height.saturating_sub(1)
+    ));
+    s.push_str("Use whole numbers only. No two units may share a cell.\n");
+    s.push_str("The formation should be visually recognizable as the requested shape.\n");
+    s.push('\n');
+    s.push_str("Example output format (for 3 units):\n");
+    s.push_str("{\"coordinates\":[{\"col\":0,\"row\":0},{\"col\":2,\"row\":0},{\"col\":1,\"row\":1}]}\n");
+    s.push('\n');
+    s.push_str("CRITICAL: Output ONLY the JSON object, nothing else. No text before or after. No markdown. No explanations.\n");
+
+    push_retry_feedback(&mut s, last_json_error, last_errors, feedback);
+
+    if enumerate_positions {
+        s.push_str("\nYou have returned the wrong number of cells more than once.\n");
+        s.push_str(&format!(
+            "Fill in every one of these {} cells, then output them in order as the coordinates array:\n",
+            input.unit_count
+        ));
+        for i in 1..=input.unit_count {
+            s.push_str(&format!("{i}. {{\"col\": ?, \"row\": ?}}\n"));
+        }
+    }
+
+    s
+}
+
+fn build_requirements_prompt(
+    input: &RequirementsInput,
+    output_schema: &TypeDef,
+    last_errors: Option<&Vec<ValidationError>>,
+    last_json_error: Option<&str>,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
+) -> String {
+    let mut s = String::new();
+
+    push_preamble(&mut s, output_schema, schema_format);
+    s.push('\n');
+
+    s.push_str("Task: Extract the requirements stated in the document below.\n");
+    s.push_str("- One entry per distinct requirement, in the order they appear.\n");
+    s.push_str("- id: a short identifier unique within the output, e.g. REQ-1, REQ-2.\n");
+    s.push_str("- text: the requirement as one self-contained sentence.\n");
+    s.push_str("- priority: exactly one of \"high\", \"medium\" or \"low\" (lowercase). Use high for must-haves, ");
+    s.push_str("medium for should-haves and low for nice-to-haves; when the document doesn't say, use medium.\n");
+    s.push_str("Do not invent requirements the document doesn't state.\n\n");
+
+    s.push_str("Document:\n");
+    s.push_str(input.document.trim_end());
+    s.push_str("\n\n");
+    s.push_str("Example output format:\n");
+    s.push_str("{\"requirements\":[{\"id\":\"REQ-1\",\"text\":\"Users can reset their password by email.\",\"priority\":\"high\"}]}\n");
+    s.push('\n');
+    s.push_str("CRITICAL: Output ONLY the JSON object, nothing else. No text before or after. No markdown. No explanations.\n");
+
+    push_retry_feedback(&mut s, last_json_error, last_errors, feedback);
+
+    s
+}
+
+fn build_api_design_prompt(
+    input: &ApiDesignInput,
+    output_schema: &TypeDef,
+    last_errors: Option<&Vec<ValidationError>>,
+    last_json_error: Option<&str>,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
+) -> String {
+    let mut s = String::new();
+
+    push_preamble(&mut s, output_schema, schema_format);
+    s.push('\n');
+
+    s.push_str(&format!("Task: Design REST API paths for the resource \"{}\".\n", input.resource.trim()));
+    s.push_str("The API must support these operations:\n");
+    for operation in &input.operations {
+        s.push_str(&format!("- {}\n", operation.trim()));
+    }
+    s.push_str("\nRules:\n");
+    s.push_str("- path: an OpenAPI path template starting with /, with path parameters in braces, e.g. /invoices/{invoiceId}.\n");
+    s.push_str("- method: exactly one of \"GET\", \"POST\", \"PUT\", \"DELETE\" or \"PATCH\" (uppercase).\n");
+    s.push_str("- summary: one short sentence saying what the operation does.\n");
+    s.push_str("- Every combination of path and method must appear only once.\n");
+    s.push_str("- Cover every operation listed; do not add operations that weren't asked for.\n\n");
+
+    s.push_str("Example output format:\n");
+    s.push_str("{\"paths\":[{\"path\":\"/invoices\",\"method\":\"GET\",\"summary\":\"List invoices.\"},");
+    s.push_str("{\"path\":\"/invoices/{invoiceId}\",\"method\":\"GET\",\"summary\":\"Get one invoice.\"}]}\n");
+    s.push('\n');
+    s.push_str("CRITICAL: Output ONLY the JSON object, nothing else. No text before or after. No markdown. No explanations.\n");
+
+    push_retry_feedback(&mut s, last_json_error, last_errors, feedback);
+
+    s
+}
+
+fn build_risk_assessment_prompt(
+    input: &RiskAssessmentInput,
+    output_schema: &TypeDef,
+    last_errors: Option<&Vec<ValidationError>>,
+    last_json_error: Option<&str>,
+    feedback: FeedbackVerbosity,
+    schema_format: SchemaFormat,
+) -> String {
+    let design = &input.design;
+    let mut s = String::new();
+
+    push_preamble(&mut s, output_schema, schema_format);
+    s.push('\n');
+
+    s.push_str(&format!("Task: Score the risks of the feature design \"{}\".\n\n", design.name.trim()));
+    s.push_str("Rationale:\n");
+    s.push_str(design.rationale.trim());
+    s.push_str("\n\nComponents:\n");
+    for component in &design.components {
+        s.push_str(&format!("- {}: {}", component.id, component.responsibility.trim()));
+        if !component.depends_on.is_empty() {
+            s.push_str(&format!(" (depends on {})", component.depends_on.join(", ")));
         }
-        s.push_str("\nFix these issues and output ONLY corrected JSON.\n");
+        s.push('\n');
     }
+    s.push_str("\nRisks to score:\n");
+    for risk in &design.risks {
+        s.push_str(&format!("- {}\n", risk.trim()));
+    }
+    let (low, high) = (RISK_RATING_RANGE.start(), RISK_RATING_RANGE.end());
+    s.push_str("\nRules:\n");
+    s.push_str("- Add one entry to scored_risks for every risk above, in the same order.\n");
+    s.push_str("- risk: the risk's text exactly as written above.\n");
+    s.push_str(&format!(
+        "- likelihood: an integer from {low} (rare) to {high} (almost certain), given this design.\n"
+    ));
+    s.push_str(&format!("- impact: an integer from {low} (negligible) to {high} (severe), if the risk happens.\n"));
+    s.push_str("- score: likelihood multiplied by impact.\n\n");
+
+    s.push_str("Example output format:\n");
+    s.push_str("{\"scored_risks\":[{\"risk\":\"Database connection pool exhaustion under load\",");
+    s.push_str("\"likelihood\":3,\"impact\":4,\"score\":12}]}\n");
+    s.push('\n');
+    s.push_str("CRITICAL: Output ONLY the JSON object, nothing else. No text before or after. No markdown. No explanations.\n");
+
+    push_retry_feedback(&mut s, last_json_error, last_errors, feedback);
 
     s
 }
 
+/// `value` with `types::repair` applied if it fails the schema and the
+/// repaired value passes; otherwise `value` unchanged, so retry feedback
+/// describes what the model actually wrote.
+fn repair_locally(shape_id: &str, output_schema: &TypeDef, value: Value) -> Value {
+    if is_valid(output_schema, &value) {
+        return value;
+    }
+    match repair(output_schema, &value) {
+        Some((repaired, fixes)) if is_valid(output_schema, &repaired) => {
+            tracing::info!(shape_id, fixes = fixes.len(), "output repaired locally");
+            repaired
+        }
+        _ => value,
+    }
+}
+
+/// `types::coerce_bools`, logging each rewritten value.
+fn coerce_bools_logged(shape_id: &str, output_schema: &TypeDef, value: Value) -> Value {
+    let (value, fixes) = coerce_bools(output_schema, value);
+    if !fixes.is_empty() {
+        tracing::info!(shape_id, fixes = fixes.len(), "booleans coerced");
+    }
+    value
+}
+
+/// Adds a generation's attempt count to `Metrics::attempts` when dropped, so
+/// every way out of the retry loop is counted.
+struct AttemptsGuard<'a> {
+    metrics: Option<&'a Metrics>,
+    attempts: u32,
+}
+
+impl Drop for AttemptsGuard<'_> {
+    fn drop(&mut self) {
+        if let (Some(metrics), 1..) = (self.metrics, self.attempts) {
+            metrics.attempts.observe(self.attempts);
+        }
+    }
+}
+
+/// What went wrong with one failed attempt, kept for `with_feedback_history`.
+struct PastAttempt {
+    /// 1-based attempt number
+    attempt: u32,
+    feedback: PastFeedback,
+}
+
+enum PastFeedback {
+    Json(String),
+    Validation(Vec<ValidationError>),
+}
+
+/// Append feedback from attempts before the previous one, oldest first, so the
+/// model sees mistakes it keeps repeating.
+fn push_earlier_feedback<'a>(
+    s: &mut String,
+    earlier: impl Iterator<Item = &'a PastAttempt>,
+    feedback: FeedbackVerbosity,
+) {
+    s.push_str("\nEarlier attempts also failed. Avoid repeating these mistakes:\n");
+    for past in earlier {
+        match &past.feedback {
+            PastFeedback::Json(error) => {
+                s.push_str(&format!("Attempt {}: invalid JSON ({error})\n", past.attempt));
+            }
+            PastFeedback::Validation(errors) => {
+                let hints: Vec<String> = match feedback {
+                    FeedbackVerbosity::Full => merge_errors(errors),
+                    FeedbackVerbosity::Summary => {
+                        errors.iter().take(SUMMARY_EXAMPLES).map(ToString::to_string).collect()
+                    }
+                    FeedbackVerbosity::Minimal => Vec::new(),
+                };
+                s.push_str(&format!("Attempt {}: {}\n", past.attempt, count_errors(errors)));
+                for hint in hints {
+                    s.push_str("- ");
+                    s.push_str(&hint);
+                    s.push('\n');
+                }
+            }
+        }
+    }
+}
+
+// Errors listed in full by `FeedbackVerbosity::Summary`
+const SUMMARY_EXAMPLES: usize = 3;
+
+/// Append the previous attempt's validation errors to a retry prompt.
+fn push_validation_feedback(s: &mut String, errors: &[ValidationError], feedback: FeedbackVerbosity) {
+    match feedback {
+        FeedbackVerbosity::Full => {
+            s.push_str("\nYour previous JSON had these validation problems:\n");
+            for hint in merge_errors(errors) {
+                s.push_str("- ");
+                s.push_str(&hint);
+                s.push('\n');
+            }
+        }
+        FeedbackVerbosity::Summary => {
+            s.push_str(&format!("\nYour previous JSON had {}. ", count_errors(errors)));
+            if errors.len() > SUMMARY_EXAMPLES {
+                s.push_str(&format!("The first {SUMMARY_EXAMPLES} were:\n"));
+            } else {
+                s.push_str("They were:\n");
+            }
+            for e in errors.iter().take(SUMMARY_EXAMPLES) {
+                s.push_str("- ");
+                s.push_str(&e.to_string());
+                s.push('\n');
+            }
+        }
+        FeedbackVerbosity::Minimal => {
+            s.push_str(&format!("\nYour previous JSON had {}.\n", count_errors(errors)));
+        }
+    }
+    s.push_str("\nFix these issues and output ONLY corrected JSON.\n");
+}
+
+/// Error counts by kind, e.g. "2 missing fields and 5 type errors".
+fn count_errors(errors: &[ValidationError]) -> String {
+    let plural = |n: usize, one: &str, many: &str| format!("{n} {}", if n == 1 { one } else { many });
+
+    let missing = errors
+        .iter()
+        .filter(|e| matches!(e, ValidationError::MissingField { .. }))
+        .count();
+    let mismatched = errors
+        .iter()
+        .filter(|e| matches!(e, ValidationError::TypeMismatch { .. }))
+        .count();
+    let violated = errors
+        .iter()
+        .filter(|e| matches!(e, ValidationError::Constraint { .. }))
+        .count();
+    let other = errors.len() - missing - mismatched - violated;
+
+    let mut parts = Vec::new();
+    if missing > 0 {
+        parts.push(plural(missing, "missing field", "missing fields"));
+    }
+    if mismatched > 0 {
+        parts.push(plural(mismatched, "type error", "type errors"));
+    }
+    if violated > 0 {
+        parts.push(plural(violated, "constraint violation", "constraint violations"));
+    }
+    if other > 0 {
+        parts.push(plural(other, "other error", "other errors"));
+    }
+    parts.join(" and ")
+}
+
+/// The schema as described in prompts, in `format`.
+fn render_schema(ty: &TypeDef, format: SchemaFormat) -> String {
+    match format {
+        SchemaFormat::Bullets => describe_schema(ty, 0),
+        SchemaFormat::TypeScript => schema_to_typescript(ty),
+    }
+}
+
 // Human-readable schema description for the prompt.
 fn describe_schema(ty: &TypeDef, indent: usize) -> String {
     use TypeDef::*;
@@ -512,32 +2837,53 @@ fn describe_schema(ty: &TypeDef, indent: usize) -> String {
         Text => s.push_str(&format!("{pad}- string\n")),
         Markdown => s.push_str(&format!("{pad}- string (markdown)\n")),
         Number => s.push_str(&format!("{pad}- number\n")),
+        Integer => s.push_str(&format!("{pad}- integer\n")),
+        IntegerRange { .. } => s.push_str(&format!("{pad}- {}\n", tuple_type_names(std::slice::from_ref(ty)))),
         Bool => s.push_str(&format!("{pad}- boolean\n")),
+        Enum(values) => s.push_str(&format!("{pad}- one of: {}\n", enum_values(values))),
         List(inner) => {
             s.push_str(&format!("{pad}- array of:\n"));
             s.push_str(&describe_schema(inner, indent + 2));
         }
+        Tuple(types) => s.push_str(&format!("{pad}- array of [{}]\n", tuple_type_names(types))),
         Object(fields) => {
             s.push_str(&format!("{pad}- object with fields:\n"));
             for f in fields {
                 s.push_str(&format!("{pad}  - {}: ", f.name));
-                match &f.ty {
-                    Text => s.push_str("string\n"),
-                    Markdown => s.push_str("string (markdown)\n"),
-                    Number => s.push_str("number\n"),
-                    Bool => s.push_str("boolean\n"),
-                    List(inner) => {
-                        s.push_str("array of:\n");
-                        s.push_str(&describe_schema(inner, indent + 4));
-                    }
-                    Object(_) => {
-                        s.push_str("nested object:\n");
-                        s.push_str(&describe_schema(&f.ty, indent + 4));
-                    }
-                }
+                s.push_str(&describe_field_type(&f.ty, indent));
             }
         }
+        Nullable(inner) => s.push_str(&or_null(describe_schema(inner, indent))),
     }
 
     s
 }
+
+/// Inline description of an object field's type, continuing the `- name: ` line.
+fn describe_field_type(ty: &TypeDef, indent: usize) -> String {
+    use TypeDef::*;
+
+    match ty {
+        Text => "string\n".to_string(),
+        Markdown => "string (markdown)\n".to_string(),
+        Number => "number\n".to_string(),
+        Integer => "integer\n".to_string(),
+        IntegerRange { .. } => format!("{}\n", tuple_type_names(std::slice::from_ref(ty))),
+        Bool => "boolean\n".to_string(),
+        Enum(values) => format!("one of: {}\n", enum_values(values)),
+        List(inner) => format!("array of:\n{}", describe_schema(inner, indent + 4)),
+        Tuple(types) => format!("array of [{}]\n", tuple_type_names(types)),
+        Object(_) => format!("nested object:\n{}", describe_schema(ty, indent + 4)),
+        Nullable(inner) => or_null(describe_field_type(inner, indent)),
+    }
+}
+
+/// Mark the first line of a type description as also accepting null:
+/// "string" becomes "string or null", "array of:" becomes "array of (or null):".
+fn or_null(desc: String) -> String {
+    let (first, rest) = desc.split_once('\n').unwrap_or((&desc, ""));
+    match first.strip_suffix(':') {
+        Some(head) => format!("{head} (or null):\n{rest}"),
+        None => format!("{first} or null\n{rest}"),
+    }
+}
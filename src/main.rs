@@ -1,112 +1,1608 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use shape_runner::codec::MsgPackCodec;
-use shape_runner::llm::LlmClient;
+use anyhow::{anyhow, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::Parser;
+use prost::Message;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use shape_runner::cache::{stable_hash, IdempotencyCache, PromptCache};
+use shape_runner::codec::{canonicalize, JsonCodec, ShapeCodec};
+use shape_runner::config::Config;
+use shape_runner::llm::{
+    best_of, BackendKind, CircuitOpen, DeadlineExceeded, LlmClient, RawOutput, ValidationFailed,
+};
+use shape_runner::metrics::Metrics;
+use shape_runner::postprocess::PostProcessors;
+use shape_runner::ratelimit::RateLimiter;
+use shape_runner::recording::{input_hash, RecordedAttempt, Recorder, Recording, ReplayCorpus, Transcript};
+use shape_runner::registry::ShapeRegistry;
+use shape_runner::scoring::{min_score, ComponentCount, CoordinateSpread, OutputScorer, RequirementCount};
 use shape_runner::rpc::shaperunner::shape_runner_server::{ShapeRunner, ShapeRunnerServer};
-use shape_runner::rpc::shaperunner::{RunRequest, RunResponse};
-use shape_runner::shape::{feature_design_output_typedef, formation_output_typedef, FeatureDesignInput, FeatureDesignOutput, FormationInput, FormationOutput};
+use shape_runner::rpc::shaperunner::run_stream_event::Event;
+use shape_runner::rpc::shaperunner::{
+    AttemptBucket, BatchRunItem, BatchRunRequest, BatchRunResponse, DescribeShapeRequest, DescribeShapeResponse,
+    FieldError, MetricsRequest, MetricsResponse, PipelineStage, RunPipelineRequest, RunPipelineResponse, RunRequest, RunResponse,
+    RunStreamEvent, TokenChunk,
+};
+use shape_runner::shape::{
+    api_design_output_typedef, feature_design_output_typedef, formation_output_typedef, grid_output_typedef,
+    requirements_output_typedef, risk_assessment_output_typedef, ApiDesignInput, FeatureDesignInput,
+    FormationGridInput, FormationInput, RequirementsInput, RiskAssessmentInput, MAX_UNIT_COUNT,
+};
+use shape_runner::types::{check_assignable, coerce, validate, TypeDef, ValidationError};
+use shape_runner::web::cors_layer;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
+use tokio_stream::Stream;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tonic::transport::server::TcpIncoming;
 use tonic::{transport::Server, Request, Response, Status};
+use tonic_web::GrpcWebLayer;
+use tracing::Instrument;
 
+// Request metadata key naming a logical request across client retries
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+// Request metadata key carrying the client's gRPC deadline
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+// Request metadata key carrying the caller's request ID for tracing
+const REQUEST_ID: &str = "x-request-id";
+
+// Source of request IDs for requests that don't bring one
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// Batch items run at once when the request doesn't say
+const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+
+// Shapes with a scorer (see `scoring`), which `best_of` and MIN_SCORE need
+const SCORED_SHAPES: &[&str] = &["FeatureDesign", "Formation", "Requirements"];
+
+// Most candidates one request may ask for with best_of
+const MAX_BEST_OF: u32 = 8;
+
+// Most stages one RunPipeline request may chain
+const MAX_PIPELINE_STAGES: usize = 8;
+
+/// Size limits on request inputs, checked before anything reaches a prompt.
+#[derive(Clone, Copy, Debug)]
+struct InputLimits {
+    // Encoded input bytes (see MAX_INPUT_BYTES)
+    max_input_bytes: usize,
+    // Bytes in any one string value, at any depth (see MAX_INPUT_STRING_BYTES)
+    max_string_bytes: usize,
+    // Items in one batch (see MAX_BATCH_ITEMS)
+    max_batch_items: usize,
+    // Batch items in flight at once, whatever the client asks for (see
+    // MAX_BATCH_CONCURRENCY)
+    max_batch_concurrency: usize,
+}
+
+#[derive(Parser)]
+#[command(name = "shape-runner")]
+#[command(about = "ShapeRunner gRPC server")]
+struct Args {
+    /// TOML config file; environment variables override its settings.
+    /// Defaults to SHAPE_RUNNER_CONFIG when set.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Clone)]
 struct ShapeRunnerService<C> {
     codec: C,
     llm: LlmClient,
+    // Clients for shapes with their own LLM endpoint (see LLM_BASE_URL_<shape_id>)
+    shape_llms: Arc<BTreeMap<String, LlmClient>>,
+    registry: Arc<ShapeRegistry>,
+    // Registered shapes this server is allowed to run (see ENABLED_SHAPES)
+    enabled: Arc<BTreeSet<String>>,
+    // Field names whose values are never logged (see LOG_REDACT_FIELDS)
+    redact: Arc<BTreeSet<String>>,
+    // Per-shape model overrides (see MODEL_<shape_id>)
+    models: Arc<BTreeMap<String, String>>,
+    // Per-shape default candidate counts (see BEST_OF_<shape_id>)
+    best_of: Arc<BTreeMap<String, u32>>,
+    // Shapes that return degraded output instead of failing (see DEGRADED_SHAPES)
+    degraded: Arc<BTreeSet<String>>,
+    // Output transforms per built-in shape (see POSTPROCESS_<shape_id>)
+    post: Arc<PostProcessors>,
+    // Writes every request to RECORD_FILE when set
+    recorder: Option<Arc<Recorder>>,
+    // Recorded responses served instead of the LLM (see REPLAY_FILE)
+    replay: Option<Arc<ReplayCorpus>>,
+    // Counters for the Metrics RPC, shared with the LLM clients
+    metrics: Arc<Metrics>,
+    // Responses by `idempotency-key` request metadata
+    idempotency: Arc<IdempotencyCache<RunResponse>>,
+    limits: InputLimits,
 }
 
-#[tonic::async_trait]
-impl<C> ShapeRunner for ShapeRunnerService<C>
+impl<C> ShapeRunnerService<C>
 where
-    C: shape_runner::codec::ShapeCodec + Send + Sync + 'static,
+    C: ShapeCodec + Clone + Send + Sync + 'static,
 {
-    async fn run(&self, request: Request<RunRequest>) -> Result<Response<RunResponse>, Status> {
+    /// Encode a successful output with the server codec, or as JSON when the
+    /// request asked for it. With `canonical_output`, object keys are sorted
+    /// first. With `include_json_base64`, the output is also encoded as JSON
+    /// and returned base64-encoded alongside.
+    #[allow(clippy::result_large_err)]
+    fn encode_output<T: Serialize>(&self, inner: &RunRequest, output: &T) -> Result<RunResponse, Status> {
+        let shape_id = &inner.shape_id;
+        let as_json = inner.output_as_json;
+        let canonical = if inner.canonical_output {
+            Some(canonicalize(output).map_err(|e| Status::internal(format!("encode output failed: {e}")))?)
+        } else {
+            None
+        };
+        let encode_json = || match &canonical {
+            Some(value) => JsonCodec.encode_value(value),
+            None => JsonCodec.encode(output),
+        };
+        let output_bytes = match &canonical {
+            _ if as_json => encode_json(),
+            Some(value) => self.codec.encode_value(value),
+            None => self.codec.encode(output),
+        }
+        .map_err(|e| Status::internal(format!("encode output failed: {e}")))?;
+
+        // Log what the client will decode, not the in-memory value
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            let decoded = if as_json {
+                JsonCodec.decode_value(&output_bytes)
+            } else {
+                self.codec.decode_value(&output_bytes)
+            };
+            match decoded {
+                Ok(value) => self.log_payload(shape_id, "output", &value),
+                Err(e) => tracing::debug!(shape_id, error = %e, "encoded output does not decode"),
+            }
+        }
+
+        let output_json_base64 = if !inner.include_json_base64 {
+            String::new()
+        } else if as_json {
+            BASE64_STANDARD.encode(&output_bytes)
+        } else {
+            let json = encode_json().map_err(|e| Status::internal(format!("encode output as JSON failed: {e}")))?;
+            BASE64_STANDARD.encode(json)
+        };
+
+        Ok(RunResponse {
+            output: output_bytes,
+            ok: true,
+            error: String::new(),
+            output_is_json: as_json,
+            output_json_base64,
+            ..Default::default()
+        })
+    }
+
+    /// Encode the result of a shape's generate call. When it failed
+    /// validation and degraded output is allowed for this request or shape
+    /// (see DEGRADED_SHAPES), the last model output is repaired to fit
+    /// `output_schema` and returned flagged `degraded` instead.
+    #[allow(clippy::result_large_err)]
+    fn finish<T: Serialize>(
+        &self,
+        inner: &RunRequest,
+        result: Result<T>,
+        output_schema: &TypeDef,
+    ) -> Result<RunResponse, Status> {
+        let e = match result {
+            Ok(output) => {
+                return self.encode_output(inner, &output)
+            }
+            Err(e) => e,
+        };
+        if e.downcast_ref::<DeadlineExceeded>().is_some() {
+            return Err(Status::deadline_exceeded(format!("LLM error: {e}")));
+        }
+        if e.downcast_ref::<CircuitOpen>().is_some() {
+            return Err(Status::resource_exhausted(format!("LLM error: {e}")));
+        }
+
+        let Some(failed) = e.downcast_ref::<ValidationFailed>() else {
+            return Err(Status::internal(format!("LLM error: {e}")));
+        };
+        let errors = field_errors(&failed.errors);
+        let allowed = inner.allow_degraded || self.degraded.contains(&inner.shape_id);
+        let (true, Some(value)) = (allowed, failed.last_value.clone()) else {
+            // The model's fault, not the server's: reported in the response
+            // with the individual errors rather than as a gRPC error
+            return Ok(RunResponse {
+                ok: false,
+                error: e.to_string(),
+                errors,
+                ..Default::default()
+            });
+        };
+
+        tracing::warn!(shape_id = %inner.shape_id, error = %e, "returning degraded output");
+        let output = coerce(output_schema, value);
+        let mut response = self.encode_output(inner, &output)?;
+        response.degraded = true;
+        response.error = e.to_string();
+        response.errors = errors;
+        Ok(response)
+    }
+
+    /// Decode request input bytes with the server codec, enforcing the input
+    /// size limits. Oversized input is `resource_exhausted`; an over-long
+    /// string field is `invalid_argument`.
+    #[allow(clippy::result_large_err)]
+    fn decode_input<T: DeserializeOwned + Serialize>(&self, shape_id: &str, input: &[u8]) -> Result<T, Status> {
+        if input.len() > self.limits.max_input_bytes {
+            return Err(Status::resource_exhausted(format!(
+                "input is {} bytes, limit is {}",
+                input.len(),
+                self.limits.max_input_bytes
+            )));
+        }
+        let decoded: T = self
+            .codec
+            .decode(input)
+            .map_err(|e| Status::invalid_argument(format!("decode input failed: {e}")))?;
+
+        let value = serde_json::to_value(&decoded)
+            .map_err(|e| Status::invalid_argument(format!("decode input failed: {e}")))?;
+        if let Some((path, len)) = oversized_string(&value, "input", self.limits.max_string_bytes) {
+            return Err(Status::invalid_argument(format!(
+                "{path} is {len} bytes, limit is {}",
+                self.limits.max_string_bytes
+            )));
+        }
+        self.log_payload(shape_id, "input", &value);
+        Ok(decoded)
+    }
+
+    /// Log a request input or output at debug level, with the values of
+    /// redacted fields replaced by `"<redacted>"`.
+    fn log_payload<T: Serialize>(&self, shape_id: &str, kind: &str, payload: &T) {
+        if !tracing::enabled!(tracing::Level::DEBUG) {
+            return;
+        }
+        let Ok(mut value) = serde_json::to_value(payload) else {
+            return;
+        };
+        redact_fields(&mut value, &self.redact);
+        tracing::debug!(shape_id, payload = %value, "{kind}");
+    }
+
+    /// `run` inside its request span.
+    async fn run_traced(&self, request: Request<RunRequest>) -> Result<Response<RunResponse>, Status> {
+        // tonic drops this future when the client disconnects; the guard then
+        // cancels the token so the retry loop stops at once.
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+
+        // A retried request with the same key gets the first one's response
+        // instead of running the LLM again
+        let key = request
+            .metadata()
+            .get(IDEMPOTENCY_KEY)
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_string);
+        let deadline = request_deadline(&request);
         let inner = request.into_inner();
+        let mut llm = self.llm_for(&inner.shape_id).clone().with_cancellation(cancel);
+        if let Some(deadline) = deadline {
+            llm = llm.with_deadline(deadline);
+        }
+        let response = match key {
+            Some(key) => {
+                // The whole request is part of the key: reusing a key with a
+                // different input or options runs the new request instead of
+                // returning the first one's response
+                let key = format!("{}/{}/{}", inner.shape_id, key, stable_hash(&[&inner.encode_to_vec()]));
+                // Only successful responses are cached: a run whose output
+                // never validated comes back as the error side, so a retry
+                // under the same key runs again
+                self.idempotency
+                    .get_or_run(key, || async move {
+                        match self.run_one(inner, llm).await {
+                            Ok(response) if !response.ok => Err(Ok(response)),
+                            result => result.map_err(Err),
+                        }
+                    })
+                    .await
+                    .or_else(|uncached| uncached)
+            }
+            None => self.run_one(inner, llm).await,
+        };
+        response.map(Response::new)
+    }
+
+    /// The client for `shape_id`: its own when configured, else the default.
+    fn llm_for(&self, shape_id: &str) -> &LlmClient {
+        self.shape_llms.get(shape_id).unwrap_or(&self.llm)
+    }
+
+    /// Run a single request on `llm`, the shared client with any per-call
+    /// settings such as cancellation already applied.
+    async fn run_one(&self, inner: RunRequest, llm: LlmClient) -> Result<RunResponse, Status> {
+        // Unknown IDs share one key so clients can't grow the map at will
+        let shape_id = match self.registry.get(&inner.shape_id) {
+            Some(spec) => spec.id.clone(),
+            None => "(unknown)".to_string(),
+        };
+
+        // Input that doesn't decode fails in run_shape; it is never recorded
+        // or replayed
+        let mut llm = llm;
+        let input = if (self.recorder.is_some() || self.replay.is_some())
+            && inner.input.len() <= self.limits.max_input_bytes
+        {
+            self.codec
+                .decode_value(&inner.input)
+                .ok()
+                .map(|input| (input_hash(&inner.shape_id, &input), input))
+        } else {
+            None
+        };
+        if let (Some(replay), Some((hash, _))) = (&self.replay, &input) {
+            let Some(backend) = replay.backend(hash) else {
+                return Err(Status::not_found(format!(
+                    "no recording of this {} input (hash {hash})",
+                    inner.shape_id
+                )));
+            };
+            llm = llm.with_llm_backend(Arc::new(backend));
+        }
+        let transcript = self.recorder.as_ref().map(|_| Arc::new(Transcript::default()));
+        if let Some(transcript) = &transcript {
+            llm = llm.with_transcript(transcript.clone());
+        }
+
+        let recorded_shape_id = inner.shape_id.clone();
+        let started = Instant::now();
+        let response = self.run_shape(inner, llm).await;
+        let ok = response.as_ref().is_ok_and(|response| response.ok);
+        self.metrics.observe_request(&shape_id, started.elapsed(), ok);
+
+        if let (Some(recorder), Some(transcript), Some((input_hash, input))) = (&self.recorder, transcript, input) {
+            recorder.record(&self.recording(recorded_shape_id, input_hash, input, transcript.take(), &response));
+        }
+        response
+    }
 
-        match inner.shape_id.as_str() {
+    /// What `Recorder` writes for one request: its input, every LLM call it
+    /// made and how it ended.
+    fn recording(
+        &self,
+        shape_id: String,
+        input_hash: String,
+        input: Value,
+        attempts: Vec<RecordedAttempt>,
+        response: &Result<RunResponse, Status>,
+    ) -> Recording {
+        let (output, error) = match response {
+            Ok(response) if response.ok => {
+                let output = if response.output_is_json {
+                    JsonCodec.decode_value(&response.output)
+                } else {
+                    self.codec.decode_value(&response.output)
+                };
+                let error = (!response.error.is_empty()).then(|| response.error.clone());
+                (output.ok(), error)
+            }
+            Ok(response) => (None, Some(response.error.clone())),
+            Err(status) => (None, Some(status.message().to_string())),
+        };
+        Recording {
+            shape_id,
+            input_hash,
+            input,
+            attempts,
+            output,
+            error,
+        }
+    }
+
+    async fn run_shape(&self, inner: RunRequest, llm: LlmClient) -> Result<RunResponse, Status> {
+        let Some(spec) = self.registry.get(&inner.shape_id) else {
+            return Err(Status::not_found(format!("unknown shape_id: {}", inner.shape_id)));
+        };
+        if !self.enabled.contains(&inner.shape_id) {
+            return Err(Status::permission_denied(format!(
+                "shape_id is disabled on this server: {}",
+                inner.shape_id
+            )));
+        }
+
+        // Per-request overrides of the shared client
+        let mut llm = llm;
+        if let Some(model) = self.models.get(&inner.shape_id) {
+            llm = llm.with_model(model.as_str());
+        }
+        if inner.fail_fast {
+            llm = llm.with_fail_fast(true);
+        }
+        let raw_output = inner.include_raw_output.then(|| Arc::new(RawOutput::default()));
+        if let Some(slot) = &raw_output {
+            llm = llm.with_raw_output(slot.clone());
+        }
+        let llm = &llm;
+        let candidates = match inner.best_of {
+            0 => self.best_of.get(&inner.shape_id).copied().unwrap_or(1),
+            n => n,
+        };
+        if candidates > MAX_BEST_OF {
+            return Err(Status::invalid_argument(format!(
+                "best_of ({candidates}) is greater than the maximum of {MAX_BEST_OF}"
+            )));
+        }
+        if candidates > 1 && !SCORED_SHAPES.contains(&inner.shape_id.as_str()) {
+            return Err(Status::invalid_argument(format!(
+                "best_of needs a scorer to pick a candidate; {} has none ({} do)",
+                inner.shape_id,
+                SCORED_SHAPES.join(", ")
+            )));
+        }
+        // A recording holds one LLM call per attempt, and a replay answers
+        // them in order, so concurrent candidates would interleave them
+        let candidates = if self.recorder.is_some() || self.replay.is_some() { 1 } else { candidates };
+
+        let mut response = match inner.shape_id.as_str() {
             "FeatureDesign" => {
-                // Decode input bytes to FeatureDesignInput
-                let input: FeatureDesignInput = self
-                    .codec
-                    .decode(&inner.input)
-                    .map_err(|e| Status::invalid_argument(format!("decode input failed: {e}")))?;
+                let input: FeatureDesignInput = self.decode_input(&inner.shape_id, &inner.input)?;
+                let blank = input.blank_constraints();
+                if !blank.is_empty() {
+                    return Err(Status::invalid_argument(format!(
+                        "constraints must not be empty or whitespace-only: indices {blank:?}"
+                    )));
+                }
+                if let (Some(min), Some(max)) = (input.min_components, input.max_components) {
+                    if min > max {
+                        return Err(Status::invalid_argument(format!(
+                            "min_components ({min}) is greater than max_components ({max})"
+                        )));
+                    }
+                }
 
                 // Call LLM + validation
-                let output: FeatureDesignOutput = self
-                    .llm
-                    .generate_feature_design(&input, &feature_design_output_typedef())
+                let output_schema = feature_design_output_typedef();
+                let generate = |llm: LlmClient| {
+                    let (input, output_schema) = (&input, &output_schema);
+                    async move { llm.generate_feature_design(input, output_schema).await }
+                };
+                let result = best_of(llm, candidates, generate, |output| ComponentCount.score(output, &input))
                     .await
-                    .map_err(|e| Status::internal(format!("LLM error: {e}")))?;
+                    .map(|output| self.post.feature_design.apply(output));
 
-                // Encode output to bytes
-                let output_bytes = self
-                    .codec
-                    .encode(&output)
-                    .map_err(|e| Status::internal(format!("encode output failed: {e}")))?;
+                self.finish(&inner, result, &output_schema)
+            }
+            "Formation" => {
+                let input: FormationInput = self.decode_input(&inner.shape_id, &inner.input)?;
+                check_unit_count(input.unit_count)?;
+                check_normalize_box(&input)?;
 
-                let resp = RunResponse {
-                    output: output_bytes,
-                    ok: true,
-                    error: String::new(),
+                // Call LLM + validation
+                let output_schema = formation_output_typedef();
+                let generate = |llm: LlmClient| {
+                    let (input, output_schema) = (&input, &output_schema);
+                    async move { llm.generate_formation(input, output_schema).await }
                 };
+                let result = best_of(llm, candidates, generate, |output| CoordinateSpread.score(output, &input))
+                    .await
+                    .map(|output| self.post.formation.apply(output));
 
-                Ok(Response::new(resp))
+                self.finish(&inner, result, &output_schema)
             }
-            "Formation" => {
-                // Decode input bytes to FormationInput
-                let input: FormationInput = self
-                    .codec
-                    .decode(&inner.input)
-                    .map_err(|e| Status::invalid_argument(format!("decode input failed: {e}")))?;
+            "FormationGrid" => {
+                let input: FormationGridInput = self.decode_input(&inner.shape_id, &inner.input)?;
+                check_unit_count(input.unit_count)?;
+                check_grid_size(&input)?;
 
-                // Call LLM + validation
-                let output: FormationOutput = self
-                    .llm
-                    .generate_formation(&input, &formation_output_typedef())
+                let output_schema = grid_output_typedef();
+                let result = llm
+                    .generate_formation_grid(&input, &output_schema)
                     .await
-                    .map_err(|e| Status::internal(format!("LLM error: {e}")))?;
+                    .map(|output| self.post.formation_grid.apply(output));
+
+                self.finish(&inner, result, &output_schema)
+            }
+            "Requirements" => {
+                let input: RequirementsInput = self.decode_input(&inner.shape_id, &inner.input)?;
+                if input.document.trim().is_empty() {
+                    return Err(Status::invalid_argument("document must not be empty"));
+                }
+
+                let output_schema = requirements_output_typedef();
+                let generate = |llm: LlmClient| {
+                    let (input, output_schema) = (&input, &output_schema);
+                    async move { llm.generate_requirements(input, output_schema).await }
+                };
+                let result = best_of(llm, candidates, generate, |output| RequirementCount.score(output, &input)).await;
+
+                self.finish(&inner, result, &output_schema)
+            }
+            "ApiDesign" => {
+                let input: ApiDesignInput = self.decode_input(&inner.shape_id, &inner.input)?;
+                if input.resource.trim().is_empty() {
+                    return Err(Status::invalid_argument("resource must not be empty"));
+                }
+                if input.operations.iter().all(|operation| operation.trim().is_empty()) {
+                    return Err(Status::invalid_argument("operations must list at least one operation"));
+                }
 
-                // Encode output to bytes
-                let output_bytes = self
+                let output_schema = api_design_output_typedef();
+                let result = llm.generate_api_design(&input, &output_schema).await;
+
+                self.finish(&inner, result, &output_schema)
+            }
+            "RiskAssessment" => {
+                let input: RiskAssessmentInput = self.decode_input(&inner.shape_id, &inner.input)?;
+                if input.design.risks.iter().all(|risk| risk.trim().is_empty()) {
+                    return Err(Status::invalid_argument("design must list at least one risk"));
+                }
+
+                let output_schema = risk_assessment_output_typedef();
+                let result = llm.generate_risk_assessment(&input, &output_schema).await;
+
+                self.finish(&inner, result, &output_schema)
+            }
+            _ => {
+                // Data-driven shape loaded from a manifest
+                let Some(template) = &spec.prompt_template else {
+                    return Err(Status::not_found(format!("no handler for shape_id: {}", inner.shape_id)));
+                };
+
+                let input: Value = self.decode_input(&inner.shape_id, &inner.input)?;
+                if let Some(input_typedef) = &spec.input_typedef {
+                    validate(input_typedef, &input).map_err(|errors| {
+                        let details: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                        Status::invalid_argument(format!("invalid input: {}", details.join("; ")))
+                    })?;
+                }
+
+                let result = llm
+                    .generate_from_template(&spec.id, template, &input, &spec.output_typedef)
+                    .await;
+
+                self.finish(&inner, result, &spec.output_typedef)
+            }
+        }?;
+
+        if inner.include_schema {
+            response.schema = spec.output_typedef.to_json_schema().to_string();
+        }
+        // Empty for degraded output, which never passed validation
+        if let Some(text) = raw_output.and_then(|slot| slot.take()) {
+            response.raw_output = text;
+        }
+        Ok(response)
+    }
+
+    /// Check a pipeline before running any of it: there are stages, each names
+    /// an enabled shape, and each stage after the first can take the previous
+    /// stage's output where `input_field` puts it, judged by the registered
+    /// typedefs. Returns the decoded `input` of every stage after the first
+    /// (None for the first, whose input goes to it as sent).
+    #[allow(clippy::result_large_err)]
+    fn check_pipeline(&self, stages: &[PipelineStage]) -> Result<Vec<Option<Value>>, Status> {
+        if stages.is_empty() {
+            return Err(Status::invalid_argument("stages must not be empty"));
+        }
+        if stages.len() > MAX_PIPELINE_STAGES {
+            return Err(Status::invalid_argument(format!(
+                "pipeline has {} stages, the maximum is {MAX_PIPELINE_STAGES}",
+                stages.len()
+            )));
+        }
+
+        let mut inputs = Vec::with_capacity(stages.len());
+        for (index, stage) in stages.iter().enumerate() {
+            let name = format!("stage {} ({})", index + 1, stage.shape_id);
+            let Some((input_typedef, _)) = self.registry.schemas_for(&stage.shape_id) else {
+                return Err(Status::not_found(format!("{name}: unknown shape_id")));
+            };
+            if !self.enabled.contains(&stage.shape_id) {
+                return Err(Status::permission_denied(format!("{name}: shape_id is disabled on this server")));
+            }
+            let Some(previous) = index.checked_sub(1).map(|i| &stages[i]) else {
+                if !stage.input_field.is_empty() {
+                    return Err(Status::invalid_argument(format!(
+                        "{name}: the first stage has no previous output to put in input_field"
+                    )));
+                }
+                inputs.push(None);
+                continue;
+            };
+
+            let input = if stage.input.is_empty() {
+                None
+            } else {
+                let input = self
                     .codec
-                    .encode(&output)
-                    .map_err(|e| Status::internal(format!("encode output failed: {e}")))?;
+                    .decode_value(&stage.input)
+                    .map_err(|e| Status::invalid_argument(format!("{name}: decode input failed: {e}")))?;
+                Some(input)
+            };
+            // Where the previous output goes, or None when the shape has no
+            // input typedef to check it against
+            let target = if stage.input_field.is_empty() {
+                if input.is_some() {
+                    return Err(Status::invalid_argument(format!(
+                        "{name}: the previous output is the whole input, so input must be empty; \
+                         set input_field to combine them"
+                    )));
+                }
+                input_typedef.as_ref()
+            } else {
+                match &input {
+                    None => {}
+                    Some(Value::Object(fields)) if fields.contains_key(&stage.input_field) => {
+                        return Err(Status::invalid_argument(format!(
+                            "{name}: input sets {:?}, which input_field fills from the previous stage",
+                            stage.input_field
+                        )));
+                    }
+                    Some(Value::Object(_)) => {}
+                    Some(_) => {
+                        return Err(Status::invalid_argument(format!(
+                            "{name}: input must be an object when input_field is set"
+                        )));
+                    }
+                }
+                match &input_typedef {
+                    None => None,
+                    Some(TypeDef::Object(fields)) => match fields.iter().find(|f| f.name == stage.input_field) {
+                        Some(field) => Some(&field.ty),
+                        None => {
+                            let names: Vec<&str> = fields.iter().map(|f| f.name.as_ref()).collect();
+                            return Err(Status::invalid_argument(format!(
+                                "{name} has no input field {:?}; its fields are {}",
+                                stage.input_field,
+                                names.join(", ")
+                            )));
+                        }
+                    },
+                    Some(_) => {
+                        return Err(Status::invalid_argument(format!(
+                            "{name}: input_field needs a shape whose input is an object"
+                        )));
+                    }
+                }
+            };
 
-                let resp = RunResponse {
-                    output: output_bytes,
-                    ok: true,
-                    error: String::new(),
+            if let Some(target) = target {
+                let output_typedef = &self.registry.get(&previous.shape_id).expect("checked above").output_typedef;
+                check_assignable(target, output_typedef).map_err(|reason| {
+                    let destination = match stage.input_field.as_str() {
+                        "" => "as its input".to_string(),
+                        field => format!("as {field:?}"),
+                    };
+                    Status::invalid_argument(format!(
+                        "{name} can't take the output of stage {index} ({}) {destination}: {reason}",
+                        previous.shape_id
+                    ))
+                })?;
+            }
+            inputs.push(input);
+        }
+        Ok(inputs)
+    }
+
+    /// Run checked pipeline stages in order, each through `run_one` like a
+    /// `Run` request on a client with `cancel` and `deadline` applied, with
+    /// `best_of` 1. Each stage after the first gets the previous output at
+    /// its `input_field`.
+    /// Stops at the first stage that fails, or that returns degraded output
+    /// another stage would consume.
+    async fn run_stages(
+        &self,
+        pipeline: RunPipelineRequest,
+        inputs: Vec<Option<Value>>,
+        cancel: CancellationToken,
+        deadline: Option<Instant>,
+    ) -> RunPipelineResponse {
+        let count = pipeline.stages.len();
+        let mut responses: Vec<RunResponse> = Vec::with_capacity(count);
+        let mut previous: Option<Value> = None;
+
+        for (index, (stage, input)) in pipeline.stages.into_iter().zip(inputs).enumerate() {
+            let name = format!("stage {} ({})", index + 1, stage.shape_id);
+            let input = match previous.take() {
+                None => Ok(stage.input),
+                Some(output) if stage.input_field.is_empty() => self.codec.encode_value(&output),
+                Some(output) => {
+                    let mut fields = match input {
+                        Some(Value::Object(fields)) => fields,
+                        _ => Map::new(),
+                    };
+                    fields.insert(stage.input_field, output);
+                    self.codec.encode_value(&Value::Object(fields))
+                }
+            };
+
+            let response = match input {
+                Ok(input) => {
+                    let mut llm = self.llm_for(&stage.shape_id).clone().with_cancellation(cancel.clone());
+                    if let Some(deadline) = deadline {
+                        llm = llm.with_deadline(deadline);
+                    }
+                    // One candidate per stage: the rate limit counts the
+                    // pipeline once, so BEST_OF_* must not multiply its calls
+                    let request = RunRequest {
+                        shape_id: stage.shape_id,
+                        input,
+                        output_as_json: pipeline.output_as_json,
+                        best_of: 1,
+                        ..Default::default()
+                    };
+                    self.run_one(request, llm).await.unwrap_or_else(|status| RunResponse {
+                        ok: false,
+                        error: status.message().to_string(),
+                        ..Default::default()
+                    })
+                }
+                Err(e) => RunResponse {
+                    ok: false,
+                    error: format!("encode input failed: {e}"),
+                    ..Default::default()
+                },
+            };
+
+            let last = index + 1 == count;
+            let error = if !response.ok {
+                Some(format!("{name} failed: {}", response.error))
+            } else if response.degraded && !last {
+                Some(format!("{name} returned degraded output, which later stages don't take: {}", response.error))
+            } else if last {
+                None
+            } else {
+                let output = if response.output_is_json {
+                    JsonCodec.decode_value(&response.output)
+                } else {
+                    self.codec.decode_value(&response.output)
+                };
+                match output {
+                    Ok(output) => {
+                        previous = Some(output);
+                        None
+                    }
+                    Err(e) => Some(format!("{name}: output does not decode: {e}")),
+                }
+            };
+            responses.push(response);
+
+            if let Some(error) = error {
+                tracing::warn!(stage = index + 1, shape_id = %name, error = %error, "pipeline stopped");
+                return RunPipelineResponse {
+                    stages: responses,
+                    ok: false,
+                    error,
+                };
+            }
+        }
+
+        RunPipelineResponse {
+            stages: responses,
+            ok: true,
+            error: String::new(),
+        }
+    }
+
+    /// Run batch items with bounded concurrency, sending each result to `sink`
+    /// as soon as it completes. Per-item failures are reported as `ok: false`
+    /// responses rather than failing the whole batch. Stops scheduling new
+    /// items once the receiving side is dropped. Once `cancel` fires, nothing
+    /// more is scheduled and items in flight are aborted: each item's generate
+    /// loop watches `cancel`, and its task is aborted through the batch's
+    /// `JoinSet` in case it is not at a cancellation point. Every item shares
+    /// the batch's `deadline`.
+    /// Reject a batch with more than `max_batch_items` items. A batch counts
+    /// as one request for the rate limit, so this bounds what one request
+    /// can start.
+    #[allow(clippy::result_large_err)]
+    fn check_batch_size(&self, batch: &BatchRunRequest) -> Result<(), Status> {
+        let max = self.limits.max_batch_items;
+        if batch.items.len() > max {
+            return Err(Status::resource_exhausted(format!(
+                "batch has {} items, more than the maximum of {max}",
+                batch.items.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn spawn_batch(
+        &self,
+        batch: BatchRunRequest,
+        sink: mpsc::Sender<Result<BatchRunItem, Status>>,
+        cancel: CancellationToken,
+        deadline: Option<Instant>,
+    ) {
+        let concurrency = match batch.max_concurrency {
+            0 => DEFAULT_BATCH_CONCURRENCY,
+            n => n as usize,
+        }
+        .min(self.limits.max_batch_concurrency);
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let service = self.clone();
+
+        tokio::spawn(async move {
+            let mut running = JoinSet::new();
+            for (index, item) in batch.items.into_iter().enumerate() {
+                let permit = tokio::select! {
+                    permit = semaphore.clone().acquire_owned() => permit,
+                    _ = cancel.cancelled() => break,
+                };
+                let Ok(permit) = permit else {
+                    break;
                 };
+                if sink.is_closed() || cancel.is_cancelled() {
+                    break;
+                }
+                // Reap finished items so the set only holds those in flight
+                while running.try_join_next().is_some() {}
+
+                let service = service.clone();
+                let sink = sink.clone();
+                let cancel = cancel.clone();
+                running.spawn(async move {
+                    let mut llm = service.llm_for(&item.shape_id).clone().with_cancellation(cancel);
+                    if let Some(deadline) = deadline {
+                        llm = llm.with_deadline(deadline);
+                    }
+                    let response = service.run_one(item, llm).await.unwrap_or_else(|status| RunResponse {
+                        ok: false,
+                        error: status.message().to_string(),
+                        ..Default::default()
+                    });
+                    let _ = sink
+                        .send(Ok(BatchRunItem {
+                            index: index as u32,
+                            response: Some(response),
+                        }))
+                        .await;
+                    drop(permit);
+                });
+            }
+
+            tokio::select! {
+                _ = async { while running.join_next().await.is_some() {} } => {}
+                _ = cancel.cancelled() => {
+                    tracing::debug!(in_flight = running.len(), "batch cancelled, aborting items in flight");
+                }
+            }
+            running.shutdown().await;
+        });
+    }
+}
+
+#[tonic::async_trait]
+impl<C> ShapeRunner for ShapeRunnerService<C>
+where
+    C: ShapeCodec + Clone + Send + Sync + 'static,
+{
+    async fn run(&self, request: Request<RunRequest>) -> Result<Response<RunResponse>, Status> {
+        let span = request_span(&request, &request.get_ref().shape_id);
+        self.run_traced(request).instrument(span).await
+    }
+
+    async fn run_batch(&self, request: Request<BatchRunRequest>) -> Result<Response<BatchRunResponse>, Status> {
+        let deadline = request_deadline(&request);
+        let batch = request.into_inner();
+        self.check_batch_size(&batch)?;
+        let total = batch.items.len();
+        let (tx, mut rx) = mpsc::channel(total.max(1));
+        // Batch items run in spawned tasks that outlive this future; cancel
+        // them if the client disconnects before the batch completes.
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        self.spawn_batch(batch, tx, cancel, deadline);
+
+        let mut items = Vec::with_capacity(total);
+        while let Some(item) = rx.recv().await {
+            items.push(item?);
+        }
+        items.sort_by_key(|item| item.index);
+
+        Ok(Response::new(BatchRunResponse { items }))
+    }
+
+    type RunBatchStreamStream = CancelOnDrop<ReceiverStream<Result<BatchRunItem, Status>>>;
+
+    async fn run_batch_stream(
+        &self,
+        request: Request<BatchRunRequest>,
+    ) -> Result<Response<Self::RunBatchStreamStream>, Status> {
+        // A client that drops the stream closes the channel, which stops the
+        // batch from scheduling further items.
+        let deadline = request_deadline(&request);
+        let batch = request.into_inner();
+        self.check_batch_size(&batch)?;
+        let (tx, rx) = mpsc::channel(DEFAULT_BATCH_CONCURRENCY);
+        let cancel = CancellationToken::new();
+        self.spawn_batch(batch, tx, cancel.clone(), deadline);
+
+        Ok(Response::new(CancelOnDrop {
+            stream: ReceiverStream::new(rx),
+            _guard: cancel.drop_guard(),
+        }))
+    }
+
+    async fn run_pipeline(
+        &self,
+        request: Request<RunPipelineRequest>,
+    ) -> Result<Response<RunPipelineResponse>, Status> {
+        let shapes: Vec<&str> = request.get_ref().stages.iter().map(|s| s.shape_id.as_str()).collect();
+        let span = request_span(&request, &shapes.join(" -> "));
+        async {
+            // Stages run in this future, so dropping it (the client went
+            // away) cancels the one in flight and no later stage starts
+            let cancel = CancellationToken::new();
+            let _cancel_on_drop = cancel.clone().drop_guard();
+            let deadline = request_deadline(&request);
+            let pipeline = request.into_inner();
+            let inputs = self.check_pipeline(&pipeline.stages)?;
+            Ok(Response::new(self.run_stages(pipeline, inputs, cancel, deadline).await))
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn describe_shape(
+        &self,
+        request: Request<DescribeShapeRequest>,
+    ) -> Result<Response<DescribeShapeResponse>, Status> {
+        let shape_id = request.into_inner().shape_id;
+        let Some((input_typedef, output_typedef)) = self.registry.schemas_for(&shape_id) else {
+            return Err(Status::not_found(format!("unknown shape_id: {shape_id}")));
+        };
+        if !self.enabled.contains(&shape_id) {
+            return Err(Status::permission_denied(format!("shape_id is disabled on this server: {shape_id}")));
+        }
+
+        Ok(Response::new(DescribeShapeResponse {
+            input_schema: input_typedef
+                .map(|typedef| typedef.to_json_schema().to_string())
+                .unwrap_or_default(),
+            output_schema: output_typedef.to_json_schema().to_string(),
+            shape_id,
+        }))
+    }
+
+    async fn metrics(&self, _request: Request<MetricsRequest>) -> Result<Response<MetricsResponse>, Status> {
+        let snapshot = self.metrics.snapshot();
+        Ok(Response::new(MetricsResponse {
+            requests_total: snapshot.requests_total,
+            requests_failed: snapshot.requests_failed,
+            requests_by_shape: snapshot.requests_by_shape.into_iter().collect(),
+            attempts: snapshot
+                .attempts
+                .into_iter()
+                .map(|(attempts, count)| AttemptBucket { attempts, count })
+                .collect(),
+            avg_request_seconds: snapshot.request_seconds.mean_seconds(),
+            avg_llm_call_seconds: snapshot.llm_call_seconds.mean_seconds(),
+        }))
+    }
+
+    type RunStreamStream = CancelOnDrop<UnboundedReceiverStream<Result<RunStreamEvent, Status>>>;
+
+    async fn run_stream(&self, request: Request<RunRequest>) -> Result<Response<Self::RunStreamStream>, Status> {
+        // Unbounded so the token sink never blocks the LLM call; chunks are
+        // small and arrive no faster than the model generates them.
+        let (tx, rx) = mpsc::unbounded_channel();
+        let cancel = CancellationToken::new();
 
-                Ok(Response::new(resp))
+        let chunks = tx.clone();
+        let mut llm = self
+            .llm_for(&request.get_ref().shape_id)
+            .clone()
+            .with_cancellation(cancel.clone())
+            .with_streaming(true)
+            .with_token_sink(move |_shape_id, attempt, text| {
+                let _ = chunks.send(Ok(RunStreamEvent {
+                    event: Some(Event::Chunk(TokenChunk {
+                        attempt,
+                        text: text.to_string(),
+                    })),
+                }));
+            });
+        if let Some(deadline) = request_deadline(&request) {
+            llm = llm.with_deadline(deadline);
+        }
+
+        let service = self.clone();
+        let span = request_span(&request, &request.get_ref().shape_id);
+        let inner = request.into_inner();
+        tokio::spawn(
+            async move {
+                let response = service.run_one(inner, llm).await.unwrap_or_else(|status| RunResponse {
+                    ok: false,
+                    error: status.message().to_string(),
+                    ..Default::default()
+                });
+                let _ = tx.send(Ok(RunStreamEvent {
+                    event: Some(Event::Result(response)),
+                }));
             }
-            _ => Err(Status::not_found(format!("unknown shape_id: {}", inner.shape_id))),
+            .instrument(span),
+        );
+
+        Ok(Response::new(CancelOnDrop {
+            stream: UnboundedReceiverStream::new(rx),
+            _guard: cancel.drop_guard(),
+        }))
+    }
+}
+
+/// Response stream that cancels its work when tonic drops it, i.e. when the
+/// client disconnects.
+struct CancelOnDrop<S> {
+    stream: S,
+    _guard: DropGuard,
+}
+
+impl<S: Stream + Unpin> Stream for CancelOnDrop<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+/// Replace the value of every object field named in `fields`, at any depth,
+/// with `"<redacted>"`.
+fn redact_fields(value: &mut Value, fields: &BTreeSet<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if fields.contains(key) {
+                    *field = Value::String("<redacted>".to_string());
+                } else {
+                    redact_fields(field, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_fields(item, fields)),
+        _ => {}
+    }
+}
+
+/// Reject a formation request for more than `MAX_UNIT_COUNT` units, which
+/// would mean an unbounded prompt and output.
+#[allow(clippy::result_large_err)]
+fn check_unit_count(unit_count: u32) -> Result<(), Status> {
+    if unit_count > MAX_UNIT_COUNT {
+        return Err(Status::invalid_argument(format!(
+            "unit_count ({unit_count}) is greater than the maximum of {MAX_UNIT_COUNT}"
+        )));
+    }
+    Ok(())
+}
+
+/// A `normalize_box` that normalizing can't fit a formation into: a width
+/// or height of 0 collapses every unit onto the center, and a negative one
+/// mirrors the formation.
+#[allow(clippy::result_large_err)]
+fn check_normalize_box(input: &FormationInput) -> Result<(), Status> {
+    let Some(target) = &input.normalize_box else {
+        return Ok(());
+    };
+    for (name, size) in [("width", target.width), ("height", target.height)] {
+        if !(size.is_finite() && size > 0.0) {
+            return Err(Status::invalid_argument(format!(
+                "normalize_box.{name} must be a positive number, found {size}"
+            )));
+        }
+    }
+    for (name, center) in [("center_x", target.center_x), ("center_y", target.center_y)] {
+        if !center.is_finite() {
+            return Err(Status::invalid_argument(format!(
+                "normalize_box.{name} must be a finite number, found {center}"
+            )));
         }
     }
+    Ok(())
+}
+
+/// A grid with no cells, or fewer cells than units: no output could pass,
+/// so the request fails before spending LLM calls on it.
+#[allow(clippy::result_large_err)]
+fn check_grid_size(input: &FormationGridInput) -> Result<(), Status> {
+    if input.grid_width == 0 || input.grid_height == 0 {
+        return Err(Status::invalid_argument(format!(
+            "grid_width and grid_height must be at least 1, found {} by {}",
+            input.grid_width, input.grid_height
+        )));
+    }
+    let cells = u64::from(input.grid_width) * u64::from(input.grid_height);
+    if u64::from(input.unit_count) > cells {
+        return Err(Status::invalid_argument(format!(
+            "unit_count ({}) is greater than the {cells} cells of a {} by {} grid",
+            input.unit_count, input.grid_width, input.grid_height
+        )));
+    }
+    Ok(())
+}
+
+/// Validation errors as they are returned in `RunResponse.errors`.
+fn field_errors(errors: &[ValidationError]) -> Vec<FieldError> {
+    errors
+        .iter()
+        .map(|err| FieldError {
+            path: err.path().unwrap_or_default().to_string(),
+            message: err.to_string(),
+            json_pointer: err.json_pointer().unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Path and byte length of the first string in `value` longer than `max`.
+fn oversized_string(value: &Value, path: &str, max: usize) -> Option<(String, usize)> {
+    match value {
+        Value::String(text) if text.len() > max => Some((path.to_string(), text.len())),
+        Value::Object(map) => map
+            .iter()
+            .find_map(|(key, field)| oversized_string(field, &format!("{path}.{key}"), max)),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .find_map(|(i, item)| oversized_string(item, &format!("{path}[{i}]"), max)),
+        _ => None,
+    }
+}
+
+/// When the client's deadline (`grpc-timeout`) runs out, if it sent one.
+/// tonic drops the request at that point anyway; passing it to the LLM
+/// client also stops retries that can't finish in time. The format is up to
+/// 8 digits and a unit: `H`, `M`, `S`, `m` (ms), `u` (µs) or `n` (ns).
+fn request_deadline<T>(request: &Request<T>) -> Option<Instant> {
+    let timeout = request.metadata().get(GRPC_TIMEOUT)?.to_str().ok()?;
+    let split = timeout.len().checked_sub(1)?;
+    let (value, unit) = timeout.split_at(split);
+    if value.len() > 8 {
+        return None;
+    }
+    let value: u64 = value.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(value * 3600),
+        "M" => Duration::from_secs(value * 60),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    };
+    Some(Instant::now() + timeout)
+}
+
+/// `(key, shape_id, value)` for every env var named `<prefix><shape_id>`
+/// with a registered shape ID. Other suffixes are skipped with a warning:
+/// the prefixes are generic enough that unrelated variables such as
+/// `MODEL_PATH` or `LLM_API_KEY_FILE` share them.
+fn shape_env_vars(registry: &ShapeRegistry, prefix: &str) -> Vec<(String, String, String)> {
+    let mut vars = Vec::new();
+    for (key, value) in std::env::vars() {
+        let Some(shape_id) = key.strip_prefix(prefix) else {
+            continue;
+        };
+        if registry.get(shape_id).is_none() {
+            tracing::warn!("ignoring {key}: {shape_id} is not a registered shape");
+            continue;
+        }
+        let shape_id = shape_id.to_string();
+        vars.push((key, shape_id, value));
+    }
+    vars
+}
+
+/// Per-shape models from `MODEL_<shape_id>` env vars, e.g.
+/// `MODEL_FeatureDesign=llama3.1`.
+fn shape_models(registry: &ShapeRegistry) -> BTreeMap<String, String> {
+    shape_env_vars(registry, "MODEL_")
+        .into_iter()
+        .map(|(_, shape_id, model)| (shape_id, model))
+        .collect()
+}
+
+/// Per-shape candidate counts from `BEST_OF_<shape_id>` env vars, e.g.
+/// `BEST_OF_FeatureDesign=3`, used by requests that don't set `best_of`. A
+/// count above `MAX_BEST_OF`, or above 1 for a shape without a scorer, is a
+/// startup error.
+fn shape_best_of(registry: &ShapeRegistry) -> Result<BTreeMap<String, u32>> {
+    let mut counts = BTreeMap::new();
+    for (key, shape_id, count) in shape_env_vars(registry, "BEST_OF_") {
+        let count: u32 = count.parse().map_err(|e| anyhow!("invalid {key}: {e}"))?;
+        if count > MAX_BEST_OF {
+            return Err(anyhow!("invalid {key}: at most {MAX_BEST_OF} candidates are allowed"));
+        }
+        if count > 1 && !SCORED_SHAPES.contains(&shape_id.as_str()) {
+            return Err(anyhow!("{key}: {shape_id} has no scorer to pick a candidate ({} do)", SCORED_SHAPES.join(", ")));
+        }
+        counts.insert(shape_id, count);
+    }
+    Ok(counts)
+}
+
+/// Quality gates from `MIN_SCORE_<shape_id>` env vars, e.g.
+/// `MIN_SCORE_Formation=20`: outputs the shape's scorer (see `scoring`)
+/// rates below the minimum fail validation and are retried. Only
+/// FeatureDesign, Formation and Requirements have a scorer; naming another
+/// registered shape is a startup error, as is a minimum that isn't finite.
+fn with_min_scores(mut llm: LlmClient, registry: &ShapeRegistry) -> Result<LlmClient> {
+    for (key, shape_id, value) in shape_env_vars(registry, "MIN_SCORE_") {
+        let min: f64 = value.parse().map_err(|e| anyhow!("invalid {key}: {e}"))?;
+        // NaN would reject every output, infinity every output or none
+        if !min.is_finite() {
+            return Err(anyhow!("invalid {key}: {value} is not a finite number"));
+        }
+        llm = match shape_id.as_str() {
+            "FeatureDesign" => llm.with_feature_design_hook(min_score(ComponentCount, min)),
+            "Formation" => llm.with_formation_hook(min_score(CoordinateSpread, min)),
+            "Requirements" => llm.with_requirements_hook(min_score(RequirementCount, min)),
+            _ => return Err(anyhow!("{key}: {shape_id} has no scorer ({} do)", SCORED_SHAPES.join(", "))),
+        };
+        println!("Rejecting {shape_id} outputs that score below {min}");
+    }
+    Ok(llm)
+}
+
+/// Output pipelines from `POSTPROCESS_<shape_id>` env vars, each a
+/// comma-separated list of transforms run in the order given, e.g.
+/// `POSTPROCESS_Formation=normalize,round`. Naming an unknown transform is a
+/// startup error.
+fn post_processors(registry: &ShapeRegistry) -> Result<PostProcessors> {
+    let mut post = PostProcessors::default();
+    for (key, shape_id, list) in shape_env_vars(registry, "POSTPROCESS_") {
+        let names: Vec<&str> = list.split(',').map(str::trim).filter(|name| !name.is_empty()).collect();
+        post.configure(&shape_id, &names).map_err(|e| anyhow!("invalid {key}: {e}"))?;
+    }
+    Ok(post)
+}
+
+/// Clients for shapes given their own LLM with `LLM_BASE_URL_<shape_id>`,
+/// `LLM_BACKEND_<shape_id>` or `LLM_API_KEY_<shape_id>`. Each starts as a copy
+/// of `default`, so retry, timeout and prompt settings are shared; the URL,
+/// backend and API key are replaced where set.
+fn shape_llms(registry: &ShapeRegistry, default: &LlmClient) -> Result<BTreeMap<String, LlmClient>> {
+    const PREFIXES: [&str; 3] = ["LLM_BASE_URL_", "LLM_BACKEND_", "LLM_API_KEY_"];
+
+    let mut llms = BTreeMap::new();
+    for prefix in PREFIXES {
+        for (_, shape_id, value) in shape_env_vars(registry, prefix) {
+            let llm: LlmClient = llms.remove(&shape_id).unwrap_or_else(|| default.clone());
+            let llm = match prefix {
+                "LLM_BASE_URL_" => {
+                    let urls: Vec<String> = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|url| !url.is_empty())
+                        .map(str::to_string)
+                        .collect();
+                    llm.with_endpoints(urls)
+                }
+                "LLM_BACKEND_" => llm.with_backend(value.parse::<BackendKind>()?),
+                _ => llm.with_api_key(value),
+            };
+            llms.insert(shape_id, llm);
+        }
+    }
+    Ok(llms)
+}
+
+/// `llm_headers` from the config as a `HeaderMap`. An invalid name or value
+/// is a startup error.
+fn header_map(headers: &BTreeMap<String, String>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name: HeaderName = name.parse().map_err(|e| anyhow!("invalid LLM header name {name:?}: {e}"))?;
+        let value: HeaderValue = value.parse().map_err(|e| anyhow!("invalid value for LLM header {name}: {e}"))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// Shape IDs from the setting `name`; each must be registered.
+fn shape_list(registry: &ShapeRegistry, name: &str, list: &[String]) -> Result<BTreeSet<String>> {
+    let mut shapes = BTreeSet::new();
+    for id in list {
+        if registry.get(id).is_none() {
+            return Err(anyhow!("{name} names unknown shape: {id}"));
+        }
+        shapes.insert(id.to_string());
+    }
+    Ok(shapes)
+}
+
+/// Span grouping everything logged while serving one request: the shape
+/// (`shape_id`, or the stages of a pipeline), the caller's `x-request-id` (or
+/// a generated one) and the peer address.
+fn request_span<T>(request: &Request<T>, shape_id: &str) -> tracing::Span {
+    let request_id = request
+        .metadata()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).to_string());
+    let peer = request.remote_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    tracing::info_span!("run", shape_id = %shape_id, request_id = %request_id, peer = %peer)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Configure from env
-    let addr: SocketAddr = "0.0.0.0:50051".parse().unwrap();
-    let llm_base_url = std::env::var("LLM_BASE_URL").unwrap_or_else(|_| {
+    // Spans per request (see `request_span`) carry shape_id, request_id and
+    // peer. To export them to OpenTelemetry, add `opentelemetry-otlp` and
+    // `tracing-opentelemetry` and build the subscriber as a registry instead:
+    //
+    //     let tracer = opentelemetry_otlp::new_pipeline()
+    //         .tracing()
+    //         .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+    //         .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    //     tracing_subscriber::registry()
+    //         .with(tracing_subscriber::EnvFilter::from_default_env())
+    //         .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+    //         .with(tracing_opentelemetry::layer().with_tracer(tracer))
+    //         .init();
+    //
+    // The exporter reads OTEL_EXPORTER_OTLP_ENDPOINT (default
+    // http://localhost:4317) and OTEL_SERVICE_NAME.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    // Configure from the config file, if any, and env
+    let args = Args::parse();
+    let config_path = args
+        .config
+        .or_else(|| std::env::var_os("SHAPE_RUNNER_CONFIG").map(PathBuf::from));
+    let config = Config::load_with_env(config_path.as_deref())?;
+    if let Some(path) = &config_path {
+        println!("Loaded config from {}", path.display());
+    }
+
+    let addr: SocketAddr = config.addr.unwrap_or_else(|| "0.0.0.0:50051".parse().unwrap());
+    let llm_base_url = config.llm_base_url.unwrap_or_else(|| {
         // Default to Ollama if available, otherwise fall back to mock server
         "http://localhost:11434/api/generate".to_string()
     });
-    let ollama_model = std::env::var("OLLAMA_MODEL").ok();
+    let ollama_model = config.model;
+    let stream = config.stream.unwrap_or(false);
+    let jitter = config.backoff_jitter.unwrap_or_default();
 
-    println!("ShapeRunner listening on {addr}");
-    println!("Using LLM endpoint: {}", llm_base_url);
+    let mut registry = ShapeRegistry::builtin();
+    if let Some(manifest) = &config.shape_manifest {
+        let loaded = registry.load_manifest(manifest)?;
+        println!("Loaded shapes from {}: {}", manifest.display(), loaded.join(", "));
+    }
+    let enabled = match &config.enabled_shapes {
+        Some(list) => shape_list(&registry, "ENABLED_SHAPES", list)?,
+        None => registry.ids().map(str::to_string).collect(),
+    };
+    let degraded = shape_list(&registry, "DEGRADED_SHAPES", config.degraded_shapes.as_deref().unwrap_or_default())?;
+    let models = shape_models(&registry);
+    let best_of = shape_best_of(&registry)?;
+    let post = post_processors(&registry)?;
+    let redact: BTreeSet<String> = config.log_redact_fields.iter().flatten().cloned().collect();
+
+    // Bound here rather than by `serve` so port 0 works: the line below
+    // reports the port the OS picked
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("ShapeRunner listening on {}", listener.local_addr()?);
+    // Several comma-separated URLs are load-balanced round-robin
+    let endpoints: Vec<String> = llm_base_url
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    println!("Using LLM endpoint: {}", endpoints.join(", "));
     if let Some(ref model) = ollama_model {
         println!("Using Ollama model: {}", model);
     }
+    let codec = config.codec.unwrap_or_default();
+    println!("Using codec: {:?}", codec);
+    for (shape_id, model) in &models {
+        println!("Using model {} for {}", model, shape_id);
+    }
+    println!("Enabled shapes: {}", enabled.iter().cloned().collect::<Vec<_>>().join(", "));
+    if !degraded.is_empty() {
+        println!("Degraded output allowed for: {}", degraded.iter().cloned().collect::<Vec<_>>().join(", "));
+    }
+    for (shape_id, names) in [
+        ("FeatureDesign", post.feature_design.names()),
+        ("Formation", post.formation.names()),
+        ("FormationGrid", post.formation_grid.names()),
+    ] {
+        if !names.is_empty() {
+            println!("Post-processing {}: {}", shape_id, names.join(" -> "));
+        }
+    }
+
+    let primary = endpoints.first().cloned().unwrap_or_default();
+    let mut llm = LlmClient::new_with_model(primary, ollama_model)
+        .with_endpoints(endpoints)
+        .with_backoff_jitter(jitter)
+        .with_streaming(stream);
+    if let Some(backend) = config.backend {
+        llm = llm.with_backend(backend);
+    }
+    if let Some(api_key) = config.api_key {
+        llm = llm.with_api_key(api_key);
+    }
+    if let Some(headers) = &config.llm_headers {
+        llm = llm.with_headers(header_map(headers)?);
+        let names: Vec<&str> = headers.keys().map(String::as_str).collect();
+        println!("Sending LLM headers: {}", names.join(", "));
+    }
+    if let Some(attempts) = config.max_attempts {
+        llm = llm.with_max_attempts(attempts);
+    }
+    if let Some(seed) = config.seed {
+        llm = llm.with_seed(seed);
+    }
+    if let Some(temperature) = config.temperature {
+        llm = llm.with_temperature(temperature);
+    }
+    if let Some(stop) = config.stop {
+        println!("Stop sequences: {stop:?}");
+        llm = llm.with_stop_sequences(stop);
+    }
+    if config.connection_close.unwrap_or(false) {
+        llm = llm.with_connection_close(true);
+    }
+    if let Some(keep_alive) = &config.keep_alive {
+        llm = llm.with_ollama_keep_alive(keep_alive);
+    }
+    let connect_timeout = Duration::from_secs(config.connect_timeout_secs.unwrap_or(10));
+    let request_timeout = Duration::from_secs(config.request_timeout_secs.unwrap_or(120));
+    llm = llm.with_http_timeouts(connect_timeout, request_timeout);
+    // Off unless configured: a threshold of 0 never opens the circuit
+    let breaker_threshold = config.circuit_threshold.unwrap_or(0);
+    let breaker_cooldown = Duration::from_secs(config.circuit_cooldown_secs.unwrap_or(30));
+    if breaker_threshold > 0 {
+        println!(
+            "Circuit breaker: open after {} transport failures, for {}s",
+            breaker_threshold,
+            breaker_cooldown.as_secs()
+        );
+    }
+    llm = llm.with_circuit_breaker(breaker_threshold, breaker_cooldown);
+    if let Some(feedback) = config.feedback_verbosity {
+        llm = llm.with_feedback_verbosity(feedback);
+    }
+    if let Some(format) = config.schema_format {
+        llm = llm.with_schema_format(format);
+    }
+    if let Some(min) = config.feature_design_min_risks {
+        llm = llm.with_min_risks(min);
+    }
+    if let Some(depth) = config.feedback_history {
+        llm = llm.with_feedback_history(depth);
+    }
+    if config.coerce_bools.unwrap_or(false) {
+        llm = llm.with_bool_coercion(true);
+    }
+    if config.local_repair.unwrap_or(false) {
+        llm = llm.with_local_repair(true);
+    }
+    if let Some(path) = &config.prompt_log {
+        llm = llm.with_prompt_log(path)?;
+    }
+    if let Some(tokens) = config.prompt_token_budget {
+        llm = llm.with_prompt_token_budget(tokens);
+    }
+    if let Some(ms) = config.total_deadline_ms {
+        llm = llm.with_total_deadline(Duration::from_millis(ms));
+    }
+    if config.cache_dir.is_some() || config.cache.unwrap_or(false) {
+        let ttl = Duration::from_secs(config.cache_ttl_secs.unwrap_or(24 * 60 * 60));
+        let max_bytes = config.cache_max_bytes.unwrap_or(64 * 1024 * 1024);
+        let mut cache = PromptCache::new(ttl, max_bytes as u64);
+        match &config.cache_dir {
+            Some(dir) => {
+                cache = cache.with_disk(dir)?;
+                println!("Caching LLM responses in memory and in {} for {}s", dir.display(), ttl.as_secs());
+            }
+            None => println!("Caching LLM responses in memory for {}s", ttl.as_secs()),
+        }
+        llm = llm.with_prompt_cache(Arc::new(cache));
+    }
+
+    let metrics = Arc::new(Metrics::new());
+    llm = llm.with_metrics(metrics.clone());
+
+    // Capture requests for later replay, or answer from a capture
+    let recorder = match &config.record_file {
+        Some(path) => {
+            println!("Recording requests to {}", path.display());
+            Some(Arc::new(Recorder::open(path)?))
+        }
+        None => None,
+    };
+    let replay = match &config.replay_file {
+        Some(path) => {
+            let corpus = ReplayCorpus::load(path)?;
+            println!(
+                "Replaying {} recorded inputs from {} instead of calling the LLM",
+                corpus.len(),
+                path.display()
+            );
+            Some(Arc::new(corpus))
+        }
+        None => None,
+    };
+    if (recorder.is_some() || replay.is_some()) && !best_of.is_empty() {
+        println!("Recording or replaying: ignoring BEST_OF_* and best_of, every request runs one candidate");
+    }
+
+    llm = with_min_scores(llm, &registry)?;
+
+    let shape_llms = shape_llms(&registry, &llm)?;
+    for (shape_id, shape_llm) in &shape_llms {
+        println!("Using LLM endpoint {} for {}", shape_llm.endpoint_urls().join(", "), shape_id);
+    }
 
     let service = ShapeRunnerService {
-        codec: MsgPackCodec,
-        llm: LlmClient::new_with_model(llm_base_url, ollama_model),
+        codec,
+        llm,
+        shape_llms: Arc::new(shape_llms),
+        registry: Arc::new(registry),
+        enabled: Arc::new(enabled),
+        degraded: Arc::new(degraded),
+        redact: Arc::new(redact),
+        models: Arc::new(models),
+        best_of: Arc::new(best_of),
+        post: Arc::new(post),
+        recorder,
+        replay,
+        metrics,
+        idempotency: Arc::new(IdempotencyCache::new(
+            Duration::from_secs(config.idempotency_ttl_secs.unwrap_or(600)),
+            config.idempotency_max_entries.unwrap_or(10_000),
+        )),
+        limits: InputLimits {
+            max_input_bytes: config.max_input_bytes.unwrap_or(1024 * 1024),
+            max_string_bytes: config.max_input_string_bytes.unwrap_or(64 * 1024),
+            max_batch_items: config.max_batch_items.unwrap_or(100),
+            max_batch_concurrency: config.max_batch_concurrency.unwrap_or(16).max(1),
+        },
+    };
+
+    // Browsers speak gRPC-Web over HTTP/1.1; native clients are unaffected
+    let cors = cors_layer(config.grpc_web_allowed_origins.as_deref().unwrap_or_default())?;
+
+    // Per-client-IP request limit, off unless configured
+    let limiter = config.rate_limit_per_minute.map(|rpm| {
+        println!("Rate limit: {} requests per minute per client IP", rpm);
+        Arc::new(RateLimiter::new(rpm))
+    });
+    #[allow(clippy::result_large_err)]
+    let rate_limit = move |request: Request<()>| -> Result<Request<()>, Status> {
+        if let (Some(limiter), Some(peer)) = (&limiter, request.remote_addr()) {
+            if !limiter.check(peer.ip()) {
+                return Err(Status::resource_exhausted(format!("rate limit exceeded for {}", peer.ip())));
+            }
+        }
+        Ok(request)
     };
 
     Server::builder()
-        .add_service(ShapeRunnerServer::new(service))
-        .serve(addr)
+        .accept_http1(true)
+        .layer(cors)
+        .layer(GrpcWebLayer::new())
+        .add_service(ShapeRunnerServer::with_interceptor(service, rate_limit))
+        .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow!("{e}"))?)
         .await?;
 
     Ok(())
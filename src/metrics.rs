@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Upper bounds (seconds) of the latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+/// Lock-free latency histogram with fixed buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // One counter per bound plus a final overflow (+Inf) bucket
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    /// `(upper_bound_seconds, count)` per bucket, non-cumulative. The last
+    /// bucket has an infinite upper bound.
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum_seconds: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let idx = self
+            .bounds
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let counts: Vec<u64> = self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        let buckets = self
+            .bounds
+            .iter()
+            .copied()
+            .chain(std::iter::once(f64::INFINITY))
+            .zip(counts.iter().copied())
+            .collect();
+
+        HistogramSnapshot {
+            buckets,
+            count: counts.iter().sum(),
+            sum_seconds: self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+// Attempt counts tracked individually; more attempts share the last bucket
+const MAX_TRACKED_ATTEMPTS: usize = 10;
+
+/// How many generations took each number of LLM attempts.
+#[derive(Debug)]
+pub struct AttemptCounts {
+    // Index i counts generations with i + 1 attempts; the last is "or more"
+    counts: Vec<AtomicU64>,
+}
+
+impl AttemptCounts {
+    fn new() -> Self {
+        Self {
+            counts: (0..MAX_TRACKED_ATTEMPTS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn observe(&self, attempts: u32) {
+        let idx = (attempts.max(1) as usize - 1).min(MAX_TRACKED_ATTEMPTS - 1);
+        self.counts[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `(attempts, generations)` for every attempt count seen. The last
+    /// tracked count also includes generations that took more attempts.
+    pub fn snapshot(&self) -> Vec<(u32, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (i as u32 + 1, count.load(Ordering::Relaxed)))
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
+}
+
+/// In-process metrics shared by the LLM client and the server.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Duration of each LLM backend call.
+    pub llm_call_seconds: Histogram,
+    /// Duration of each validation pass (schema plus shape-specific checks).
+    pub validation_seconds: Histogram,
+    /// LLM attempts each generation took, successful or not.
+    pub attempts: AttemptCounts,
+    /// Duration of each request the server handled, from decoding the input
+    /// to encoding the output.
+    pub request_seconds: Histogram,
+    /// Requests handled, by shape ID.
+    requests_by_shape: Mutex<BTreeMap<String, u64>>,
+    /// Requests that ended in an error.
+    requests_failed: AtomicU64,
+}
+
+/// Point-in-time copy of `Metrics`, e.g. for the `Metrics` RPC.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub requests_total: u64,
+    pub requests_failed: u64,
+    pub requests_by_shape: BTreeMap<String, u64>,
+    /// `(attempts, generations)`; see `AttemptCounts::snapshot`.
+    pub attempts: Vec<(u32, u64)>,
+    pub request_seconds: HistogramSnapshot,
+    pub llm_call_seconds: HistogramSnapshot,
+    pub validation_seconds: HistogramSnapshot,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            llm_call_seconds: Histogram::new(LATENCY_BUCKETS),
+            validation_seconds: Histogram::new(LATENCY_BUCKETS),
+            attempts: AttemptCounts::new(),
+            request_seconds: Histogram::new(LATENCY_BUCKETS),
+            requests_by_shape: Mutex::new(BTreeMap::new()),
+            requests_failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a finished request for `shape_id`.
+    pub fn observe_request(&self, shape_id: &str, elapsed: Duration, ok: bool) {
+        self.request_seconds.observe(elapsed);
+        if !ok {
+            self.requests_failed.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut by_shape = self.requests_by_shape.lock().unwrap_or_else(|e| e.into_inner());
+        *by_shape.entry(shape_id.to_string()).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let requests_by_shape = self
+            .requests_by_shape
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        MetricsSnapshot {
+            requests_total: requests_by_shape.values().sum(),
+            requests_failed: self.requests_failed.load(Ordering::Relaxed),
+            requests_by_shape,
+            attempts: self.attempts.snapshot(),
+            request_seconds: self.request_seconds.snapshot(),
+            llm_call_seconds: self.llm_call_seconds.snapshot(),
+            validation_seconds: self.validation_seconds.snapshot(),
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    /// Mean observation in seconds, or 0 with no observations.
+    pub fn mean_seconds(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum_seconds / self.count as f64
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+
+use crate::shape::{FeatureDesignOutput, FormationGridOutput, FormationInput, FormationOutput, NormalizeBox};
+
+/// An output transform, by the name operators list it under in
+/// `POSTPROCESS_<shape_id>`.
+pub struct Transform<O> {
+    pub name: &'static str,
+    pub apply: Arc<dyn Fn(O) -> O + Send + Sync>,
+}
+
+impl<O> Transform<O> {
+    pub fn new(name: &'static str, apply: impl Fn(O) -> O + Send + Sync + 'static) -> Self {
+        Self {
+            name,
+            apply: Arc::new(apply),
+        }
+    }
+}
+
+impl<O> Clone for Transform<O> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            apply: self.apply.clone(),
+        }
+    }
+}
+
+/// Ordered output transforms for one shape, run on a validated output before
+/// it is encoded. Each transform gets the previous one's result, so order
+/// matters: `normalize,round` rounds the normalized coordinates, while
+/// `round,normalize` rounds first and normalizing then brings back long
+/// fractions. Degraded output (see `allow_degraded`) is not post-processed.
+pub struct Pipeline<O> {
+    steps: Vec<Transform<O>>,
+}
+
+impl<O> Default for Pipeline<O> {
+    fn default() -> Self {
+        Self { steps: Vec::new() }
+    }
+}
+
+impl<O> Pipeline<O> {
+    /// Append `transform`, to run after those already added.
+    pub fn then(mut self, transform: Transform<O>) -> Self {
+        self.steps.push(transform);
+        self
+    }
+
+    /// The transforms in `names`, in that order, looked up in `available`.
+    /// An unknown name is an error listing the available ones.
+    pub fn from_names(available: &[Transform<O>], names: &[&str]) -> Result<Self> {
+        let mut pipeline = Self::default();
+        for name in names {
+            let transform = available.iter().find(|t| t.name == *name).ok_or_else(|| {
+                let known: Vec<&str> = available.iter().map(|t| t.name).collect();
+                anyhow!("unknown transform {name} (available: {})", known.join(", "))
+            })?;
+            pipeline = pipeline.then(transform.clone());
+        }
+        Ok(pipeline)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Transform names in the order they run.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.steps.iter().map(|t| t.name).collect()
+    }
+
+    pub fn apply(&self, output: O) -> O {
+        self.steps.iter().fold(output, |output, t| (t.apply)(output))
+    }
+}
+
+/// Transforms for FeatureDesign:
+///
+/// - `sort_components`: components ordered by id;
+/// - `dedupe_risks`: repeated risks dropped, keeping the first.
+pub fn feature_design_transforms() -> Vec<Transform<FeatureDesignOutput>> {
+    vec![
+        Transform::new("sort_components", |mut output: FeatureDesignOutput| {
+            output.components.sort_by(|a, b| a.id.cmp(&b.id));
+            output
+        }),
+        Transform::new("dedupe_risks", |mut output: FeatureDesignOutput| {
+            let mut seen = std::collections::HashSet::new();
+            output.risks.retain(|risk| seen.insert(risk.clone()));
+            output
+        }),
+    ]
+}
+
+/// Transforms for Formation:
+///
+/// - `normalize`: fit into the default `NormalizeBox`, see
+///   `FormationOutput::normalize`;
+/// - `round`: coordinates rounded to two decimals;
+/// - `sort`: coordinates ordered by y, then x.
+pub fn formation_transforms() -> Vec<Transform<FormationOutput>> {
+    vec![
+        normalize_into(NormalizeBox::default()),
+        Transform::new("round", |mut output: FormationOutput| {
+            output.round();
+            output
+        }),
+        Transform::new("sort", |mut output: FormationOutput| {
+            output.sort_stable();
+            output
+        }),
+    ]
+}
+
+/// `normalize`, fitting into `frame` instead of the default box.
+fn normalize_into(frame: NormalizeBox) -> Transform<FormationOutput> {
+    Transform::new("normalize", move |mut output: FormationOutput| {
+        output.normalize(&frame);
+        output
+    })
+}
+
+/// The transforms a Formation request asks for itself: `normalize` into its
+/// `normalize_box` when `normalize` is set, then `sort` when `stable_order`
+/// is. `LlmClient::generate_formation` runs them on every output, model or
+/// canonical; configured `POSTPROCESS_Formation` transforms run after.
+pub fn formation_input_pipeline(input: &FormationInput) -> Pipeline<FormationOutput> {
+    let mut pipeline = Pipeline::default();
+    if input.normalize {
+        pipeline = pipeline.then(normalize_into(input.normalize_box.clone().unwrap_or_default()));
+    }
+    if input.stable_order {
+        let sort = formation_transforms().into_iter().find(|t| t.name == "sort");
+        pipeline = pipeline.then(sort.expect("formation_transforms has sort"));
+    }
+    pipeline
+}
+
+/// Transforms for FormationGrid:
+///
+/// - `sort`: cells ordered by row, then column.
+pub fn formation_grid_transforms() -> Vec<Transform<FormationGridOutput>> {
+    vec![Transform::new("sort", |mut output: FormationGridOutput| {
+        output.coordinates.sort_by_key(|cell| (cell.row, cell.col));
+        output
+    })]
+}
+
+/// Post-processing pipelines for the built-in shapes. Data-driven shapes have
+/// no transforms.
+#[derive(Default)]
+pub struct PostProcessors {
+    pub feature_design: Pipeline<FeatureDesignOutput>,
+    pub formation: Pipeline<FormationOutput>,
+    pub formation_grid: Pipeline<FormationGridOutput>,
+}
+
+impl PostProcessors {
+    /// Set `shape_id`'s pipeline to the transforms in `names`, in order.
+    pub fn configure(&mut self, shape_id: &str, names: &[&str]) -> Result<()> {
+        match shape_id {
+            "FeatureDesign" => self.feature_design = Pipeline::from_names(&feature_design_transforms(), names)?,
+            "Formation" => self.formation = Pipeline::from_names(&formation_transforms(), names)?,
+            "FormationGrid" => self.formation_grid = Pipeline::from_names(&formation_grid_transforms(), names)?,
+            other => return Err(anyhow!("shape {other} has no output transforms")),
+        }
+        Ok(())
+    }
+}
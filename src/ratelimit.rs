@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How often idle buckets are dropped from the map
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token-bucket rate limit per client IP.
+///
+/// Each IP may burst up to `per_minute` requests and then gets one more every
+/// `60 / per_minute` seconds. Buckets that have refilled completely are
+/// dropped at most once per `CLEANUP_INTERVAL`, so the map only holds
+/// recently active clients.
+pub struct RateLimiter {
+    per_minute: u32,
+    state: Mutex<State>,
+}
+
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_cleanup: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute: per_minute.max(1),
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_cleanup: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token for `ip`. False when its bucket is empty.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    /// `check` with the clock at `now`, which must not go backwards between
+    /// calls.
+    pub fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let capacity = f64::from(self.per_minute);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if now.duration_since(state.last_cleanup) >= CLEANUP_INTERVAL {
+            state
+                .buckets
+                .retain(|_, bucket| bucket.refilled(now, capacity) < capacity);
+            state.last_cleanup = now;
+        }
+
+        let bucket = state.buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Number of IPs with a bucket, i.e. not yet dropped by cleanup.
+    pub fn tracked_ips(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).buckets.len()
+    }
+}
+
+impl Bucket {
+    /// Tokens in the bucket at `now`, capped at `capacity`.
+    fn refilled(&self, now: Instant, capacity: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * capacity / 60.0).min(capacity)
+    }
+}
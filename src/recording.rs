@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::backend::MockLlmBackend;
+use crate::cache::stable_hash;
+use crate::codec::canonicalize;
+
+/// One request as recorded by `Recorder`: one line of the JSONL file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub shape_id: String,
+    /// `input_hash(shape_id, input)`, the key `ReplayCorpus` looks up.
+    pub input_hash: String,
+    pub input: Value,
+    /// Every LLM call the request made, in order.
+    pub attempts: Vec<RecordedAttempt>,
+    /// The validated output, when the request succeeded.
+    #[serde(default)]
+    pub output: Option<Value>,
+    /// Why the request failed, when it did.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedAttempt {
+    pub prompt: String,
+    /// The model's text as it was parsed: before repair or validation, with
+    /// markdown code fences and any text around the JSON object stripped.
+    pub raw_response: String,
+}
+
+/// Collects the LLM calls of one generation (see `LlmClient::with_transcript`).
+#[derive(Debug, Default)]
+pub struct Transcript {
+    attempts: Mutex<Vec<RecordedAttempt>>,
+}
+
+impl Transcript {
+    pub fn push(&self, prompt: &str, raw_response: &str) {
+        self.attempts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(RecordedAttempt {
+                prompt: prompt.to_string(),
+                raw_response: raw_response.to_string(),
+            });
+    }
+
+    /// The calls so far, leaving the transcript empty.
+    pub fn take(&self) -> Vec<RecordedAttempt> {
+        std::mem::take(&mut *self.attempts.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Stable key for a request: FNV-1a over the shape ID and the input as
+/// canonical JSON (object keys sorted), as 16 hex digits. Equal inputs hash
+/// equal whatever their key order or codec, across runs and builds.
+pub fn input_hash(shape_id: &str, input: &Value) -> String {
+    let canonical = canonicalize(input).unwrap_or_else(|_| input.clone()).to_string();
+    stable_hash(&[shape_id.as_bytes(), canonical.as_bytes()])
+}
+
+/// Appends `Recording`s to a JSONL file, one line per request.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Open `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open recording file {}", path.display()))?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    /// Append one line. Failures are logged, not returned, so recording never
+    /// fails a request.
+    pub fn record(&self, recording: &Recording) {
+        let line = match serde_json::to_string(recording) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to serialize recording");
+                return;
+            }
+        };
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(file, "{line}") {
+            tracing::warn!(error = %e, "failed to write recording");
+        }
+    }
+}
+
+/// Raw model responses from a `Recorder` file, by input hash, for replaying
+/// requests without a live model.
+#[derive(Debug, Default)]
+pub struct ReplayCorpus {
+    responses: HashMap<String, Vec<String>>,
+}
+
+impl ReplayCorpus {
+    /// Load a JSONL file written by `Recorder`. When an input was recorded
+    /// more than once, the last recording wins.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("failed to open replay file {}", path.display()))?;
+        let mut corpus = Self::default();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("failed to read replay file {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let recording: Recording = serde_json::from_str(&line)
+                .map_err(|e| anyhow!("invalid recording on line {} of {}: {e}", number + 1, path.display()))?;
+            let responses = recording.attempts.into_iter().map(|a| a.raw_response).collect();
+            corpus.responses.insert(recording.input_hash, responses);
+        }
+        Ok(corpus)
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    /// A backend that answers with the raw responses recorded for
+    /// `input_hash`, in their original order. None when nothing was recorded
+    /// for it.
+    pub fn backend(&self, input_hash: &str) -> Option<MockLlmBackend> {
+        self.responses.get(input_hash).map(|responses| MockLlmBackend::new(responses.clone()))
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::shape::{
+    api_design_input_typedef, api_design_output_typedef, feature_design_input_typedef, feature_design_output_typedef, formation_grid_input_typedef,
+    formation_input_typedef, formation_output_typedef, grid_output_typedef, requirements_input_typedef,
+    requirements_output_typedef, risk_assessment_input_typedef, risk_assessment_output_typedef,
+};
+use crate::types::{validate, TypeDef, ValidationError};
+
+/// Everything the crate knows about a shape, looked up by shape ID.
+#[derive(Debug, Clone)]
+pub struct ShapeSpec {
+    pub id: String,
+    /// Schema for request inputs. Built-in shapes set it for schema export
+    /// only; their requests are decoded into typed structs instead.
+    pub input_typedef: Option<TypeDef>,
+    pub output_typedef: TypeDef,
+    /// Prompt template for data-driven shapes (see `render_template`). Built-in
+    /// shapes have dedicated prompt builders and leave this `None`.
+    pub prompt_template: Option<String>,
+}
+
+/// Manifest file listing data-driven shapes.
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    shapes: Vec<ManifestEntry>,
+}
+
+/// One manifest shape. Paths are relative to the manifest file.
+#[derive(Debug, Deserialize)]
+struct ManifestEntry {
+    shape_id: String,
+    input_schema: String,
+    output_schema: String,
+    prompt_template: String,
+}
+
+/// Shapes keyed by shape ID.
+#[derive(Debug, Clone, Default)]
+pub struct ShapeRegistry {
+    shapes: BTreeMap<String, ShapeSpec>,
+}
+
+impl ShapeRegistry {
+    /// Registry with the shapes compiled into this crate.
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register(ShapeSpec {
+            id: "FeatureDesign".to_string(),
+            input_typedef: Some(feature_design_input_typedef()),
+            output_typedef: feature_design_output_typedef(),
+            prompt_template: None,
+        });
+        registry.register(ShapeSpec {
+            id: "Formation".to_string(),
+            input_typedef: Some(formation_input_typedef()),
+            output_typedef: formation_output_typedef(),
+            prompt_template: None,
+        });
+        registry.register(ShapeSpec {
+            id: "FormationGrid".to_string(),
+            input_typedef: Some(formation_grid_input_typedef()),
+            output_typedef: grid_output_typedef(),
+            prompt_template: None,
+        });
+        registry.register(ShapeSpec {
+            id: "Requirements".to_string(),
+            input_typedef: Some(requirements_input_typedef()),
+            output_typedef: requirements_output_typedef(),
+            prompt_template: None,
+        });
+        registry.register(ShapeSpec {
+            id: "ApiDesign".to_string(),
+            input_typedef: Some(api_design_input_typedef()),
+            output_typedef: api_design_output_typedef(),
+            prompt_template: None,
+        });
+        registry.register(ShapeSpec {
+            id: "RiskAssessment".to_string(),
+            input_typedef: Some(risk_assessment_input_typedef()),
+            output_typedef: risk_assessment_output_typedef(),
+            prompt_template: None,
+        });
+        registry
+    }
+
+    /// Register every shape listed in a manifest file (`.toml`, otherwise JSON).
+    ///
+    /// Each entry names JSON Schema files for the input and output (converted
+    /// with `TypeDef::from_json_schema`) and a prompt template file. Returns the
+    /// IDs of the shapes that were loaded. An ID that is already registered,
+    /// such as a built-in shape's, is an error: built-in IDs keep running their
+    /// typed handlers, so a manifest copy would only change the schemas
+    /// clients see.
+    pub fn load_manifest(&mut self, path: &Path) -> Result<Vec<String>> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read shape manifest {}", path.display()))?;
+        let manifest: Manifest = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&text)?
+        } else {
+            serde_json::from_str(&text)?
+        };
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut loaded = Vec::new();
+        for entry in manifest.shapes {
+            if self.shapes.contains_key(&entry.shape_id) {
+                return Err(anyhow!(
+                    "shape manifest {} defines {}, which is already registered",
+                    path.display(),
+                    entry.shape_id
+                ));
+            }
+            let spec = ShapeSpec {
+                input_typedef: Some(load_schema(&base.join(&entry.input_schema))?),
+                output_typedef: load_schema(&base.join(&entry.output_schema))?,
+                prompt_template: Some(
+                    std::fs::read_to_string(base.join(&entry.prompt_template)).with_context(|| {
+                        format!("failed to read prompt template for {}", entry.shape_id)
+                    })?,
+                ),
+                id: entry.shape_id,
+            };
+            loaded.push(spec.id.clone());
+            self.register(spec);
+        }
+        Ok(loaded)
+    }
+
+    /// Add a shape, replacing any existing shape with the same ID.
+    pub fn register(&mut self, spec: ShapeSpec) {
+        self.shapes.insert(spec.id.clone(), spec);
+    }
+
+    pub fn get(&self, shape_id: &str) -> Option<&ShapeSpec> {
+        self.shapes.get(shape_id)
+    }
+
+    /// The input and output typedefs of `shape_id`, in that order, or None
+    /// for an unknown shape. The input half is None for a shape registered
+    /// without an input typedef, whose input is not checked.
+    pub fn schemas_for(&self, shape_id: &str) -> Option<(Option<TypeDef>, TypeDef)> {
+        let spec = self.get(shape_id)?;
+        Some((spec.input_typedef.clone(), spec.output_typedef.clone()))
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.shapes.keys().map(String::as_str)
+    }
+
+    /// Validate `value` against the output typedef of `shape_id`.
+    pub fn validate_output(&self, shape_id: &str, value: &Value) -> Result<(), Vec<ValidationError>> {
+        let Some(spec) = self.get(shape_id) else {
+            return Err(vec![ValidationError::UnknownShape {
+                shape_id: shape_id.to_string(),
+            }]);
+        };
+        validate(&spec.output_typedef, value)
+    }
+}
+
+fn load_schema(path: &Path) -> Result<TypeDef> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read schema {}", path.display()))?;
+    let schema: Value = serde_json::from_str(&text)
+        .with_context(|| format!("schema {} is not valid JSON", path.display()))?;
+    TypeDef::from_json_schema(&schema).map_err(|e| anyhow!("schema {}: {e}", path.display()))
+}
+
+/// Validate `value` against a built-in shape's output typedef by shape ID.
+///
+/// An unknown shape ID yields a single `ValidationError::UnknownShape`.
+pub fn validate_shape_output(shape_id: &str, value: &Value) -> Result<(), Vec<ValidationError>> {
+    ShapeRegistry::builtin().validate_output(shape_id, value)
+}
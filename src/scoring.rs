@@ -0,0 +1,106 @@
+use crate::shape::{
+    FeatureDesignInput, FeatureDesignOutput, FormationInput, FormationOutput, RequirementsInput, RequirementsOutput,
+};
+use crate::types::ValidationError;
+
+/// Rates a validated output of one shape; higher is better. Scorers rank
+/// best-of candidates (see `llm::best_of`) and, through `min_score`, reject
+/// outputs below a quality threshold. Any `Fn(&O, &I) -> f64` is a scorer.
+pub trait OutputScorer<O, I>: Send + Sync {
+    fn score(&self, output: &O, input: &I) -> f64;
+
+    /// What the score measures, e.g. `component count`, for the feedback
+    /// `min_score` gives the model.
+    fn name(&self) -> &str {
+        "score"
+    }
+}
+
+impl<O, I, F> OutputScorer<O, I> for F
+where
+    F: Fn(&O, &I) -> f64 + Send + Sync,
+{
+    fn score(&self, output: &O, input: &I) -> f64 {
+        self(output, input)
+    }
+}
+
+/// FeatureDesign: the number of components. Candidates have already passed
+/// the count, risk and dependency checks, so more components means a more
+/// detailed design that is still consistent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ComponentCount;
+
+impl OutputScorer<FeatureDesignOutput, FeatureDesignInput> for ComponentCount {
+    fn score(&self, output: &FeatureDesignOutput, _input: &FeatureDesignInput) -> f64 {
+        output.components.len() as f64
+    }
+
+    fn name(&self) -> &str {
+        "component count"
+    }
+}
+
+/// Formation: how far the units spread out, as the root-mean-square distance
+/// of the coordinates from their centroid. Models sometimes bunch units
+/// together; this prefers the formation that uses the space. 0 for fewer
+/// than two units.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CoordinateSpread;
+
+impl OutputScorer<FormationOutput, FormationInput> for CoordinateSpread {
+    fn score(&self, output: &FormationOutput, _input: &FormationInput) -> f64 {
+        let coordinates = &output.coordinates;
+        if coordinates.len() < 2 {
+            return 0.0;
+        }
+        let n = coordinates.len() as f64;
+        let cx = coordinates.iter().map(|c| c.x).sum::<f64>() / n;
+        let cy = coordinates.iter().map(|c| c.y).sum::<f64>() / n;
+        let mean_square = coordinates
+            .iter()
+            .map(|c| (c.x - cx).powi(2) + (c.y - cy).powi(2))
+            .sum::<f64>()
+            / n;
+        mean_square.sqrt()
+    }
+
+    fn name(&self) -> &str {
+        "coordinate spread"
+    }
+}
+
+/// Requirements: the number of requirements found.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequirementCount;
+
+impl OutputScorer<RequirementsOutput, RequirementsInput> for RequirementCount {
+    fn score(&self, output: &RequirementsOutput, _input: &RequirementsInput) -> f64 {
+        output.requirements.len() as f64
+    }
+
+    fn name(&self) -> &str {
+        "requirement count"
+    }
+}
+
+/// A validation hook (see `LlmClient::with_feature_design_hook` and the like)
+/// that rejects outputs `scorer` rates below `min`. The rejection is fed back
+/// into the retry loop like any other validation error.
+pub fn min_score<O, I>(
+    scorer: impl OutputScorer<O, I> + 'static,
+    min: f64,
+) -> impl Fn(&O, &I) -> Vec<ValidationError> + Send + Sync + 'static {
+    move |output, input| {
+        let score = scorer.score(output, input);
+        if score >= min {
+            return Vec::new();
+        }
+        let name = scorer.name();
+        eprintln!("[DEMO] ✗ Output {name} is {score}, below the minimum of {min}");
+        vec![ValidationError::Constraint {
+            path: "$".to_string(),
+            message: format!("expected {name} of at least {min}, found {name} {score}"),
+        }]
+    }
+}
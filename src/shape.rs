@@ -1,3 +1,8 @@
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::types::{FieldDef, TypeDef};
@@ -6,6 +11,117 @@ use crate::types::{FieldDef, TypeDef};
 pub struct FeatureDesignInput {
     pub repo_summary: String,
     pub constraints: Vec<String>,
+    /// Fewest components the design may have.
+    #[serde(default)]
+    pub min_components: Option<u32>,
+    /// Most components the design may have.
+    #[serde(default)]
+    pub max_components: Option<u32>,
+    /// Fewest risks the design must list, overriding the server's minimum
+    /// (see `LlmClient::with_min_risks`). 0 turns the check off.
+    #[serde(default)]
+    pub min_risks: Option<u32>,
+}
+
+impl FeatureDesignInput {
+    pub fn builder() -> FeatureDesignInputBuilder {
+        FeatureDesignInputBuilder::default()
+    }
+
+    /// A builder starting from this input, e.g. to add constraints to one
+    /// read from a file. Duplicate constraints are dropped, keeping the first.
+    pub fn into_builder(self) -> FeatureDesignInputBuilder {
+        FeatureDesignInputBuilder {
+            repo_summary: self.repo_summary,
+            min_components: self.min_components,
+            max_components: self.max_components,
+            min_risks: self.min_risks,
+            ..Default::default()
+        }
+        .constraints(self.constraints)
+    }
+
+    /// Indices of constraints that are empty or whitespace-only. They would
+    /// become dangling bullets in the prompt.
+    pub fn blank_constraints(&self) -> Vec<usize> {
+        self.constraints
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.trim().is_empty())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The allowed number of components in words, e.g. "between 3 and 8",
+    /// or None when the count is unconstrained.
+    pub fn component_range(&self) -> Option<String> {
+        match (self.min_components, self.max_components) {
+            (Some(min), Some(max)) if min == max => Some(format!("exactly {min}")),
+            (Some(min), Some(max)) => Some(format!("between {min} and {max}")),
+            (Some(min), None) => Some(format!("at least {min}")),
+            (None, Some(max)) => Some(format!("at most {max}")),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Builds a `FeatureDesignInput` from constraints gathered from several
+/// sources. Duplicate constraints are dropped, keeping the first occurrence.
+#[derive(Debug, Default)]
+pub struct FeatureDesignInputBuilder {
+    repo_summary: String,
+    constraints: Vec<String>,
+    min_components: Option<u32>,
+    max_components: Option<u32>,
+    min_risks: Option<u32>,
+}
+
+impl FeatureDesignInputBuilder {
+    pub fn repo_summary(mut self, repo_summary: impl Into<String>) -> Self {
+        self.repo_summary = repo_summary.into();
+        self
+    }
+
+    pub fn constraint(mut self, constraint: impl Into<String>) -> Self {
+        let constraint = constraint.into();
+        if !self.constraints.contains(&constraint) {
+            self.constraints.push(constraint);
+        }
+        self
+    }
+
+    pub fn constraints<I>(self, constraints: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        constraints.into_iter().fold(self, Self::constraint)
+    }
+
+    pub fn min_components(mut self, min: u32) -> Self {
+        self.min_components = Some(min);
+        self
+    }
+
+    pub fn max_components(mut self, max: u32) -> Self {
+        self.max_components = Some(max);
+        self
+    }
+
+    pub fn min_risks(mut self, min: u32) -> Self {
+        self.min_risks = Some(min);
+        self
+    }
+
+    pub fn build(self) -> FeatureDesignInput {
+        FeatureDesignInput {
+            repo_summary: self.repo_summary,
+            constraints: self.constraints,
+            min_components: self.min_components,
+            max_components: self.max_components,
+            min_risks: self.min_risks,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,52 +132,284 @@ pub struct FeatureDesignOutput {
     pub risks: Vec<String>,
 }
 
+impl FeatureDesignOutput {
+    /// One-line human summary, e.g. for CLI status output.
+    pub fn summary(&self) -> String {
+        format!(
+            "FeatureDesign \"{}\": {} components, {} risks",
+            self.name,
+            self.components.len(),
+            self.risks.len()
+        )
+    }
+
+    /// What changed from `self` to `other`. Components are matched by `id`;
+    /// risks by exact text.
+    pub fn diff(&self, other: &FeatureDesignOutput) -> FeatureDesignDiff {
+        let find = |components: &[Component], id: &str| components.iter().find(|c| c.id == id).cloned();
+
+        let mut diff = FeatureDesignDiff::default();
+        for before in &self.components {
+            match find(&other.components, &before.id) {
+                None => diff.removed_components.push(before.clone()),
+                Some(after) if after != *before => diff.changed_components.push(ComponentChange {
+                    id: before.id.clone(),
+                    before: before.clone(),
+                    after,
+                }),
+                Some(_) => {}
+            }
+        }
+        for after in &other.components {
+            if find(&self.components, &after.id).is_none() {
+                diff.added_components.push(after.clone());
+            }
+        }
+
+        diff.added_risks = other.risks.iter().filter(|r| !self.risks.contains(r)).cloned().collect();
+        diff.removed_risks = self.risks.iter().filter(|r| !other.risks.contains(r)).cloned().collect();
+        diff
+    }
+
+    /// Write the design into `dir` (created if missing) as `design.json`, with
+    /// the name, rationale and risks, plus one `<id>.json` per component.
+    /// Component ids are reduced to filename-safe characters; ids that end up
+    /// clashing (case-insensitively, or with `design`) get `-2`, `-3`, ...
+    /// appended. Returns the written paths, top-level file first.
+    pub fn write_split(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+
+        let write = |name: &str, value: serde_json::Value| -> Result<PathBuf> {
+            let path = dir.join(format!("{name}.json"));
+            let json = serde_json::to_string_pretty(&value)?;
+            std::fs::write(&path, json).with_context(|| format!("failed to write {}", path.display()))?;
+            Ok(path)
+        };
+
+        let top = serde_json::json!({
+            "name": self.name,
+            "rationale": self.rationale,
+            "risks": self.risks,
+        });
+        let mut paths = vec![write("design", top)?];
+
+        let mut taken: HashSet<String> = HashSet::from(["design".to_string()]);
+        for component in &self.components {
+            let base = file_stem(&component.id);
+            let mut name = base.clone();
+            let mut n = 2;
+            while !taken.insert(name.to_lowercase()) {
+                name = format!("{base}-{n}");
+                n += 1;
+            }
+            paths.push(write(&name, serde_json::to_value(component)?)?);
+        }
+        Ok(paths)
+    }
+}
+
+/// Mermaid flowchart of a design's components, one node per component. An
+/// edge `a --> b` is drawn when the `api` text of `a` mentions the id of `b`.
+pub fn feature_design_to_mermaid(design: &FeatureDesignOutput) -> String {
+    // Mermaid node ids must be plain identifiers; labels keep the real id
+    let node_id = |id: &str| -> String {
+        id.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    };
+
+    let mut s = String::from("graph TD\n");
+    for c in &design.components {
+        s.push_str(&format!("    {}[\"{}\"]\n", node_id(&c.id), c.id.replace('"', "#quot;")));
+    }
+    for from in &design.components {
+        for to in &design.components {
+            if from.id != to.id && !to.id.is_empty() && from.api.contains(&to.id) {
+                s.push_str(&format!("    {} --> {}\n", node_id(&from.id), node_id(&to.id)));
+            }
+        }
+    }
+    s
+}
+
+// Component id as a file name: anything outside `[A-Za-z0-9_.-]` becomes `_`,
+// and leading dots are dropped so nothing ends up hidden or as `..`
+fn file_stem(id: &str) -> String {
+    let stem: String = id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') { c } else { '_' })
+        .collect();
+    let stem = stem.trim_start_matches('.');
+    if stem.is_empty() {
+        "component".to_string()
+    } else {
+        stem.to_string()
+    }
+}
+
+/// Differences between two `FeatureDesignOutput`s, see `FeatureDesignOutput::diff`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FeatureDesignDiff {
+    pub added_components: Vec<Component>,
+    pub removed_components: Vec<Component>,
+    pub changed_components: Vec<ComponentChange>,
+    pub added_risks: Vec<String>,
+    pub removed_risks: Vec<String>,
+}
+
+impl FeatureDesignDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_components.is_empty()
+            && self.removed_components.is_empty()
+            && self.changed_components.is_empty()
+            && self.added_risks.is_empty()
+            && self.removed_risks.is_empty()
+    }
+}
+
+/// A component present in both outputs whose fields differ.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct ComponentChange {
+    pub id: String,
+    pub before: Component,
+    pub after: Component,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Component {
     pub id: String,
     pub responsibility: String,
     pub api: String,
+    /// Ids of the components this one depends on.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+// TypeDef for FeatureDesignInput (schema export only; requests decode into the struct)
+pub fn feature_design_input_typedef() -> TypeDef {
+    TypeDef::Object(vec![
+        FieldDef {
+            name: "repo_summary".into(),
+            ty: TypeDef::Text,
+        },
+        FieldDef {
+            name: "constraints".into(),
+            ty: TypeDef::List(Box::new(TypeDef::Text)),
+        },
+    ])
 }
 
 // TypeDef for FeatureDesignOutput (for validation of LLM JSON)
 pub fn feature_design_output_typedef() -> TypeDef {
     TypeDef::Object(vec![
         FieldDef {
-            name: "name",
+            name: "name".into(),
             ty: TypeDef::Text,
         },
         FieldDef {
-            name: "rationale",
+            name: "rationale".into(),
             ty: TypeDef::Markdown,
         },
         FieldDef {
-            name: "components",
+            name: "components".into(),
             ty: TypeDef::List(Box::new(TypeDef::Object(vec![
                 FieldDef {
-                    name: "id",
+                    name: "id".into(),
                     ty: TypeDef::Text,
                 },
                 FieldDef {
-                    name: "responsibility",
+                    name: "responsibility".into(),
                     ty: TypeDef::Text,
                 },
                 FieldDef {
-                    name: "api",
+                    name: "api".into(),
                     ty: TypeDef::Markdown,
                 },
+                FieldDef {
+                    name: "depends_on".into(),
+                    ty: TypeDef::List(Box::new(TypeDef::Text)),
+                },
             ]))),
         },
         FieldDef {
-            name: "risks",
+            name: "risks".into(),
             ty: TypeDef::List(Box::new(TypeDef::Text)),
         },
     ])
 }
 
+/// Most units a Formation or FormationGrid request may ask for. The server
+/// rejects larger `unit_count`s before prompting, and `canonical` computes
+/// no more than this many points.
+pub const MAX_UNIT_COUNT: u32 = 1000;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FormationInput {
     pub formation_description: String,
+    /// At most `MAX_UNIT_COUNT`.
     pub unit_count: u32,
+    /// Re-center and scale the returned coordinates into `normalize_box`.
+    #[serde(default)]
+    pub normalize: bool,
+    /// Target frame for `normalize`; defaults to `NormalizeBox::default()`.
+    #[serde(default)]
+    pub normalize_box: Option<NormalizeBox>,
+    /// Sort the returned coordinates by y, then x, so runs that produce the
+    /// same points compare equal. Off by default to keep the model's order.
+    #[serde(default)]
+    pub stable_order: bool,
+    /// Sampling seed for this formation, overriding the server default.
+    /// Reproducible coordinates also need the server at temperature 0.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Name of a built-in formation (`circle`, `grid`, `line` or `star`) to
+    /// compute exactly instead of asking the model. Other names are ignored
+    /// and the LLM is used as usual.
+    #[serde(default)]
+    pub canonical: Option<String>,
+    /// Accept `unit_count` give or take this many coordinates instead of
+    /// exactly `unit_count`. The prompt asks for a count in that band,
+    /// ideally `unit_count`.
+    #[serde(default)]
+    pub count_tolerance: Option<u32>,
+    /// Accept any number of coordinates: no count check, no count retries,
+    /// and the prompt treats `unit_count` as a target, not a requirement.
+    #[serde(default)]
+    pub ignore_count: bool,
+}
+
+impl FormationInput {
+    /// Coordinate counts that pass validation, or None when `ignore_count`
+    /// turns the check off.
+    pub fn accepted_counts(&self) -> Option<RangeInclusive<u32>> {
+        if self.ignore_count {
+            return None;
+        }
+        let tolerance = self.count_tolerance.unwrap_or(0);
+        Some(self.unit_count.saturating_sub(tolerance)..=self.unit_count.saturating_add(tolerance))
+    }
+}
+
+/// Target frame for normalized formations. `width` and `height` must be
+/// positive; the server rejects a box that isn't with `INVALID_ARGUMENT`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NormalizeBox {
+    pub center_x: f64,
+    pub center_y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Default for NormalizeBox {
+    /// Centered on the origin, fitting within [-1, 1] on both axes.
+    fn default() -> Self {
+        Self {
+            center_x: 0.0,
+            center_y: 0.0,
+            width: 2.0,
+            height: 2.0,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,21 +423,498 @@ pub struct FormationOutput {
     pub coordinates: Vec<Coordinate>,
 }
 
+impl FormationOutput {
+    /// One-line human summary, e.g. for CLI status output.
+    pub fn summary(&self) -> String {
+        format!("Formation: {} coordinates", self.coordinates.len())
+    }
+
+    /// Exact coordinates for a built-in formation, in the 0-100 range the
+    /// prompt asks the model for. `name` is case-insensitive; None when it
+    /// isn't one of `circle`, `grid`, `line` or `star`, or when `unit_count`
+    /// is above `MAX_UNIT_COUNT`.
+    pub fn canonical(name: &str, unit_count: u32) -> Option<FormationOutput> {
+        if unit_count > MAX_UNIT_COUNT {
+            return None;
+        }
+        let n = unit_count as usize;
+        let points: Vec<(f64, f64)> = match name.trim().to_ascii_lowercase().as_str() {
+            "circle" => (0..n)
+                .map(|i| {
+                    // Start at the top and go clockwise
+                    let angle = std::f64::consts::TAU * i as f64 / n as f64 - std::f64::consts::FRAC_PI_2;
+                    (50.0 + 40.0 * angle.cos(), 50.0 + 40.0 * angle.sin())
+                })
+                .collect(),
+            "line" => (0..n).map(|i| (spread(i, n), 50.0)).collect(),
+            "grid" => {
+                let cols = (n as f64).sqrt().ceil().max(1.0) as usize;
+                let rows = n.div_ceil(cols);
+                (0..n).map(|i| (spread(i % cols, cols), spread(i / cols, rows))).collect()
+            }
+            "star" => star_outline(n),
+            _ => return None,
+        };
+
+        Some(FormationOutput {
+            coordinates: points
+                .into_iter()
+                .map(|(x, y)| Coordinate {
+                    x: round2(x),
+                    y: round2(y),
+                })
+                .collect(),
+        })
+    }
+
+    /// Round coordinates to two decimals, as `canonical` does.
+    pub fn round(&mut self) {
+        for c in &mut self.coordinates {
+            c.x = round2(c.x);
+            c.y = round2(c.y);
+        }
+    }
+
+    /// Sort coordinates by y, then x.
+    pub fn sort_stable(&mut self) {
+        self.coordinates
+            .sort_by(|a, b| a.y.total_cmp(&b.y).then(a.x.total_cmp(&b.x)));
+    }
+
+    /// Move the centroid to the box center and scale uniformly so every point
+    /// fits inside the box. Aspect ratio is preserved; a formation with no
+    /// extent (all points equal) is only translated.
+    pub fn normalize(&mut self, target: &NormalizeBox) {
+        if self.coordinates.is_empty() {
+            return;
+        }
+
+        let n = self.coordinates.len() as f64;
+        let cx = self.coordinates.iter().map(|c| c.x).sum::<f64>() / n;
+        let cy = self.coordinates.iter().map(|c| c.y).sum::<f64>() / n;
+
+        let max_dx = self.coordinates.iter().map(|c| (c.x - cx).abs()).fold(0.0, f64::max);
+        let max_dy = self.coordinates.iter().map(|c| (c.y - cy).abs()).fold(0.0, f64::max);
+
+        let scale_x = if max_dx > 0.0 { target.width / 2.0 / max_dx } else { f64::INFINITY };
+        let scale_y = if max_dy > 0.0 { target.height / 2.0 / max_dy } else { f64::INFINITY };
+        let scale = match scale_x.min(scale_y) {
+            s if s.is_finite() => s,
+            _ => 1.0,
+        };
+
+        for c in &mut self.coordinates {
+            c.x = target.center_x + (c.x - cx) * scale;
+            c.y = target.center_y + (c.y - cy) * scale;
+        }
+    }
+}
+
+// TypeDef for FormationInput (schema export only). Optional fields such as
+// `normalize` and `seed` can't be expressed and are left out.
+pub fn formation_input_typedef() -> TypeDef {
+    TypeDef::Object(vec![
+        FieldDef {
+            name: "formation_description".into(),
+            ty: TypeDef::Text,
+        },
+        FieldDef {
+            name: "unit_count".into(),
+            ty: TypeDef::IntegerRange { min: Some(0), max: Some(MAX_UNIT_COUNT as i64) },
+        },
+    ])
+}
+
 // TypeDef for FormationOutput (for validation of LLM JSON)
 pub fn formation_output_typedef() -> TypeDef {
     TypeDef::Object(vec![
         FieldDef {
-            name: "coordinates",
+            name: "coordinates".into(),
             ty: TypeDef::List(Box::new(TypeDef::Object(vec![
                 FieldDef {
-                    name: "x",
+                    name: "x".into(),
                     ty: TypeDef::Number,
                 },
                 FieldDef {
-                    name: "y",
+                    name: "y".into(),
                     ty: TypeDef::Number,
                 },
             ]))),
         },
     ])
 }
+
+/// Position `i` of `n` evenly spaced from 10 to 90; the middle when `n` is 1.
+fn spread(i: usize, n: usize) -> f64 {
+    if n <= 1 {
+        50.0
+    } else {
+        10.0 + 80.0 * i as f64 / (n - 1) as f64
+    }
+}
+
+fn round2(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+/// `n` points evenly spaced along the outline of a five-pointed star,
+/// starting at the top point.
+fn star_outline(n: usize) -> Vec<(f64, f64)> {
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    // Alternating outer and inner corners
+    let corners: Vec<(f64, f64)> = (0..10)
+        .map(|k| {
+            let radius = if k % 2 == 0 { 40.0 } else { 16.0 };
+            let angle = PI * k as f64 / 5.0 - FRAC_PI_2;
+            (50.0 + radius * angle.cos(), 50.0 + radius * angle.sin())
+        })
+        .collect();
+    let edges: Vec<((f64, f64), (f64, f64))> = (0..10).map(|k| (corners[k], corners[(k + 1) % 10])).collect();
+    let length = |((x0, y0), (x1, y1)): ((f64, f64), (f64, f64))| ((x1 - x0).powi(2) + (y1 - y0).powi(2)).sqrt();
+    let perimeter: f64 = edges.iter().copied().map(length).sum();
+
+    (0..n)
+        .map(|i| {
+            let mut distance = perimeter * i as f64 / n as f64;
+            for &edge in &edges {
+                let edge_length = length(edge);
+                if distance <= edge_length {
+                    let t = distance / edge_length;
+                    let ((x0, y0), (x1, y1)) = edge;
+                    return (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t);
+                }
+                distance -= edge_length;
+            }
+            corners[0]
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FormationGridInput {
+    pub formation_description: String,
+    pub unit_count: u32,
+    /// Number of columns; valid `col` values are `0..grid_width`.
+    pub grid_width: u32,
+    /// Number of rows; valid `row` values are `0..grid_height`.
+    pub grid_height: u32,
+}
+
+/// A cell on the formation grid, zero-based from the top-left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GridCoordinate {
+    pub col: u32,
+    pub row: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FormationGridOutput {
+    pub coordinates: Vec<GridCoordinate>,
+}
+
+impl FormationGridOutput {
+    /// One-line human summary, e.g. for CLI status output.
+    pub fn summary(&self) -> String {
+        format!("FormationGrid: {} cells", self.coordinates.len())
+    }
+}
+
+// TypeDef of a `u32` field, so a negative value is a validation error fed
+// back to the model rather than a failure to deserialize
+const UNSIGNED: TypeDef = TypeDef::IntegerRange { min: Some(0), max: None };
+
+// TypeDef for FormationGridInput (schema export only)
+pub fn formation_grid_input_typedef() -> TypeDef {
+    TypeDef::Object(vec![
+        FieldDef {
+            name: "formation_description".into(),
+            ty: TypeDef::Text,
+        },
+        FieldDef {
+            name: "unit_count".into(),
+            ty: UNSIGNED,
+        },
+        FieldDef {
+            name: "grid_width".into(),
+            ty: UNSIGNED,
+        },
+        FieldDef {
+            name: "grid_height".into(),
+            ty: UNSIGNED,
+        },
+    ])
+}
+
+// TypeDef for FormationGridOutput (for validation of LLM JSON)
+pub fn grid_output_typedef() -> TypeDef {
+    TypeDef::Object(vec![
+        FieldDef {
+            name: "coordinates".into(),
+            ty: TypeDef::List(Box::new(TypeDef::Object(vec![
+                FieldDef {
+                    name: "col".into(),
+                    ty: UNSIGNED,
+                },
+                FieldDef {
+                    name: "row".into(),
+                    ty: UNSIGNED,
+                },
+            ]))),
+        },
+    ])
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequirementsInput {
+    /// Free text to extract requirements from, e.g. a spec or meeting notes.
+    pub document: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    Medium,
+    Low,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Requirement {
+    /// Unique within the output, e.g. `REQ-1`.
+    pub id: String,
+    pub text: String,
+    pub priority: Priority,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequirementsOutput {
+    pub requirements: Vec<Requirement>,
+}
+
+impl RequirementsOutput {
+    /// One-line human summary, e.g. for CLI status output.
+    pub fn summary(&self) -> String {
+        let count = |priority| self.requirements.iter().filter(|r| r.priority == priority).count();
+        format!(
+            "Requirements: {} ({} high, {} medium, {} low)",
+            self.requirements.len(),
+            count(Priority::High),
+            count(Priority::Medium),
+            count(Priority::Low)
+        )
+    }
+}
+
+// TypeDef for RequirementsInput (schema export only)
+pub fn requirements_input_typedef() -> TypeDef {
+    TypeDef::Object(vec![FieldDef {
+        name: "document".into(),
+        ty: TypeDef::Text,
+    }])
+}
+
+// TypeDef for RequirementsOutput (for validation of LLM JSON)
+pub fn requirements_output_typedef() -> TypeDef {
+    TypeDef::Object(vec![FieldDef {
+        name: "requirements".into(),
+        ty: TypeDef::List(Box::new(TypeDef::Object(vec![
+            FieldDef {
+                name: "id".into(),
+                ty: TypeDef::Text,
+            },
+            FieldDef {
+                name: "text".into(),
+                ty: TypeDef::Text,
+            },
+            FieldDef {
+                name: "priority".into(),
+                ty: TypeDef::Enum(vec!["high".into(), "medium".into(), "low".into()]),
+            },
+        ]))),
+    }])
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiDesignInput {
+    /// The resource the API exposes, e.g. `invoices`.
+    pub resource: String,
+    /// What clients need to do with it, in plain words, e.g. `list`,
+    /// `download as PDF`.
+    pub operations: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Patch,
+}
+
+impl HttpMethod {
+    pub const ALL: [HttpMethod; 5] = [
+        HttpMethod::Get,
+        HttpMethod::Post,
+        HttpMethod::Put,
+        HttpMethod::Delete,
+        HttpMethod::Patch,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Patch => "PATCH",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathSpec {
+    /// OpenAPI path template, e.g. `/invoices/{invoiceId}`.
+    pub path: String,
+    pub method: HttpMethod,
+    pub summary: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiDesignOutput {
+    /// Unique by `(path, method)`.
+    pub paths: Vec<PathSpec>,
+}
+
+impl ApiDesignOutput {
+    /// One-line human summary, e.g. for CLI status output.
+    pub fn summary(&self) -> String {
+        let distinct: HashSet<&str> = self.paths.iter().map(|p| p.path.as_str()).collect();
+        format!("ApiDesign: {} operations on {} paths", self.paths.len(), distinct.len())
+    }
+}
+
+// TypeDef for ApiDesignInput (schema export only)
+pub fn api_design_input_typedef() -> TypeDef {
+    TypeDef::Object(vec![
+        FieldDef {
+            name: "resource".into(),
+            ty: TypeDef::Text,
+        },
+        FieldDef {
+            name: "operations".into(),
+            ty: TypeDef::List(Box::new(TypeDef::Text)),
+        },
+    ])
+}
+
+// TypeDef for ApiDesignOutput (for validation of LLM JSON)
+pub fn api_design_output_typedef() -> TypeDef {
+    TypeDef::Object(vec![FieldDef {
+        name: "paths".into(),
+        ty: TypeDef::List(Box::new(TypeDef::Object(vec![
+            FieldDef {
+                name: "path".into(),
+                ty: TypeDef::Text,
+            },
+            FieldDef {
+                name: "method".into(),
+                ty: TypeDef::Enum(HttpMethod::ALL.iter().map(|m| m.as_str().into()).collect()),
+            },
+            FieldDef {
+                name: "summary".into(),
+                ty: TypeDef::Text,
+            },
+        ]))),
+    }])
+}
+
+/// Input of the RiskAssessment shape: the output of a FeatureDesign run,
+/// so the two chain into a pipeline.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RiskAssessmentInput {
+    pub design: FeatureDesignOutput,
+}
+
+/// Lowest and highest `likelihood` and `impact` the model may give.
+pub const RISK_RATING_RANGE: RangeInclusive<u32> = 1..=5;
+
+// `likelihood` and `impact` in the typedef, so the range is validated and
+// shown in the prompt's schema
+const RISK_RATING: TypeDef = TypeDef::IntegerRange {
+    min: Some(*RISK_RATING_RANGE.start() as i64),
+    max: Some(*RISK_RATING_RANGE.end() as i64),
+};
+
+// `score`, the product of two ratings
+const RISK_SCORE: TypeDef = TypeDef::IntegerRange {
+    min: Some((*RISK_RATING_RANGE.start() * *RISK_RATING_RANGE.start()) as i64),
+    max: Some((*RISK_RATING_RANGE.end() * *RISK_RATING_RANGE.end()) as i64),
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredRisk {
+    /// One of the design's risks, as written there.
+    pub risk: String,
+    /// How likely the risk is to happen, 1 (rare) to 5 (almost certain).
+    pub likelihood: u32,
+    /// How bad it would be, 1 (negligible) to 5 (severe).
+    pub impact: u32,
+    /// `likelihood * impact`, 1 to 25.
+    pub score: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RiskAssessmentOutput {
+    pub scored_risks: Vec<ScoredRisk>,
+}
+
+impl RiskAssessmentOutput {
+    /// One-line human summary, e.g. for CLI status output.
+    pub fn summary(&self) -> String {
+        let highest = self.scored_risks.iter().map(|r| r.score).max().unwrap_or(0);
+        format!(
+            "RiskAssessment: {} risks scored, highest score {}",
+            self.scored_risks.len(),
+            highest
+        )
+    }
+}
+
+// TypeDef for RiskAssessmentInput (schema export only)
+pub fn risk_assessment_input_typedef() -> TypeDef {
+    TypeDef::Object(vec![FieldDef {
+        name: "design".into(),
+        ty: feature_design_output_typedef(),
+    }])
+}
+
+// TypeDef for RiskAssessmentOutput (for validation of LLM JSON). That each
+// score is its likelihood times its impact, and that every risk of the design
+// is scored exactly once, is checked after validation, see
+// `LlmClient::generate_risk_assessment`.
+pub fn risk_assessment_output_typedef() -> TypeDef {
+    TypeDef::Object(vec![FieldDef {
+        name: "scored_risks".into(),
+        ty: TypeDef::List(Box::new(TypeDef::Object(vec![
+            FieldDef {
+                name: "risk".into(),
+                ty: TypeDef::Text,
+            },
+            FieldDef {
+                name: "likelihood".into(),
+                ty: RISK_RATING,
+            },
+            FieldDef {
+                name: "impact".into(),
+                ty: RISK_RATING,
+            },
+            FieldDef {
+                name: "score".into(),
+                ty: RISK_SCORE,
+            },
+        ]))),
+    }])
+}
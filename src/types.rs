@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use serde_json::Value;
 
 /// Simple type system for shapes.
@@ -6,14 +8,205 @@ pub enum TypeDef {
     Text,
     Markdown,
     Number,
+    /// A JSON number written without a fraction or exponent, e.g. `3` but
+    /// not `3.0`. Any sign and size pass; use IntegerRange for a field read
+    /// into an unsigned or otherwise bounded integer. `repair` rewrites whole
+    /// floats such as `3.0` before validation.
+    Integer,
+    /// An Integer within `min..=max`; a `None` bound is open. A Rust `u32`
+    /// field is `min: Some(0)`.
+    IntegerRange { min: Option<i64>, max: Option<i64> },
     Bool,
+    /// A string that must be one of these values, e.g. `high`, `medium`, `low`.
+    Enum(Vec<Cow<'static, str>>),
     List(Box<TypeDef>),
+    /// An array of exactly these elements, element `i` of type `i`, e.g. a
+    /// bounding box `[minx, miny, maxx, maxy]`.
+    Tuple(Vec<TypeDef>),
     Object(Vec<FieldDef>),
+    /// The key must be present, but its value may be `null`.
+    Nullable(Box<TypeDef>),
+}
+
+impl TypeDef {
+    /// Build a TypeDef from a subset of JSON Schema.
+    ///
+    /// Supported: `type` of `string` (`format: "markdown"` maps to Markdown,
+    /// an `enum` of strings to Enum),
+    /// `number`, `integer` (with `minimum`/`maximum`, an IntegerRange),
+    /// `boolean`, `array` with `items` or `prefixItems`
+    /// (a Tuple), and `object` with `properties`. TypeDef fields are always required, so only properties
+    /// listed in `required` are kept; other properties are not validated.
+    /// A two-element `type` such as `["string", "null"]` maps to Nullable.
+    pub fn from_json_schema(schema: &Value) -> anyhow::Result<TypeDef> {
+        from_json_schema_inner(schema, "$")
+    }
+
+    /// Export as JSON Schema, in the subset `from_json_schema` reads back.
+    /// Every object field is listed in `required`.
+    pub fn to_json_schema(&self) -> Value {
+        use serde_json::json;
+
+        match self {
+            TypeDef::Text => json!({ "type": "string" }),
+            TypeDef::Markdown => json!({ "type": "string", "format": "markdown" }),
+            TypeDef::Number => json!({ "type": "number" }),
+            TypeDef::Integer => json!({ "type": "integer" }),
+            TypeDef::IntegerRange { min, max } => {
+                let mut schema = json!({ "type": "integer" });
+                if let Some(min) = min {
+                    schema["minimum"] = json!(min);
+                }
+                if let Some(max) = max {
+                    schema["maximum"] = json!(max);
+                }
+                schema
+            }
+            TypeDef::Bool => json!({ "type": "boolean" }),
+            TypeDef::Enum(values) => json!({ "type": "string", "enum": values }),
+            TypeDef::List(inner) => json!({ "type": "array", "items": inner.to_json_schema() }),
+            TypeDef::Tuple(items) => {
+                let prefix: Vec<Value> = items.iter().map(TypeDef::to_json_schema).collect();
+                json!({
+                    "type": "array",
+                    "prefixItems": prefix,
+                    "items": false,
+                    "minItems": items.len(),
+                    "maxItems": items.len(),
+                })
+            }
+            TypeDef::Object(fields) => {
+                let properties: serde_json::Map<String, Value> = fields
+                    .iter()
+                    .map(|f| (f.name.to_string(), f.ty.to_json_schema()))
+                    .collect();
+                let required: Vec<&str> = fields.iter().map(|f| f.name.as_ref()).collect();
+                json!({ "type": "object", "properties": properties, "required": required })
+            }
+            TypeDef::Nullable(inner) => {
+                let mut schema = inner.to_json_schema();
+                // `Nullable(Nullable(_))` is already `[ty, "null"]`
+                if !schema["type"].is_array() {
+                    let ty = schema["type"].take();
+                    schema["type"] = json!([ty, "null"]);
+                }
+                // validators check `enum` on its own, so it must list null too
+                if let Some(values) = schema.get_mut("enum").and_then(Value::as_array_mut) {
+                    if !values.contains(&Value::Null) {
+                        values.push(Value::Null);
+                    }
+                }
+                schema
+            }
+        }
+    }
+}
+
+fn from_json_schema_inner(schema: &Value, path: &str) -> anyhow::Result<TypeDef> {
+    use anyhow::anyhow;
+
+    // `"type": ["string", "null"]` is a nullable string
+    if let Some(types) = schema.get("type").and_then(Value::as_array) {
+        let non_null: Vec<&Value> = types.iter().filter(|t| t.as_str() != Some("null")).collect();
+        if let ([ty], true) = (non_null.as_slice(), types.len() == 2) {
+            let mut inner = schema.clone();
+            inner["type"] = (*ty).clone();
+            if let Some(values) = inner.get_mut("enum").and_then(Value::as_array_mut) {
+                values.retain(|value| !value.is_null());
+            }
+            return Ok(TypeDef::Nullable(Box::new(from_json_schema_inner(&inner, path)?)));
+        }
+        return Err(anyhow!("unsupported type list at {path}: {}", Value::Array(types.clone())));
+    }
+
+    let ty = schema
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("schema at {path} has no string `type`"))?;
+
+    match ty {
+        "string" if schema.get("enum").is_some() => {
+            let values = schema
+                .get("enum")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("`enum` at {path} is not an array"))?
+                .iter()
+                .map(|value| {
+                    value
+                        .as_str()
+                        .map(|value| Cow::Owned(value.to_string()))
+                        .ok_or_else(|| anyhow!("`enum` at {path} has a non-string value: {value}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            if values.is_empty() {
+                return Err(anyhow!("`enum` at {path} is empty"));
+            }
+            Ok(TypeDef::Enum(values))
+        }
+        "string" => match schema.get("format").and_then(Value::as_str) {
+            Some("markdown") => Ok(TypeDef::Markdown),
+            _ => Ok(TypeDef::Text),
+        },
+        "number" => Ok(TypeDef::Number),
+        "integer" => {
+            let bound = |key: &str| -> anyhow::Result<Option<i64>> {
+                schema
+                    .get(key)
+                    .map(|bound| bound.as_i64().ok_or_else(|| anyhow!("`{key}` at {path} is not an integer: {bound}")))
+                    .transpose()
+            };
+            match (bound("minimum")?, bound("maximum")?) {
+                (None, None) => Ok(TypeDef::Integer),
+                (min, max) => Ok(TypeDef::IntegerRange { min, max }),
+            }
+        }
+        "boolean" => Ok(TypeDef::Bool),
+        "array" if schema.get("prefixItems").is_some() => {
+            let prefix = schema
+                .get("prefixItems")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("`prefixItems` at {path} is not an array"))?;
+            let items = prefix
+                .iter()
+                .enumerate()
+                .map(|(idx, item)| from_json_schema_inner(item, &format!("{path}[{idx}]")))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(TypeDef::Tuple(items))
+        }
+        "array" => {
+            let items = schema
+                .get("items")
+                .ok_or_else(|| anyhow!("array schema at {path} has no `items`"))?;
+            let inner = from_json_schema_inner(items, &format!("{path}[]"))?;
+            Ok(TypeDef::List(Box::new(inner)))
+        }
+        "object" => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let required = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|names| names.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+                .unwrap_or_default();
+
+            let mut fields = Vec::new();
+            for name in required {
+                let field_schema = properties
+                    .and_then(|props| props.get(name))
+                    .ok_or_else(|| anyhow!("required field {path}.{name} has no entry in `properties`"))?;
+                fields.push(FieldDef {
+                    name: Cow::Owned(name.to_string()),
+                    ty: from_json_schema_inner(field_schema, &format!("{path}.{name}"))?,
+                });
+            }
+            Ok(TypeDef::Object(fields))
+        }
+        other => Err(anyhow!("unsupported schema type at {path}: {other}")),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct FieldDef {
-    pub name: &'static str,
+    pub name: Cow<'static, str>,
     pub ty: TypeDef,
 }
 
@@ -22,6 +215,14 @@ pub struct FieldDef {
 pub enum ValidationError {
     MissingField { path: String },
     TypeMismatch { path: String, expected: String, found: String },
+    /// The value has the right type but breaks a rule the schema can't
+    /// express, e.g. a duplicate id or a count the input asked for.
+    /// `message` says what was expected and what was found.
+    Constraint { path: String, message: String },
+    UnknownShape { shape_id: String },
+    /// The value matched the schema but still could not be read into the
+    /// shape's output struct, e.g. a number too large for its field.
+    Deserialize { message: String },
 }
 
 impl std::fmt::Display for ValidationError {
@@ -33,18 +234,231 @@ impl std::fmt::Display for ValidationError {
             ValidationError::TypeMismatch { path, expected, found } => {
                 write!(f, "Type mismatch at {path}: expected {expected}, found {found}")
             }
+            ValidationError::Constraint { path, message } => {
+                write!(f, "Constraint violated at {path}: {message}")
+            }
+            ValidationError::UnknownShape { shape_id } => {
+                write!(f, "Unknown shape: {shape_id}")
+            }
+            ValidationError::Deserialize { message } => {
+                write!(f, "Output does not fit the expected structure: {message}")
+            }
         }
     }
 }
 
+impl ValidationError {
+    /// The dotted path, e.g. `$.components[0].id`, if the error has one.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            ValidationError::MissingField { path }
+            | ValidationError::TypeMismatch { path, .. }
+            | ValidationError::Constraint { path, .. } => Some(path),
+            ValidationError::UnknownShape { .. } | ValidationError::Deserialize { .. } => None,
+        }
+    }
+
+    /// The path as an RFC 6901 JSON Pointer, e.g. `/components/0/id`. The
+    /// root `$` is the empty pointer.
+    pub fn json_pointer(&self) -> Option<String> {
+        self.path().map(to_json_pointer)
+    }
+}
+
+/// Convert a dotted path (`$.a[0].b`) to a JSON Pointer (`/a/0/b`), escaping
+/// `~` and `/` in keys. A key that itself contains `.` or `[` is split, as the
+/// dotted path can't tell it apart.
+fn to_json_pointer(path: &str) -> String {
+    let mut pointer = String::new();
+    let rest = path.strip_prefix('$').unwrap_or(path);
+    for c in rest.chars() {
+        match c {
+            '.' | '[' => pointer.push('/'),
+            ']' => {}
+            '~' => pointer.push_str("~0"),
+            '/' => pointer.push_str("~1"),
+            c => pointer.push(c),
+        }
+    }
+    pointer
+}
+
 impl std::error::Error for ValidationError {}
 
+/// Errors `merge_errors` may show as one: the first of them, the text of its
+/// path around the indices (see `split_indices`) and each member's indices.
+type ErrorGroup<'a> = (&'a ValidationError, Vec<&'a str>, Vec<Vec<usize>>);
+
+/// Merge errors that differ only in an array index into one line each, e.g.
+/// twelve missing `id`s become `Missing required field at path
+/// $.components[0..11].id`. Ranges are inclusive and gaps are listed, as in
+/// `[0..2,5]`. Order follows the first error of each group; other errors are
+/// kept as they are. Meant for retry prompts, where it saves tokens on large
+/// error sets.
+pub fn merge_errors(errors: &[ValidationError]) -> Vec<String> {
+    // Errors with the same kind and details and the same path once indices
+    // are taken out, with the indices of each
+    let mut groups: Vec<ErrorGroup> = Vec::new();
+    for err in errors {
+        let Some(path) = err.path() else {
+            groups.push((err, Vec::new(), Vec::new()));
+            continue;
+        };
+        let (template, indices) = split_indices(path);
+        let same = |(other, other_template, _): &&mut ErrorGroup| {
+            *other_template == template && same_details(other, err)
+        };
+        match groups.iter_mut().find(same) {
+            Some((_, _, members)) => members.push(indices),
+            None => groups.push((err, template, vec![indices])),
+        }
+    }
+
+    let mut lines = Vec::new();
+    for (first, template, members) in groups {
+        if members.len() <= 1 {
+            lines.push(first.to_string());
+            continue;
+        }
+
+        // Collapse the one index that varies; when several do, collapse the
+        // last and keep the others apart
+        let varying: Vec<usize> = (0..members[0].len())
+            .filter(|&pos| members.iter().any(|m| m[pos] != members[0][pos]))
+            .collect();
+        let collapse = match varying.as_slice() {
+            [] => {
+                lines.push(first.to_string());
+                continue;
+            }
+            [pos] => *pos,
+            _ => members[0].len() - 1,
+        };
+
+        let mut subgroups: Vec<(Vec<usize>, Vec<usize>)> = Vec::new();
+        for member in &members {
+            let mut key = member.clone();
+            key[collapse] = 0;
+            match subgroups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, values)) => values.push(member[collapse]),
+                None => subgroups.push((key, vec![member[collapse]])),
+            }
+        }
+        for (key, values) in subgroups {
+            let parts: Vec<String> = key
+                .iter()
+                .enumerate()
+                .map(|(pos, idx)| if pos == collapse { index_ranges(&values) } else { idx.to_string() })
+                .collect();
+            lines.push(with_path(first, &fill_indices(&template, &parts), values.len() > 1));
+        }
+    }
+    lines
+}
+
+fn same_details(a: &ValidationError, b: &ValidationError) -> bool {
+    match (a, b) {
+        (ValidationError::MissingField { .. }, ValidationError::MissingField { .. }) => true,
+        (
+            ValidationError::TypeMismatch { expected, found, .. },
+            ValidationError::TypeMismatch {
+                expected: other_expected,
+                found: other_found,
+                ..
+            },
+        ) => expected == other_expected && found == other_found,
+        (ValidationError::Constraint { message, .. }, ValidationError::Constraint { message: other, .. }) => {
+            message == other
+        }
+        _ => false,
+    }
+}
+
+/// `err` reported at `path` instead of its own, in the plural when `many`.
+fn with_path(err: &ValidationError, path: &str, many: bool) -> String {
+    match err {
+        ValidationError::MissingField { .. } if many => format!("Missing required fields at paths {path}"),
+        ValidationError::MissingField { .. } => format!("Missing required field at path {path}"),
+        ValidationError::TypeMismatch { expected, found, .. } if many => {
+            format!("Type mismatches at {path}: expected {expected}, found {found}")
+        }
+        ValidationError::TypeMismatch { expected, found, .. } => {
+            format!("Type mismatch at {path}: expected {expected}, found {found}")
+        }
+        ValidationError::Constraint { message, .. } if many => format!("Constraints violated at {path}: {message}"),
+        ValidationError::Constraint { message, .. } => format!("Constraint violated at {path}: {message}"),
+        ValidationError::UnknownShape { .. } | ValidationError::Deserialize { .. } => err.to_string(),
+    }
+}
+
+/// `$.a[3].b[0]` split at its array indices: the text around them
+/// (`["$.a", ".b", ""]`, always one more than the indices) and the indices
+/// (`[3, 0]`). Brackets without a number inside, as in a key named `a[]`,
+/// stay part of the text.
+fn split_indices(path: &str) -> (Vec<&str>, Vec<usize>) {
+    let mut texts = Vec::new();
+    let mut indices = Vec::new();
+    let (mut start, mut search) = (0, 0);
+    while let Some(open) = path[search..].find('[').map(|i| search + i) {
+        let index = path[open..].find(']').and_then(|len| {
+            let close = open + len;
+            path[open + 1..close].parse::<usize>().ok().map(|index| (close, index))
+        });
+        match index {
+            Some((close, index)) => {
+                texts.push(&path[start..open]);
+                indices.push(index);
+                start = close + 1;
+                search = start;
+            }
+            None => search = open + 1,
+        }
+    }
+    texts.push(&path[start..]);
+    (texts, indices)
+}
+
+/// The reverse of `split_indices`, with `parts` in place of the indices.
+fn fill_indices(texts: &[&str], parts: &[String]) -> String {
+    let mut path = texts.first().map_or_else(String::new, |text| text.to_string());
+    for (part, text) in parts.iter().zip(texts.iter().skip(1)) {
+        path.push('[');
+        path.push_str(part);
+        path.push(']');
+        path.push_str(text);
+    }
+    path
+}
+
+/// Indices as inclusive ranges, e.g. `0..2,5` for 0, 1, 2 and 5.
+fn index_ranges(values: &[usize]) -> String {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < sorted.len() {
+        let start = sorted[i];
+        while i + 1 < sorted.len() && sorted[i + 1] == sorted[i] + 1 {
+            i += 1;
+        }
+        ranges.push(if sorted[i] == start {
+            start.to_string()
+        } else {
+            format!("{start}..{}", sorted[i])
+        });
+        i += 1;
+    }
+    ranges.join(",")
+}
+
 /// Validate a serde_json::Value against a TypeDef.
 ///
 /// Returns Ok(()) if everything matches, or Err(vec![]) with one or more errors.
 pub fn validate(ty: &TypeDef, value: &Value) -> Result<(), Vec<ValidationError>> {
     let mut errors = Vec::new();
-    validate_inner(ty, value, "$", &mut errors);
+    validate_inner(ty, value, &mut Location::root(), false, &mut errors);
     if errors.is_empty() {
         Ok(())
     } else {
@@ -52,14 +466,399 @@ pub fn validate(ty: &TypeDef, value: &Value) -> Result<(), Vec<ValidationError>>
     }
 }
 
-fn validate_inner(ty: &TypeDef, value: &Value, path: &str, errors: &mut Vec<ValidationError>) {
+/// The first error `validate` would report, stopping the walk there. Cheaper
+/// than `validate` on large values when the full list isn't needed.
+pub fn validate_first(ty: &TypeDef, value: &Value) -> Option<ValidationError> {
+    let mut errors = Vec::new();
+    validate_inner(ty, value, &mut Location::root(), true, &mut errors);
+    errors.into_iter().next()
+}
+
+/// Whether `value` matches `ty`, stopping at the first mismatch.
+pub fn is_valid(ty: &TypeDef, value: &Value) -> bool {
+    validate_first(ty, value).is_none()
+}
+
+/// Validate each of `values` against `ty`, e.g. a file of model outputs.
+/// Results are in the same order as `values`.
+pub fn validate_many(ty: &TypeDef, values: &[Value]) -> Vec<Result<(), Vec<ValidationError>>> {
+    values.iter().map(|value| validate(ty, value)).collect()
+}
+
+/// Guess a TypeDef from an example value, as a starting point for a new
+/// shape's output typedef. The heuristics are deliberately simple:
+///
+/// - strings are Text and numbers are Number, never Markdown or Integer;
+/// - `null` is Nullable Text;
+/// - an array is a List of its first element's type, so later elements of a
+///   different type are ignored, and an empty array is a List of Text;
+/// - an object is an Object with every key as a required field, in key order.
+///
+/// Review the result before using it: tighten Number to Integer and Text to
+/// Markdown where that is meant, and fix up empty or mixed arrays. Exporting
+/// it with `to_json_schema` gives a manifest schema that is easy to hand-edit.
+pub fn infer_typedef(value: &Value) -> TypeDef {
+    match value {
+        Value::Null => TypeDef::Nullable(Box::new(TypeDef::Text)),
+        Value::Bool(_) => TypeDef::Bool,
+        Value::Number(_) => TypeDef::Number,
+        Value::String(_) => TypeDef::Text,
+        Value::Array(items) => TypeDef::List(Box::new(items.first().map_or(TypeDef::Text, infer_typedef))),
+        Value::Object(map) => TypeDef::Object(
+            map.iter()
+                .map(|(name, field)| FieldDef {
+                    name: Cow::Owned(name.clone()),
+                    ty: infer_typedef(field),
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Check that every value passing `source` also passes `target`, e.g. that
+/// one shape's output can be another shape's input. Extra object fields in
+/// `source` are fine, since `validate` ignores them; Text and Markdown are
+/// interchangeable, an Integer is a Number and an Enum is Text. The error
+/// names the first place the types differ, e.g. `$.design.risks[]: expected
+/// string, found integer`.
+pub fn check_assignable(target: &TypeDef, source: &TypeDef) -> Result<(), String> {
+    assignable_inner(target, source, "$")
+}
+
+fn assignable_inner(target: &TypeDef, source: &TypeDef, path: &str) -> Result<(), String> {
+    let mismatch = || {
+        Err(format!(
+            "{path}: expected {}, found {}",
+            tuple_type_names(std::slice::from_ref(target)),
+            tuple_type_names(std::slice::from_ref(source))
+        ))
+    };
+    match (target, source) {
+        (TypeDef::Nullable(target), TypeDef::Nullable(source)) => assignable_inner(target, source, path),
+        (TypeDef::Nullable(target), source) => assignable_inner(target, source, path),
+        (_, TypeDef::Nullable(_)) => mismatch(),
+        (TypeDef::Text | TypeDef::Markdown, TypeDef::Text | TypeDef::Markdown | TypeDef::Enum(_)) => Ok(()),
+        (TypeDef::Enum(allowed), TypeDef::Enum(values)) => match values.iter().find(|v| !allowed.contains(v)) {
+            Some(value) => Err(format!("{path}: {value:?} is not one of {}", enum_values(allowed))),
+            None => Ok(()),
+        },
+        (TypeDef::Number, TypeDef::Number | TypeDef::Integer | TypeDef::IntegerRange { .. })
+        | (TypeDef::Integer, TypeDef::Integer | TypeDef::IntegerRange { .. })
+        | (TypeDef::Bool, TypeDef::Bool) => Ok(()),
+        (TypeDef::IntegerRange { min, max }, TypeDef::Integer) if min.is_none() && max.is_none() => Ok(()),
+        (TypeDef::IntegerRange { min, max }, TypeDef::IntegerRange { min: source_min, max: source_max }) => {
+            // Every value the source allows must be in the target's range
+            let min_ok = min.is_none_or(|min| source_min.is_some_and(|source| source >= min));
+            let max_ok = max.is_none_or(|max| source_max.is_some_and(|source| source <= max));
+            if min_ok && max_ok {
+                Ok(())
+            } else {
+                mismatch()
+            }
+        }
+        (TypeDef::List(target), TypeDef::List(source)) => assignable_inner(target, source, &format!("{path}[]")),
+        (TypeDef::Tuple(targets), TypeDef::Tuple(sources)) if targets.len() == sources.len() => {
+            for (i, (target, source)) in targets.iter().zip(sources).enumerate() {
+                assignable_inner(target, source, &format!("{path}[{i}]"))?;
+            }
+            Ok(())
+        }
+        (TypeDef::Object(targets), TypeDef::Object(sources)) => {
+            for field in targets {
+                let field_path = format!("{path}.{}", field.name);
+                match sources.iter().find(|f| f.name == field.name) {
+                    Some(source) => assignable_inner(&field.ty, &source.ty, &field_path)?,
+                    None => return Err(format!("{field_path}: missing")),
+                }
+            }
+            Ok(())
+        }
+        _ => mismatch(),
+    }
+}
+
+/// Best-effort repair of `value` so it matches `ty`: missing or mistyped
+/// fields get a default (`""`, `0`, `false`, `[]`, `null` for Nullable, the
+/// first value for Enum), integers out of range are clamped to it,
+/// list items are repaired one by one, tuples are padded or cut to length,
+/// and extra object keys are dropped.
+///
+/// The result passes `validate` but may be meaningless; it is meant for
+/// degraded output that the caller has explicitly asked for.
+pub fn coerce(ty: &TypeDef, value: Value) -> Value {
+    match (ty, value) {
+        (TypeDef::Text | TypeDef::Markdown, value @ Value::String(_)) => value,
+        (TypeDef::Text | TypeDef::Markdown, _) => Value::String(String::new()),
+        (TypeDef::Number, value @ Value::Number(_)) => value,
+        (TypeDef::Integer, Value::Number(n)) if n.is_i64() || n.is_u64() => Value::Number(n),
+        (TypeDef::Integer, Value::Number(n)) => n.as_f64().map_or(Value::from(0), |f| Value::from(f.round() as i64)),
+        (TypeDef::IntegerRange { min, max }, value) => {
+            let n = coerce(&TypeDef::Integer, value).as_i64().unwrap_or_default();
+            let n = min.map_or(n, |min| n.max(min));
+            Value::from(max.map_or(n, |max| n.min(max)))
+        }
+        (TypeDef::Number | TypeDef::Integer, _) => Value::from(0),
+        (TypeDef::Bool, value @ Value::Bool(_)) => value,
+        (TypeDef::Bool, _) => Value::Bool(false),
+        (TypeDef::Enum(values), Value::String(text)) if values.iter().any(|v| *v == text) => Value::String(text),
+        (TypeDef::Enum(values), _) => Value::String(values.first().map(|v| v.to_string()).unwrap_or_default()),
+        (TypeDef::List(inner), Value::Array(items)) => {
+            Value::Array(items.into_iter().map(|item| coerce(inner, item)).collect())
+        }
+        (TypeDef::List(_), _) => Value::Array(Vec::new()),
+        (TypeDef::Tuple(types), value) => {
+            let mut items = match value {
+                Value::Array(items) => items.into_iter(),
+                _ => Vec::new().into_iter(),
+            };
+            Value::Array(
+                types
+                    .iter()
+                    .map(|ty| coerce(ty, items.next().unwrap_or(Value::Null)))
+                    .collect(),
+            )
+        }
+        (TypeDef::Nullable(_), Value::Null) => Value::Null,
+        (TypeDef::Nullable(inner), value) => coerce(inner, value),
+        (TypeDef::Object(fields), value) => {
+            let mut obj = match value {
+                Value::Object(obj) => obj,
+                _ => serde_json::Map::new(),
+            };
+            Value::Object(
+                fields
+                    .iter()
+                    .map(|field| {
+                        let field_value = obj.remove(field.name.as_ref()).unwrap_or(Value::Null);
+                        (field.name.to_string(), coerce(&field.ty, field_value))
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// Conservative local fixes for output that is almost right, so a retry
+/// isn't spent on it: numbers and booleans written as strings (`"3"`,
+/// `"true"`), whole floats where an integer is expected (`3.0`), enum values
+/// in the wrong case or with stray whitespace (`" High"`), and object
+/// keys the schema doesn't list, which are dropped. Nothing is invented for a
+/// missing or wrongly shaped value.
+///
+/// Returns the repaired value and a description of each fix, or None when
+/// nothing could be fixed. The result may still fail `validate`.
+pub fn repair(ty: &TypeDef, value: &Value) -> Option<(Value, Vec<String>)> {
+    let mut fixes = Vec::new();
+    let repaired = repair_inner(ty, value.clone(), "$", &mut fixes);
+    if fixes.is_empty() {
+        None
+    } else {
+        Some((repaired, fixes))
+    }
+}
+
+fn repair_inner(ty: &TypeDef, value: Value, path: &str, fixes: &mut Vec<String>) -> Value {
+    match (ty, value) {
+        (TypeDef::Number, Value::String(text)) => match text.trim().parse::<serde_json::Number>() {
+            Ok(n) => {
+                fixes.push(format!("{path}: string {text:?} read as number"));
+                Value::Number(n)
+            }
+            Err(_) => Value::String(text),
+        },
+        (TypeDef::Integer | TypeDef::IntegerRange { .. }, Value::String(text)) => match text.trim().parse::<i64>() {
+            Ok(n) => {
+                fixes.push(format!("{path}: string {text:?} read as integer"));
+                Value::from(n)
+            }
+            Err(_) => Value::String(text),
+        },
+        (TypeDef::Integer | TypeDef::IntegerRange { .. }, Value::Number(n)) => match n.as_f64() {
+            Some(f) if !(n.is_i64() || n.is_u64()) && f.fract() == 0.0 && f.abs() < 9.0e15 => {
+                fixes.push(format!("{path}: {n} read as integer"));
+                Value::from(f as i64)
+            }
+            _ => Value::Number(n),
+        },
+        (TypeDef::Bool, Value::String(text)) => match text.trim().to_ascii_lowercase().as_str() {
+            "true" => {
+                fixes.push(format!("{path}: string {text:?} read as boolean"));
+                Value::Bool(true)
+            }
+            "false" => {
+                fixes.push(format!("{path}: string {text:?} read as boolean"));
+                Value::Bool(false)
+            }
+            _ => Value::String(text),
+        },
+        (TypeDef::Enum(values), Value::String(text)) if !values.iter().any(|v| *v == text) => {
+            match values.iter().find(|v| v.eq_ignore_ascii_case(text.trim())) {
+                Some(value) => {
+                    fixes.push(format!("{path}: {text:?} read as {value:?}"));
+                    Value::String(value.to_string())
+                }
+                None => Value::String(text),
+            }
+        }
+        (TypeDef::List(inner), Value::Array(items)) => Value::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(idx, item)| repair_inner(inner, item, &format!("{path}[{idx}]"), fixes))
+                .collect(),
+        ),
+        (TypeDef::Tuple(types), Value::Array(items)) => Value::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(idx, item)| match types.get(idx) {
+                    Some(ty) => repair_inner(ty, item, &format!("{path}[{idx}]"), fixes),
+                    None => item,
+                })
+                .collect(),
+        ),
+        (TypeDef::Nullable(_), Value::Null) => Value::Null,
+        (TypeDef::Nullable(inner), value) => repair_inner(inner, value, path, fixes),
+        (TypeDef::Object(fields), Value::Object(obj)) => {
+            let mut repaired = serde_json::Map::with_capacity(obj.len());
+            for (key, field_value) in obj {
+                match fields.iter().find(|f| f.name == key.as_str()) {
+                    Some(field) => {
+                        let field_path = format!("{path}.{key}");
+                        let field_value = repair_inner(&field.ty, field_value, &field_path, fixes);
+                        repaired.insert(key, field_value);
+                    }
+                    None => fixes.push(format!("{path}.{key}: unknown field dropped")),
+                }
+            }
+            Value::Object(repaired)
+        }
+        (_, value) => value,
+    }
+}
+
+/// Rewrite values where `ty` expects a Bool but the model wrote a common
+/// stand-in: the strings `"true"`/`"false"`, `"yes"`/`"no"`, `"y"`/`"n"`,
+/// `"on"`/`"off"` and `"1"`/`"0"` (any case, surrounding whitespace ignored)
+/// and the numbers `1` and `0`. Anything else, and every value outside a
+/// Bool position, is left alone.
+///
+/// Returns the rewritten value and a description of each change, empty when
+/// nothing was changed.
+pub fn coerce_bools(ty: &TypeDef, value: Value) -> (Value, Vec<String>) {
+    let mut fixes = Vec::new();
+    let value = coerce_bools_inner(ty, value, "$", &mut fixes);
+    (value, fixes)
+}
+
+fn coerce_bools_inner(ty: &TypeDef, value: Value, path: &str, fixes: &mut Vec<String>) -> Value {
+    match (ty, value) {
+        (TypeDef::Bool, value @ (Value::String(_) | Value::Number(_))) => match bool_like(&value) {
+            Some(b) => {
+                fixes.push(format!("{path}: {value} read as {b}"));
+                Value::Bool(b)
+            }
+            None => value,
+        },
+        (TypeDef::List(inner), Value::Array(items)) => Value::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(idx, item)| coerce_bools_inner(inner, item, &format!("{path}[{idx}]"), fixes))
+                .collect(),
+        ),
+        (TypeDef::Tuple(types), Value::Array(items)) => Value::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(idx, item)| match types.get(idx) {
+                    Some(ty) => coerce_bools_inner(ty, item, &format!("{path}[{idx}]"), fixes),
+                    None => item,
+                })
+                .collect(),
+        ),
+        (TypeDef::Nullable(inner), value) if !value.is_null() => coerce_bools_inner(inner, value, path, fixes),
+        (TypeDef::Object(fields), Value::Object(obj)) => Value::Object(
+            obj.into_iter()
+                .map(|(key, field_value)| {
+                    let field_value = match fields.iter().find(|f| f.name == key.as_str()) {
+                        Some(field) => coerce_bools_inner(&field.ty, field_value, &format!("{path}.{key}"), fixes),
+                        None => field_value,
+                    };
+                    (key, field_value)
+                })
+                .collect(),
+        ),
+        (_, value) => value,
+    }
+}
+
+/// The boolean a string or number stands for, see `coerce_bools`.
+fn bool_like(value: &Value) -> Option<bool> {
+    match value {
+        Value::String(text) => match text.trim().to_ascii_lowercase().as_str() {
+            "true" | "yes" | "y" | "on" | "1" => Some(true),
+            "false" | "no" | "n" | "off" | "0" => Some(false),
+            _ => None,
+        },
+        Value::Number(n) => match n.as_f64() {
+            Some(1.0) => Some(true),
+            Some(0.0) => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// One step on the way from the root to a value: an array index or an
+/// object field.
+#[derive(Clone, Copy)]
+enum Segment<'a> {
+    Index(usize),
+    Field(&'a str),
+}
+
+/// Where `validate_inner` is, as the segments from the root. Rendered as a
+/// `$.components[0].id` path only when an error is reported there, so valid
+/// values cost no path allocations at all.
+struct Location<'a> {
+    segments: Vec<Segment<'a>>,
+}
+
+impl<'a> Location<'a> {
+    fn root() -> Self {
+        Self { segments: Vec::new() }
+    }
+
+    fn path(&self) -> String {
+        use std::fmt::Write;
+
+        let mut path = String::from("$");
+        for segment in &self.segments {
+            let _ = match segment {
+                Segment::Index(idx) => write!(path, "[{idx}]"),
+                Segment::Field(name) => write!(path, ".{name}"),
+            };
+        }
+        path
+    }
+}
+
+/// With `first_only`, returns as soon as `errors` is non-empty.
+fn validate_inner<'a>(
+    ty: &'a TypeDef,
+    value: &Value,
+    loc: &mut Location<'a>,
+    first_only: bool,
+    errors: &mut Vec<ValidationError>,
+) {
     use TypeDef::*;
 
     match ty {
         Text | Markdown => {
             if !value.is_string() {
                 errors.push(ValidationError::TypeMismatch {
-                    path: path.to_string(),
+                    path: loc.path(),
                     expected: "string".to_string(),
                     found: value_type_name(value).to_string(),
                 });
@@ -68,39 +867,117 @@ fn validate_inner(ty: &TypeDef, value: &Value, path: &str, errors: &mut Vec<Vali
         Number => {
             if !value.is_number() {
                 errors.push(ValidationError::TypeMismatch {
-                    path: path.to_string(),
+                    path: loc.path(),
                     expected: "number".to_string(),
                     found: value_type_name(value).to_string(),
                 });
             }
         }
+        Integer => {
+            if !(value.is_i64() || value.is_u64()) {
+                errors.push(ValidationError::TypeMismatch {
+                    path: loc.path(),
+                    expected: "integer".to_string(),
+                    found: value_type_name(value).to_string(),
+                });
+            }
+        }
+        IntegerRange { min, max } => {
+            // A u64 beyond i64 is above any max and below no min
+            let in_range = match value.as_i64() {
+                Some(n) => min.is_none_or(|min| n >= min) && max.is_none_or(|max| n <= max),
+                None => value.is_u64() && max.is_none(),
+            };
+            if !in_range {
+                errors.push(ValidationError::TypeMismatch {
+                    path: loc.path(),
+                    expected: integer_name(*min, *max),
+                    found: match value {
+                        Value::Number(n) if value.is_i64() || value.is_u64() => n.to_string(),
+                        other => value_type_name(other).to_string(),
+                    },
+                });
+            }
+        }
         Bool => {
             if !value.is_boolean() {
                 errors.push(ValidationError::TypeMismatch {
-                    path: path.to_string(),
+                    path: loc.path(),
                     expected: "boolean".to_string(),
                     found: value_type_name(value).to_string(),
                 });
             }
         }
+        Enum(values) => {
+            if !value.as_str().is_some_and(|text| values.iter().any(|v| v == text)) {
+                errors.push(ValidationError::TypeMismatch {
+                    path: loc.path(),
+                    expected: format!("one of {}", enum_values(values)),
+                    found: match value {
+                        Value::String(text) => format!("{text:?}"),
+                        other => value_type_name(other).to_string(),
+                    },
+                });
+            }
+        }
         List(inner) => {
             if let Value::Array(items) = value {
                 for (idx, item) in items.iter().enumerate() {
-                    let child_path = format!("{path}[{idx}]");
-                    validate_inner(inner, item, &child_path, errors);
+                    loc.segments.push(Segment::Index(idx));
+                    validate_inner(inner, item, loc, first_only, errors);
+                    loc.segments.pop();
+                    if first_only && !errors.is_empty() {
+                        return;
+                    }
                 }
             } else {
+                // Spell out the element shape so retry feedback is self-contained
+                let expected = match inner.as_ref() {
+                    Object(fields) => {
+                        let names: Vec<&str> = fields.iter().map(|f| f.name.as_ref()).collect();
+                        format!("array of objects with fields [{}]", names.join(", "))
+                    }
+                    _ => "array".to_string(),
+                };
                 errors.push(ValidationError::TypeMismatch {
-                    path: path.to_string(),
-                    expected: "array".to_string(),
+                    path: loc.path(),
+                    expected,
                     found: value_type_name(value).to_string(),
                 });
             }
         }
+        Tuple(types) => {
+            let Some(items) = value.as_array() else {
+                errors.push(ValidationError::TypeMismatch {
+                    path: loc.path(),
+                    expected: format!("array of [{}]", tuple_type_names(types)),
+                    found: value_type_name(value).to_string(),
+                });
+                return;
+            };
+            if items.len() != types.len() {
+                errors.push(ValidationError::TypeMismatch {
+                    path: loc.path(),
+                    expected: format!("array of {} items [{}]", types.len(), tuple_type_names(types)),
+                    found: format!("array of {} items", items.len()),
+                });
+                if first_only {
+                    return;
+                }
+            }
+            for (idx, (ty, item)) in types.iter().zip(items).enumerate() {
+                loc.segments.push(Segment::Index(idx));
+                validate_inner(ty, item, loc, first_only, errors);
+                loc.segments.pop();
+                if first_only && !errors.is_empty() {
+                    return;
+                }
+            }
+        }
         Object(fields) => {
             let Some(obj) = value.as_object() else {
                 errors.push(ValidationError::TypeMismatch {
-                    path: path.to_string(),
+                    path: loc.path(),
                     expected: "object".to_string(),
                     found: value_type_name(value).to_string(),
                 });
@@ -108,24 +985,153 @@ fn validate_inner(ty: &TypeDef, value: &Value, path: &str, errors: &mut Vec<Vali
             };
 
             for field in fields {
-                let field_value = obj.get(field.name);
-                let field_path = format!("{path}.{}", field.name);
+                let field_value = obj.get(field.name.as_ref());
+                loc.segments.push(Segment::Field(&field.name));
 
                 match field_value {
                     None => {
-                        errors.push(ValidationError::MissingField { path: field_path });
+                        errors.push(ValidationError::MissingField { path: loc.path() });
                     }
                     Some(v) => {
-                        validate_inner(&field.ty, v, &field_path, errors);
+                        validate_inner(&field.ty, v, loc, first_only, errors);
                     }
                 }
+                loc.segments.pop();
+                if first_only && !errors.is_empty() {
+                    return;
+                }
             }
 
             // Extra fields are ignored (lenient). Can tighten later.
         }
+        Nullable(inner) => {
+            if value.is_null() {
+                return;
+            }
+            let first_new = errors.len();
+            validate_inner(inner, value, loc, first_only, errors);
+            if errors.len() > first_new {
+                let path = loc.path();
+                for err in &mut errors[first_new..] {
+                    if let ValidationError::TypeMismatch { path: p, expected, .. } = err {
+                        if *p == path {
+                            expected.push_str(" or null");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The schema as a TypeScript type, a compact alternative to the bullet list
+/// in prompts. An Object becomes `interface Output { ... }` with one field
+/// per line and nested objects inline:
+///
+/// ```text
+/// interface Output {
+///   name: string;
+///   components: { id: string; depends_on: string[]; }[];
+/// }
+/// ```
+///
+/// Any other type becomes `type Output = ...;`. Markdown is `string`, and
+/// Integer is `number` marked `/* integer */`, with its range if it has one.
+pub fn schema_to_typescript(ty: &TypeDef) -> String {
+    match ty {
+        TypeDef::Object(fields) => {
+            let mut s = String::from("interface Output {\n");
+            for field in fields {
+                s.push_str(&format!("  {}: {};\n", ts_field_name(&field.name), ts_type(&field.ty)));
+            }
+            s.push('}');
+            s
+        }
+        other => format!("type Output = {};", ts_type(other)),
+    }
+}
+
+fn ts_type(ty: &TypeDef) -> String {
+    match ty {
+        TypeDef::Text | TypeDef::Markdown => "string".to_string(),
+        TypeDef::Number => "number".to_string(),
+        TypeDef::Integer => "number /* integer */".to_string(),
+        TypeDef::IntegerRange { min, max } => format!("number /* {} */", integer_name(*min, *max)),
+        TypeDef::Bool => "boolean".to_string(),
+        TypeDef::Enum(values) => values
+            .iter()
+            .map(|v| Value::String(v.to_string()).to_string())
+            .collect::<Vec<_>>()
+            .join(" | "),
+        TypeDef::List(inner) => match inner.as_ref() {
+            // A union needs parentheses to stay one element type
+            TypeDef::Nullable(_) | TypeDef::Enum(_) => format!("({})[]", ts_type(inner)),
+            _ => format!("{}[]", ts_type(inner)),
+        },
+        TypeDef::Tuple(types) => {
+            let items: Vec<String> = types.iter().map(ts_type).collect();
+            format!("[{}]", items.join(", "))
+        }
+        TypeDef::Object(fields) => {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|f| format!("{}: {};", ts_field_name(&f.name), ts_type(&f.ty)))
+                .collect();
+            format!("{{ {} }}", fields.join(" "))
+        }
+        TypeDef::Nullable(inner) => format!("{} | null", ts_type(inner)),
+    }
+}
+
+/// A field name as a TypeScript property key, quoted unless it is a plain
+/// identifier.
+fn ts_field_name(name: &str) -> String {
+    let mut chars = name.chars();
+    let identifier = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        name.to_string()
+    } else {
+        Value::String(name.to_string()).to_string()
+    }
+}
+
+/// Element types of a tuple as a short list, e.g. `number, number, string`.
+/// Nested arrays and objects are only named, not spelled out.
+pub fn tuple_type_names(types: &[TypeDef]) -> String {
+    let name = |ty: &TypeDef| -> String {
+        match ty {
+            TypeDef::Text => "string".to_string(),
+            TypeDef::Markdown => "string (markdown)".to_string(),
+            TypeDef::Number => "number".to_string(),
+            TypeDef::Integer => "integer".to_string(),
+            TypeDef::IntegerRange { min, max } => integer_name(*min, *max),
+            TypeDef::Bool => "boolean".to_string(),
+            TypeDef::Enum(values) => format!("one of {}", enum_values(values)),
+            TypeDef::List(_) | TypeDef::Tuple(_) => "array".to_string(),
+            TypeDef::Object(_) => "object".to_string(),
+            TypeDef::Nullable(inner) => format!("{} or null", tuple_type_names(std::slice::from_ref(inner))),
+        }
+    };
+    types.iter().map(name).collect::<Vec<_>>().join(", ")
+}
+
+/// An integer type with its bounds, e.g. `integer from 1 to 5` or
+/// `integer >= 0`.
+fn integer_name(min: Option<i64>, max: Option<i64>) -> String {
+    match (min, max) {
+        (Some(min), Some(max)) => format!("integer from {min} to {max}"),
+        (Some(min), None) => format!("integer >= {min}"),
+        (None, Some(max)) => format!("integer <= {max}"),
+        (None, None) => "integer".to_string(),
     }
 }
 
+/// Enum values quoted and comma-separated, e.g. `"high", "medium", "low"`.
+pub fn enum_values(values: &[Cow<'static, str>]) -> String {
+    values.iter().map(|v| format!("{v:?}")).collect::<Vec<_>>().join(", ")
+}
+
 fn value_type_name(v: &Value) -> &'static str {
     match v {
         Value::Null => "null",
@@ -0,0 +1,37 @@
+use anyhow::{anyhow, Result};
+use http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use http::Method;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// CORS for browser gRPC-Web clients on `origins`; `*` allows any origin.
+/// With no origins only same-origin pages can call the service. The server
+/// puts it in front of `tonic_web::GrpcWebLayer`, which translates gRPC-Web
+/// to native gRPC.
+pub fn cors_layer(origins: &[String]) -> Result<CorsLayer> {
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin).map_err(|e| anyhow!("invalid origin {origin}: {e}")))
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::POST])
+        .allow_headers([
+            CONTENT_TYPE,
+            HeaderName::from_static("x-grpc-web"),
+            HeaderName::from_static("x-user-agent"),
+            HeaderName::from_static("grpc-timeout"),
+            HeaderName::from_static("idempotency-key"),
+            HeaderName::from_static("x-request-id"),
+        ])
+        .expose_headers([
+            HeaderName::from_static("grpc-status"),
+            HeaderName::from_static("grpc-message"),
+            HeaderName::from_static("grpc-status-details-bin"),
+        ]))
+}
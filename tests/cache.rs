@@ -0,0 +1,47 @@
+//! Library tests of the idempotency cache: what is remembered, and what
+//! happens once it is full.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use shape_runner::cache::IdempotencyCache;
+
+/// Run `cache.get_or_run(key, ..)` with a run that counts itself in `runs`
+/// and returns `result`.
+async fn run(
+    cache: &IdempotencyCache<u32>,
+    runs: &AtomicUsize,
+    key: &str,
+    result: Result<u32, String>,
+) -> Result<u32, String> {
+    cache
+        .get_or_run(key.to_string(), || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            result
+        })
+        .await
+}
+
+#[tokio::test]
+async fn successes_are_shared_and_errors_run_again() {
+    let cache = IdempotencyCache::new(Duration::from_secs(60), 10);
+    let runs = AtomicUsize::new(0);
+
+    assert_eq!(run(&cache, &runs, "a", Err("down".to_string())).await, Err("down".to_string()));
+    assert_eq!(run(&cache, &runs, "a", Ok(1)).await, Ok(1));
+    assert_eq!(run(&cache, &runs, "a", Ok(2)).await, Ok(1));
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn new_keys_run_uncached_once_full() {
+    let cache = IdempotencyCache::new(Duration::from_secs(60), 1);
+    let runs = AtomicUsize::new(0);
+
+    assert_eq!(run(&cache, &runs, "a", Ok(1)).await, Ok(1));
+    assert_eq!(run(&cache, &runs, "b", Ok(2)).await, Ok(2));
+    assert_eq!(run(&cache, &runs, "b", Ok(3)).await, Ok(3));
+    // The key held before the cache filled up is still served
+    assert_eq!(run(&cache, &runs, "a", Ok(4)).await, Ok(1));
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
@@ -0,0 +1,511 @@
+//! End-to-end tests: the gRPC server wired to the mock LLM, driven through
+//! `ShapeRunnerClientWrapper`.
+//!
+//! Both binaries run as child processes (Cargo builds them for integration
+//! tests) with a cleared environment, so only the variables a test sets
+//! reach them. Each binds port 0 and the test reads the port it got from the
+//! "listening on" line, so tests can run in parallel without racing for
+//! ports. Each test gets a fresh pair; the processes are killed when its
+//! `Stack` is dropped.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use prost::Message;
+use serde_json::Value;
+use shape_runner::client::ShapeRunnerClientWrapper;
+use shape_runner::codec::{MsgPackCodec, ShapeCodec};
+use shape_runner::rpc::shaperunner::shape_runner_client::ShapeRunnerClient;
+use shape_runner::rpc::shaperunner::{DescribeShapeRequest, DescribeShapeResponse, PipelineStage, RunRequest};
+use shape_runner::shape::{
+    FeatureDesignInput, FeatureDesignOutput, FormationInput, FormationOutput, RiskAssessmentOutput, MAX_UNIT_COUNT,
+};
+
+// How long a child process gets to start accepting connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A running mock LLM and server pointed at it.
+struct Stack {
+    mock: Child,
+    server: Child,
+    mock_url: String,
+    server_url: String,
+}
+
+impl Stack {
+    /// Start the mock with `mock_env` (e.g. `MOCK_LLM_MODE`) and the server
+    /// with `server_env`, and wait until both accept connections.
+    async fn start(mock_env: &[(&str, &str)], server_env: &[(&str, &str)]) -> Stack {
+        let mut mock = Command::new(env!("CARGO_BIN_EXE_mock-llm-server"));
+        mock.env_clear().env("MOCK_LLM_PORT", "0").envs(mock_env.iter().copied());
+        let (mock, mock_port) = spawn_listening(mock, "mock-llm-server");
+
+        let mut server = Command::new(env!("CARGO_BIN_EXE_shape-runner"));
+        server
+            .env_clear()
+            .env("SHAPE_RUNNER_ADDR", "127.0.0.1:0")
+            .env("LLM_BASE_URL", format!("http://127.0.0.1:{mock_port}/llm"))
+            .env("LLM_BACKEND", "mock")
+            .envs(server_env.iter().copied());
+        let (server, server_port) = spawn_listening(server, "shape-runner");
+
+        let stack = Stack {
+            mock,
+            server,
+            mock_url: format!("http://127.0.0.1:{mock_port}"),
+            server_url: format!("http://127.0.0.1:{server_port}"),
+        };
+        stack.wait_until_ready().await;
+        stack
+    }
+
+    async fn wait_until_ready(&self) {
+        let started = Instant::now();
+        loop {
+            let mock_up = reqwest::get(format!("{}/stats", self.mock_url)).await.is_ok();
+            let server_up = ShapeRunnerClientWrapper::connect(self.server_url.clone()).await.is_ok();
+            if mock_up && server_up {
+                return;
+            }
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "mock or server did not start in time");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn client(&self) -> ShapeRunnerClientWrapper {
+        ShapeRunnerClientWrapper::connect(self.server_url.clone())
+            .await
+            .expect("failed to connect to server")
+    }
+
+    /// LLM calls the mock has answered so far.
+    async fn mock_attempts(&self) -> u64 {
+        let stats: Value = reqwest::get(format!("{}/stats", self.mock_url))
+            .await
+            .expect("mock /stats failed")
+            .json()
+            .await
+            .expect("mock /stats is not JSON");
+        stats["attempts"].as_u64().expect("mock /stats has no attempts")
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+        let _ = self.mock.kill();
+        let _ = self.server.wait();
+        let _ = self.mock.wait();
+    }
+}
+
+/// Spawn `command` and wait for the port in its "listening on <addr>" line.
+/// The rest of its stdout is drained on a thread so the child never blocks
+/// on a full pipe.
+fn spawn_listening(mut command: Command, name: &str) -> (Child, u16) {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to start {name}: {e}"));
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    let (port_tx, port_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if let Some((_, addr)) = line.split_once(" listening on ") {
+                let port = addr.rsplit(':').next().and_then(|port| port.parse::<u16>().ok());
+                let _ = port_tx.send(port);
+            }
+        }
+    });
+
+    match port_rx.recv_timeout(STARTUP_TIMEOUT) {
+        Ok(Some(port)) => (child, port),
+        outcome => {
+            let _ = child.kill();
+            match outcome {
+                Ok(_) => panic!("{name} printed a listening address without a port"),
+                Err(_) => panic!("{name} did not report a listening address in time"),
+            }
+        }
+    }
+}
+
+/// The stderr of a server started with only `env`, which must exit on its
+/// own within the startup timeout.
+fn startup_failure(env: &[(&str, &str)]) -> String {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_shape-runner"))
+        .env_clear()
+        .env("SHAPE_RUNNER_ADDR", "127.0.0.1:0")
+        .envs(env.iter().copied())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start shape-runner");
+    let started = Instant::now();
+    while server.try_wait().expect("failed to poll shape-runner").is_none() {
+        if started.elapsed() > STARTUP_TIMEOUT {
+            let _ = server.kill();
+            panic!("shape-runner kept running with {env:?}");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let output = server.wait_with_output().expect("failed to read shape-runner output");
+    assert!(!output.status.success(), "shape-runner exited successfully with {env:?}");
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn feature_design_input() -> FeatureDesignInput {
+    FeatureDesignInput::builder()
+        .repo_summary("A task tracker with a REST API and a web frontend.")
+        .constraint("Use PostgreSQL")
+        .build()
+}
+
+fn formation_input(unit_count: u32) -> FormationInput {
+    serde_json::from_value(serde_json::json!({
+        "formation_description": "a circle",
+        "unit_count": unit_count,
+    }))
+    .expect("invalid FormationInput")
+}
+
+#[tokio::test]
+async fn feature_design_round_trip() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = stack.client().await;
+
+    let output: FeatureDesignOutput = client
+        .run_shape_with_timeout("FeatureDesign".to_string(), &feature_design_input(), RUN_TIMEOUT)
+        .await
+        .expect("FeatureDesign failed");
+
+    assert_eq!(output.name, "Task Management & Collaboration System");
+    assert_eq!(output.components.len(), 5);
+    assert!(output.components.iter().any(|c| c.id == "postgres-db"));
+    assert!(!output.risks.is_empty());
+    assert_eq!(stack.mock_attempts().await, 1);
+}
+
+#[tokio::test]
+async fn formation_round_trip() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = stack.client().await;
+
+    let output: FormationOutput = client
+        .run_shape_with_timeout("Formation".to_string(), &formation_input(7), RUN_TIMEOUT)
+        .await
+        .expect("Formation failed");
+
+    assert_eq!(output.coordinates.len(), 7);
+    assert!(output
+        .coordinates
+        .iter()
+        .all(|c| (0.0..=100.0).contains(&c.x) && (0.0..=100.0).contains(&c.y)));
+}
+
+#[tokio::test]
+async fn unrelated_prefixed_env_vars_are_ignored() {
+    let stack = Stack::start(
+        &[("MOCK_LLM_MODE", "always_ok")],
+        &[
+            ("MODEL_PATH", "/models"),
+            ("LLM_API_KEY_FILE", "/run/secrets/llm"),
+            ("BEST_OF_DEFAULT", "2"),
+            ("MIN_SCORE_THRESHOLD", "0.5"),
+        ],
+    )
+    .await;
+    let mut client = stack.client().await;
+
+    let output: FormationOutput = client
+        .run_shape_with_timeout("Formation".to_string(), &formation_input(4), RUN_TIMEOUT)
+        .await
+        .expect("Formation failed");
+
+    assert_eq!(output.coordinates.len(), 4);
+}
+
+#[tokio::test]
+async fn idempotency_key_is_scoped_to_the_input() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = stack.client().await;
+
+    for unit_count in [3, 3, 5] {
+        let output: FormationOutput = client
+            .run_shape_idempotent("Formation".to_string(), &formation_input(unit_count), "key-1", RUN_TIMEOUT)
+            .await
+            .expect("Formation failed");
+        assert_eq!(output.coordinates.len(), unit_count as usize);
+    }
+
+    // The repeat of the 3-unit request was answered from the cache
+    assert_eq!(stack.mock_attempts().await, 2);
+}
+
+#[tokio::test]
+async fn oversized_unit_count_is_rejected_before_prompting() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = stack.client().await;
+
+    let input = FormationInput {
+        canonical: Some("circle".to_string()),
+        ..formation_input(MAX_UNIT_COUNT + 1)
+    };
+    let result: anyhow::Result<FormationOutput> = client
+        .run_shape_with_timeout("Formation".to_string(), &input, RUN_TIMEOUT)
+        .await;
+
+    let err = result.expect_err("a unit_count above the maximum should be rejected");
+    assert!(err.to_string().contains("InvalidArgument"), "unexpected error: {err}");
+    assert_eq!(stack.mock_attempts().await, 0);
+}
+
+#[tokio::test]
+async fn grids_too_small_for_the_units_are_rejected_before_prompting() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = stack.client().await;
+
+    for (grid_width, grid_height, unit_count) in [(0, 8, 1), (8, 0, 1), (2, 2, 5)] {
+        let input = serde_json::json!({
+            "formation_description": "a line",
+            "unit_count": unit_count,
+            "grid_width": grid_width,
+            "grid_height": grid_height,
+        });
+        let result: anyhow::Result<Value> = client
+            .run_shape_with_timeout("FormationGrid".to_string(), &input, RUN_TIMEOUT)
+            .await;
+
+        let err = result.expect_err("a grid that can't hold the units should be rejected");
+        assert!(err.to_string().contains("InvalidArgument"), "unexpected error: {err}");
+    }
+    assert_eq!(stack.mock_attempts().await, 0);
+}
+
+#[tokio::test]
+async fn non_positive_normalize_boxes_are_rejected_before_prompting() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = stack.client().await;
+
+    for (width, height) in [(0.0, 2.0), (2.0, -1.0)] {
+        let input = serde_json::json!({
+            "formation_description": "a circle",
+            "unit_count": 3,
+            "normalize": true,
+            "normalize_box": { "center_x": 0.0, "center_y": 0.0, "width": width, "height": height },
+        });
+        let result: anyhow::Result<FormationOutput> = client
+            .run_shape_with_timeout("Formation".to_string(), &input, RUN_TIMEOUT)
+            .await;
+
+        let err = result.expect_err("a box without a positive size should be rejected");
+        assert!(err.to_string().contains("InvalidArgument"), "unexpected error: {err}");
+    }
+    assert_eq!(stack.mock_attempts().await, 0);
+}
+
+#[tokio::test]
+async fn invalid_json_is_retried() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "fail_n"), ("MOCK_LLM_FAIL_ATTEMPTS", "2")], &[]).await;
+    let mut client = stack.client().await;
+
+    let output: FeatureDesignOutput = client
+        .run_shape_with_timeout("FeatureDesign".to_string(), &feature_design_input(), RUN_TIMEOUT)
+        .await
+        .expect("FeatureDesign failed after retries");
+
+    assert_eq!(output.components.len(), 5);
+    assert_eq!(stack.mock_attempts().await, 3);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_fail")], &[("LLM_MAX_ATTEMPTS", "2")]).await;
+    let mut client = stack.client().await;
+
+    let result: anyhow::Result<FormationOutput> = client
+        .run_shape_with_timeout("Formation".to_string(), &formation_input(5), RUN_TIMEOUT)
+        .await;
+
+    let err = result.expect_err("Formation should fail when every attempt is invalid JSON");
+    assert!(err.to_string().contains("2 attempts"), "unexpected error: {err}");
+    assert_eq!(stack.mock_attempts().await, 2);
+}
+
+#[tokio::test]
+async fn validation_failure_returns_field_errors() {
+    // The mock's design has 5 components, so a minimum of 6 rejects it
+    let stack = Stack::start(
+        &[("MOCK_LLM_MODE", "always_ok")],
+        &[("LLM_MAX_ATTEMPTS", "1"), ("MIN_SCORE_FeatureDesign", "6")],
+    )
+    .await;
+    let mut client = ShapeRunnerClient::connect(stack.server_url.clone())
+        .await
+        .expect("failed to connect to server");
+
+    let input = serde_json::to_value(feature_design_input()).expect("input does not serialize");
+    let response = client
+        .run(RunRequest {
+            shape_id: "FeatureDesign".to_string(),
+            input: MsgPackCodec.encode_value(&input).expect("input does not encode"),
+            ..Default::default()
+        })
+        .await
+        .expect("a validation failure should not be a gRPC error")
+        .into_inner();
+
+    assert!(!response.ok);
+    assert!(response.error.contains("1 attempts"), "unexpected error: {}", response.error);
+    assert_eq!(response.errors.len(), 1, "unexpected errors: {:?}", response.errors);
+    assert_eq!(response.errors[0].path, "$");
+    assert_eq!(response.errors[0].json_pointer, "");
+    assert!(response.errors[0].message.contains("component count"), "{:?}", response.errors[0]);
+}
+
+fn design_then_risks() -> Vec<PipelineStage> {
+    vec![
+        PipelineStage {
+            shape_id: "FeatureDesign".to_string(),
+            ..Default::default()
+        },
+        PipelineStage {
+            shape_id: "RiskAssessment".to_string(),
+            input_field: "design".to_string(),
+            ..Default::default()
+        },
+    ]
+}
+
+#[tokio::test]
+async fn pipeline_scores_the_designed_risks() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = stack.client().await;
+
+    let response = client
+        .run_pipeline(&feature_design_input(), design_then_risks(), RUN_TIMEOUT)
+        .await
+        .expect("RunPipeline failed");
+
+    assert!(response.ok, "unexpected error: {}", response.error);
+    assert_eq!(response.stages.len(), 2);
+    let decode = |output: &[u8]| MsgPackCodec.decode_value(output).expect("output does not decode");
+    let design: FeatureDesignOutput =
+        serde_json::from_value(decode(&response.stages[0].output)).expect("not a FeatureDesignOutput");
+    let assessment: RiskAssessmentOutput =
+        serde_json::from_value(decode(&response.stages[1].output)).expect("not a RiskAssessmentOutput");
+    assert_eq!(assessment.scored_risks.len(), design.risks.len());
+    assert!(assessment.scored_risks.iter().all(|r| r.score == r.likelihood * r.impact));
+    assert_eq!(stack.mock_attempts().await, 2);
+}
+
+#[tokio::test]
+async fn pipeline_stops_at_the_failed_stage() {
+    // The mock's design has 5 components, so a minimum of 6 rejects it
+    let stack = Stack::start(
+        &[("MOCK_LLM_MODE", "always_ok")],
+        &[("LLM_MAX_ATTEMPTS", "1"), ("MIN_SCORE_FeatureDesign", "6")],
+    )
+    .await;
+    let mut client = stack.client().await;
+
+    let response = client
+        .run_pipeline(&feature_design_input(), design_then_risks(), RUN_TIMEOUT)
+        .await
+        .expect("a failed stage should not be a gRPC error");
+
+    assert!(!response.ok);
+    assert!(response.error.starts_with("stage 1 (FeatureDesign) failed"), "unexpected error: {}", response.error);
+    assert_eq!(response.stages.len(), 1);
+    assert!(!response.stages[0].ok);
+    // RiskAssessment never ran
+    assert_eq!(stack.mock_attempts().await, 1);
+}
+
+#[tokio::test]
+async fn pipeline_stages_ignore_best_of() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[("BEST_OF_FeatureDesign", "3")]).await;
+    let mut client = stack.client().await;
+
+    let response = client
+        .run_pipeline(&feature_design_input(), design_then_risks(), RUN_TIMEOUT)
+        .await
+        .expect("RunPipeline failed");
+
+    assert!(response.ok, "unexpected error: {}", response.error);
+    // One call per stage, not three candidates for the design
+    assert_eq!(stack.mock_attempts().await, 2);
+}
+
+#[test]
+fn non_finite_min_score_is_a_startup_error() {
+    for value in ["NaN", "inf", "-inf"] {
+        let stderr = startup_failure(&[("MIN_SCORE_Formation", value)]);
+        assert!(stderr.contains("is not a finite number"), "{value}: unexpected stderr: {stderr}");
+    }
+}
+
+#[tokio::test]
+async fn best_of_is_rejected_for_shapes_without_a_scorer() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+    let mut client = ShapeRunnerClient::connect(stack.server_url.clone())
+        .await
+        .expect("failed to connect to server");
+
+    let input = serde_json::json!({ "resource": "invoices", "operations": ["list invoices"] });
+    let status = client
+        .run(RunRequest {
+            shape_id: "ApiDesign".to_string(),
+            input: MsgPackCodec.encode_value(&input).expect("input does not encode"),
+            best_of: 3,
+            ..Default::default()
+        })
+        .await
+        .expect_err("best_of 3 for ApiDesign should be rejected");
+
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(status.message().contains("has none"), "unexpected message: {}", status.message());
+    assert_eq!(stack.mock_attempts().await, 0);
+}
+
+#[tokio::test]
+async fn grpc_web_request_gets_trailers_in_the_body() {
+    let stack = Stack::start(&[("MOCK_LLM_MODE", "always_ok")], &[]).await;
+
+    // One uncompressed message frame: flag byte, big-endian length, message
+    let message = DescribeShapeRequest {
+        shape_id: "Formation".to_string(),
+    }
+    .encode_to_vec();
+    let mut body = vec![0u8];
+    body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    body.extend_from_slice(&message);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/shaperunner.ShapeRunner/DescribeShape", stack.server_url))
+        .header("content-type", "application/grpc-web+proto")
+        .header("x-grpc-web", "1")
+        .body(body)
+        .send()
+        .await
+        .expect("gRPC-Web request failed");
+    assert_eq!(response.status(), 200);
+    let bytes = response.bytes().await.expect("gRPC-Web response has no body");
+
+    // A message frame, then the trailers frame (flag 0x80)
+    let frame_len = |at: usize| u32::from_be_bytes(bytes[at + 1..at + 5].try_into().unwrap()) as usize;
+    assert_eq!(bytes[0], 0);
+    let message_len = frame_len(0);
+    let described = DescribeShapeResponse::decode(&bytes[5..5 + message_len]).expect("message does not decode");
+    assert_eq!(described.shape_id, "Formation");
+    let trailers_at = 5 + message_len;
+    assert_eq!(bytes[trailers_at], 0x80);
+    let trailers = String::from_utf8_lossy(&bytes[trailers_at + 5..trailers_at + 5 + frame_len(trailers_at)]);
+    assert!(trailers.contains("grpc-status:0"), "unexpected trailers: {trailers}");
+}
@@ -0,0 +1,606 @@
+//! Library tests of the LLM client: prompt rendering and the retry loop,
+//! driven through `MockLlmBackend` so no server or network is involved.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::response::IntoResponse;
+use serde_json::json;
+use shape_runner::backend::MockLlmBackend;
+use shape_runner::cache::PromptCache;
+use shape_runner::llm::{best_of, render_template, CircuitOpen, LlmClient, ValidationFailed};
+use shape_runner::metrics::Metrics;
+use shape_runner::postprocess::formation_input_pipeline;
+use shape_runner::recording::Transcript;
+use shape_runner::scoring::{min_score, CoordinateSpread};
+use shape_runner::types::ValidationError;
+use shape_runner::shape::{
+    feature_design_output_typedef, formation_output_typedef, risk_assessment_output_typedef, FeatureDesignInput,
+    FormationInput, FormationOutput, RiskAssessmentInput,
+};
+
+const FEATURE_DESIGN: &str = include_str!("../examples/feature-design-output.json");
+
+/// A client that answers from `backend` and retries without sleeping.
+fn client(backend: &Arc<MockLlmBackend>) -> LlmClient {
+    LlmClient::new("http://127.0.0.1:9/unused".to_string())
+        .with_llm_backend(backend.clone())
+        .with_backoff(Duration::ZERO, Duration::ZERO)
+}
+
+fn feature_design_input() -> FeatureDesignInput {
+    FeatureDesignInput::builder()
+        .repo_summary("A task tracker with a REST API and a web frontend.")
+        .build()
+}
+
+fn formation_input(unit_count: u32) -> FormationInput {
+    serde_json::from_value(json!({ "formation_description": "a line", "unit_count": unit_count }))
+        .expect("invalid FormationInput")
+}
+
+/// `n` coordinates, as the model would write them.
+fn coordinates(n: usize) -> String {
+    let coordinates: Vec<_> = (0..n).map(|i| json!({ "x": 10 * i, "y": 50 })).collect();
+    json!({ "coordinates": coordinates }).to_string()
+}
+
+#[test]
+fn template_placeholders_are_filled() {
+    let rendered = render_template(
+        "Summarize {{title}} in {{words}} words. Keep {{unknown}} as is.",
+        &json!({ "title": "the release notes", "words": 50 }),
+    );
+    assert_eq!(rendered, "Summarize the release notes in 50 words. Keep {{unknown}} as is.");
+}
+
+#[test]
+fn placeholders_in_input_values_are_not_expanded() {
+    let rendered = render_template(
+        "First: {{a}}. Second: {{x}}.",
+        &json!({ "a": "literal {{x}}", "x": "expanded" }),
+    );
+    assert_eq!(rendered, "First: literal {{x}}. Second: expanded.");
+}
+
+#[test]
+fn unclosed_braces_are_left_as_text() {
+    let rendered = render_template("{{ {{a}} {{b", &json!({ "a": "A", "b": "B" }));
+    assert_eq!(rendered, "{{ A {{b");
+}
+
+#[tokio::test]
+async fn invalid_json_is_retried_with_the_parse_error() {
+    let backend = Arc::new(MockLlmBackend::new(["{\"name\": \"Task", FEATURE_DESIGN]));
+
+    let output = client(&backend)
+        .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+        .await
+        .expect("FeatureDesign failed after a retry");
+
+    assert_eq!(output.components.len(), 5);
+    let prompts = backend.prompts();
+    assert_eq!(prompts.len(), 2);
+    assert_eq!(backend.remaining(), 0);
+    assert!(!prompts[0].contains("not valid JSON"));
+    assert!(prompts[1].contains("Your previous response was not valid JSON. The error was:"), "{}", prompts[1]);
+}
+
+#[tokio::test]
+async fn long_non_ascii_responses_are_previewed_safely() {
+    // Each "—" is 3 bytes, so byte 200 and byte 500 fall inside one
+    let rationale = "—".repeat(400);
+    let mut design: serde_json::Value = serde_json::from_str(FEATURE_DESIGN).expect("example is not JSON");
+    design["rationale"] = json!(rationale);
+    let unparseable = format!("{{\"rationale\": \"{rationale}");
+    let backend = Arc::new(MockLlmBackend::new([unparseable, design.to_string()]));
+
+    let output = client(&backend)
+        .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+        .await
+        .expect("FeatureDesign failed after a retry");
+
+    assert_eq!(output.rationale, rationale);
+    assert_eq!(backend.prompts().len(), 2);
+}
+
+#[tokio::test]
+async fn validation_errors_are_fed_back() {
+    let backend = Arc::new(MockLlmBackend::new([coordinates(2), coordinates(3)]));
+
+    let output = client(&backend)
+        .generate_formation(&formation_input(3), &formation_output_typedef())
+        .await
+        .expect("Formation failed after a retry");
+
+    assert_eq!(output.coordinates.len(), 3);
+    let prompts = backend.prompts();
+    assert_eq!(prompts.len(), 2);
+    assert!(!prompts[0].contains("validation problems"));
+    assert!(prompts[1].contains("Your previous JSON had these validation problems:"), "{}", prompts[1]);
+    assert!(prompts[1].contains("$.coordinates: expected array with exactly 3 items, found array with 2 items"), "{}", prompts[1]);
+}
+
+fn tolerant_formation_input(unit_count: u32, count_tolerance: u32) -> FormationInput {
+    serde_json::from_value(json!({
+        "formation_description": "a line",
+        "unit_count": unit_count,
+        "count_tolerance": count_tolerance,
+    }))
+    .expect("invalid FormationInput")
+}
+
+#[tokio::test]
+async fn counts_within_the_tolerance_are_accepted() {
+    for n in [4, 5, 6] {
+        let backend = Arc::new(MockLlmBackend::new([coordinates(n)]));
+        let output = client(&backend)
+            .generate_formation(&tolerant_formation_input(5, 1), &formation_output_typedef())
+            .await
+            .expect("a count within the tolerance should pass");
+
+        assert_eq!(output.coordinates.len(), n);
+        let prompts = backend.prompts();
+        assert_eq!(prompts.len(), 1);
+        assert!(prompts[0].contains("between 4 and 6 coordinates"), "{}", prompts[0]);
+        assert!(!prompts[0].contains("EXACTLY"), "{}", prompts[0]);
+    }
+}
+
+#[tokio::test]
+async fn counts_outside_the_tolerance_are_fed_back() {
+    let backend = Arc::new(MockLlmBackend::new([coordinates(7), coordinates(6)]));
+
+    client(&backend)
+        .generate_formation(&tolerant_formation_input(5, 1), &formation_output_typedef())
+        .await
+        .expect("Formation failed after a retry");
+
+    let prompts = backend.prompts();
+    assert_eq!(prompts.len(), 2);
+    assert!(
+        prompts[1].contains("$.coordinates: expected array with between 4 and 6 items, found array with 7 items"),
+        "{}",
+        prompts[1]
+    );
+}
+
+#[tokio::test]
+async fn ignore_count_accepts_any_count() {
+    let input: FormationInput = serde_json::from_value(json!({
+        "formation_description": "a line",
+        "unit_count": 5,
+        "ignore_count": true,
+    }))
+    .expect("invalid FormationInput");
+    let backend = Arc::new(MockLlmBackend::new([coordinates(2)]));
+
+    let output = client(&backend)
+        .generate_formation(&input, &formation_output_typedef())
+        .await
+        .expect("ignore_count should accept any count");
+
+    assert_eq!(output.coordinates.len(), 2);
+    let prompts = backend.prompts();
+    assert_eq!(prompts.len(), 1);
+    assert!(!prompts[0].contains("EXACTLY"), "{}", prompts[0]);
+}
+
+#[tokio::test]
+async fn missing_fields_are_fed_back() {
+    let missing_risks = {
+        let mut design: serde_json::Value = serde_json::from_str(FEATURE_DESIGN).expect("example is not JSON");
+        design.as_object_mut().expect("example is not an object").remove("risks");
+        design.to_string()
+    };
+    let backend = Arc::new(MockLlmBackend::new([missing_risks.as_str(), FEATURE_DESIGN]));
+
+    client(&backend)
+        .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+        .await
+        .expect("FeatureDesign failed after a retry");
+
+    let prompts = backend.prompts();
+    assert_eq!(prompts.len(), 2);
+    assert!(prompts[1].contains("- Missing required field at path $.risks"), "{}", prompts[1]);
+}
+
+#[tokio::test]
+async fn request_options_run_as_transforms() {
+    let unsorted = json!({ "coordinates": [{ "x": 30, "y": 50 }, { "x": 10, "y": 0 }] }).to_string();
+    let backend = Arc::new(MockLlmBackend::new([unsorted]));
+    let input: FormationInput = serde_json::from_value(json!({
+        "formation_description": "two points",
+        "unit_count": 2,
+        "normalize": true,
+        "normalize_box": { "center_x": 0.0, "center_y": 0.0, "width": 2.0, "height": 2.0 },
+        "stable_order": true,
+    }))
+    .expect("invalid FormationInput");
+
+    assert_eq!(formation_input_pipeline(&input).names(), ["normalize", "sort"]);
+    let output = client(&backend)
+        .generate_formation(&input, &formation_output_typedef())
+        .await
+        .expect("Formation failed");
+
+    let points: Vec<_> = output.coordinates.iter().map(|c| (c.x, c.y)).collect();
+    assert!(points[0].1 < points[1].1, "not sorted by y: {points:?}");
+    assert!(points.iter().all(|(x, y)| x.abs() <= 1.0 && y.abs() <= 1.0), "not normalized: {points:?}");
+}
+
+fn risk_assessment_input() -> RiskAssessmentInput {
+    let design = serde_json::from_str(FEATURE_DESIGN).expect("example is not a FeatureDesignOutput");
+    RiskAssessmentInput { design }
+}
+
+/// Every risk of `input`'s design with likelihood 2, impact 3 and score 6.
+fn scored_risks(input: &RiskAssessmentInput) -> Vec<serde_json::Value> {
+    input
+        .design
+        .risks
+        .iter()
+        .map(|risk| json!({ "risk": risk, "likelihood": 2, "impact": 3, "score": 6 }))
+        .collect()
+}
+
+#[tokio::test]
+async fn risk_ratings_are_checked_by_the_typedef() {
+    let input = risk_assessment_input();
+    let mut risks = scored_risks(&input);
+    risks[0]["likelihood"] = json!(6);
+    risks[0]["score"] = json!(18);
+    let out_of_range = json!({ "scored_risks": risks }).to_string();
+    let valid = json!({ "scored_risks": scored_risks(&input) }).to_string();
+    let backend = Arc::new(MockLlmBackend::new([out_of_range, valid]));
+
+    client(&backend)
+        .generate_risk_assessment(&input, &risk_assessment_output_typedef())
+        .await
+        .expect("RiskAssessment failed after a retry");
+
+    let prompts = backend.prompts();
+    assert!(prompts[0].contains("integer from 1 to 5"), "range not in the schema: {}", prompts[0]);
+    assert!(
+        prompts[1].contains("Type mismatch at $.scored_risks[0].likelihood: expected integer from 1 to 5, found 6"),
+        "{}",
+        prompts[1]
+    );
+}
+
+#[tokio::test]
+async fn duplicate_and_unknown_risks_are_rejected() {
+    let input = risk_assessment_input();
+    let mut risks = scored_risks(&input);
+    let last = risks.len() - 1;
+    risks[last] = risks[0].clone();
+    risks.push(json!({ "risk": "Meteor strike", "likelihood": 1, "impact": 5, "score": 5 }));
+    let wrong = json!({ "scored_risks": risks }).to_string();
+    let backend = Arc::new(MockLlmBackend::new([wrong]));
+
+    let err = client(&backend)
+        .with_max_attempts(1)
+        .generate_risk_assessment(&input, &risk_assessment_output_typedef())
+        .await
+        .expect_err("a duplicate and an unknown risk should fail");
+
+    let failed = err.downcast_ref::<ValidationFailed>().expect("not a ValidationFailed");
+    let messages: Vec<String> = failed.errors.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        messages,
+        [
+            format!(
+                "Constraint violated at $.scored_risks[{last}].risk: expected a risk not already scored by \
+                 scored_risks[0], found {:?}",
+                input.design.risks[0]
+            ),
+            format!(
+                "Constraint violated at $.scored_risks[{}].risk: expected one of the design's risks, found \"Meteor strike\"",
+                last + 1
+            ),
+            format!(
+                "Constraint violated at $.scored_risks: expected an entry for the risk {:?}, found none",
+                input.design.risks[last]
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_risk_listed_twice_is_reported_missing_once() {
+    let mut input = risk_assessment_input();
+    let first = input.design.risks[0].clone();
+    input.design.risks.push(first.clone());
+    input.design.risks.push(format!("  {}  ", first.to_uppercase()));
+    let mut risks = scored_risks(&risk_assessment_input());
+    risks.remove(0);
+    let missing_first = json!({ "scored_risks": risks }).to_string();
+    let backend = Arc::new(MockLlmBackend::new([missing_first]));
+
+    let err = client(&backend)
+        .with_max_attempts(1)
+        .generate_risk_assessment(&input, &risk_assessment_output_typedef())
+        .await
+        .expect_err("an unscored risk should fail");
+
+    let failed = err.downcast_ref::<ValidationFailed>().expect("not a ValidationFailed");
+    let messages: Vec<String> = failed.errors.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        messages,
+        [format!("Constraint violated at $.scored_risks: expected an entry for the risk {first:?}, found none")]
+    );
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+    let backend = Arc::new(MockLlmBackend::new([coordinates(1), coordinates(2), coordinates(4)]));
+
+    let err = client(&backend)
+        .with_max_attempts(2)
+        .generate_formation(&formation_input(3), &formation_output_typedef())
+        .await
+        .expect_err("Formation should fail when every attempt has the wrong count");
+
+    let failed = err.downcast_ref::<ValidationFailed>().expect("not a ValidationFailed");
+    assert_eq!(failed.attempts, 2);
+    assert_eq!(failed.errors.len(), 1);
+    assert_eq!(failed.errors[0].path(), Some("$.coordinates"));
+    // The third response was never asked for
+    assert_eq!(backend.prompts().len(), 2);
+    assert_eq!(backend.remaining(), 1);
+}
+
+#[tokio::test]
+async fn prompt_cache_hits_skip_the_llm_call_and_its_records() {
+    let backend = Arc::new(MockLlmBackend::new([FEATURE_DESIGN, FEATURE_DESIGN]));
+    let cache = Arc::new(PromptCache::new(Duration::from_secs(60), 1 << 20));
+    let metrics = Arc::new(Metrics::new());
+    let transcript = Arc::new(Transcript::default());
+    let cached = client(&backend)
+        .with_prompt_cache(cache.clone())
+        .with_metrics(metrics.clone())
+        .with_transcript(transcript.clone());
+
+    for _ in 0..2 {
+        cached
+            .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+            .await
+            .expect("FeatureDesign failed");
+    }
+    assert_eq!(backend.prompts().len(), 1);
+    assert_eq!(metrics.snapshot().llm_call_seconds.count, 1);
+    assert_eq!(transcript.take().len(), 1);
+
+    // A different temperature is a different scope
+    client(&backend)
+        .with_prompt_cache(cache)
+        .with_temperature(0.9)
+        .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+        .await
+        .expect("FeatureDesign failed");
+    assert_eq!(backend.prompts().len(), 2);
+}
+
+#[tokio::test]
+async fn best_of_candidates_skip_the_prompt_cache() {
+    let backend = Arc::new(MockLlmBackend::new([FEATURE_DESIGN; 4]));
+    let cache = Arc::new(PromptCache::new(Duration::from_secs(60), 1 << 20));
+    let cached = client(&backend).with_prompt_cache(cache);
+    cached
+        .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+        .await
+        .expect("FeatureDesign failed");
+
+    let generate = |llm: LlmClient| async move {
+        llm.generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+            .await
+    };
+    best_of(&cached, 3, generate, |_| 0.0).await.expect("every candidate failed");
+    // Three fresh generations, not three copies of the cached one
+    assert_eq!(backend.prompts().len(), 4);
+}
+
+#[tokio::test]
+async fn prompt_cache_directory_stays_under_its_size_limit() {
+    let dir = std::env::temp_dir().join(format!("shape-runner-cache-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let response = "x".repeat(400);
+    // Room for two entries of about 500 bytes each, not three
+    let cache = PromptCache::new(Duration::from_secs(60), 1200).with_disk(&dir).expect("cache dir");
+    for prompt in ["first", "second", "third"] {
+        cache.put("scope", prompt, &response).await;
+    }
+    let files = std::fs::read_dir(&dir).expect("cache dir").count();
+    assert_eq!(files, 2);
+
+    // A new cache over the same directory reads what's left
+    let reopened = PromptCache::new(Duration::from_secs(60), 1200).with_disk(&dir).expect("cache dir");
+    assert_eq!(reopened.get("scope", "third").await.as_deref(), Some(response.as_str()));
+    assert_eq!(reopened.get("other scope", "third").await, None);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn min_score_reports_a_constraint_violation() {
+    let hook = min_score(CoordinateSpread, 100.0);
+    let output: FormationOutput = serde_json::from_str(&coordinates(2)).expect("invalid FormationOutput");
+
+    let errors = hook(&output, &formation_input(2));
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(&errors[0], ValidationError::Constraint { path, .. } if path == "$"), "{:?}", errors[0]);
+    assert_eq!(
+        errors[0].to_string(),
+        "Constraint violated at $: expected coordinate spread of at least 100, found coordinate spread 5"
+    );
+}
+
+/// An HTTP endpoint speaking the mock server's API in-process. While
+/// `healthy` is false it stalls past the client's request timeout, which the
+/// client sees as a transport failure. `calls` counts requests received.
+struct FlakyEndpoint {
+    url: String,
+    healthy: Arc<AtomicBool>,
+    calls: Arc<AtomicUsize>,
+}
+
+// Request timeout of clients talking to a `FlakyEndpoint`
+const FLAKY_TIMEOUT: Duration = Duration::from_millis(200);
+
+impl FlakyEndpoint {
+    async fn start() -> FlakyEndpoint {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = axum::Router::new().route(
+            "/llm",
+            axum::routing::post({
+                let (healthy, calls) = (healthy.clone(), calls.clone());
+                move || async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    if !healthy.load(Ordering::SeqCst) {
+                        tokio::time::sleep(FLAKY_TIMEOUT * 5).await;
+                    }
+                    axum::Json(json!({ "output": FEATURE_DESIGN }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let url = format!("http://{}/llm", listener.local_addr().expect("local addr"));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        FlakyEndpoint { url, healthy, calls }
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[tokio::test]
+async fn circuit_opens_after_transport_failures_and_a_probe_closes_it() {
+    let endpoint = FlakyEndpoint::start().await;
+    let cooldown = Duration::from_millis(300);
+    let llm = LlmClient::new(endpoint.url.clone())
+        .with_http_timeouts(FLAKY_TIMEOUT, FLAKY_TIMEOUT)
+        .with_max_attempts(1)
+        .with_circuit_breaker(2, cooldown);
+    let generate = |llm: &LlmClient| {
+        let llm = llm.clone();
+        async move {
+            llm.generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+                .await
+        }
+    };
+
+    for _ in 0..2 {
+        let err = generate(&llm).await.expect_err("the endpoint is stalled");
+        assert!(err.downcast_ref::<CircuitOpen>().is_none(), "opened too early: {err}");
+    }
+    assert_eq!(endpoint.calls(), 2);
+
+    let err = generate(&llm).await.expect_err("the circuit should be open");
+    let open = err.downcast_ref::<CircuitOpen>().expect("not a CircuitOpen");
+    assert_eq!(open.consecutive_failures, 2);
+    assert_eq!(endpoint.calls(), 2, "an open circuit still called the endpoint");
+
+    // A client with endpoints of its own has its own circuit
+    let other = llm.clone().with_endpoints(vec![endpoint.url.clone()]);
+    generate(&other).await.expect_err("the endpoint is still stalled");
+    assert_eq!(endpoint.calls(), 3);
+
+    endpoint.healthy.store(true, Ordering::SeqCst);
+    tokio::time::sleep(cooldown).await;
+    generate(&llm).await.expect("the probe should succeed");
+    generate(&llm).await.expect("the circuit should be closed");
+    assert_eq!(endpoint.calls(), 5);
+}
+
+/// Start an in-process Ollama `/api/generate` endpoint that answers with
+/// `output`, the whole body compressed with `encoding` (`gzip` or `br`) when
+/// one is given. A streamed request gets `output` in several NDJSON chunks.
+async fn ollama_endpoint(output: String, encoding: Option<&'static str>) -> String {
+    let app = axum::Router::new().route(
+        "/api/generate",
+        axum::routing::post(move |axum::Json(request): axum::Json<serde_json::Value>| async move {
+            let body = if request["stream"] == json!(true) {
+                let chars: Vec<char> = output.chars().collect();
+                let mut lines: String = chars
+                    .chunks(100)
+                    .map(|piece| json!({ "response": piece.iter().collect::<String>(), "done": false }).to_string() + "\n")
+                    .collect();
+                lines.push_str(&json!({ "response": "", "done": true }).to_string());
+                lines.push('\n');
+                lines
+            } else {
+                json!({ "response": output, "done": true }).to_string()
+            };
+            let compressed = match encoding {
+                None => return body.into_bytes().into_response(),
+                Some("gzip") => {
+                    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(body.as_bytes()).expect("gzip");
+                    encoder.finish().expect("gzip")
+                }
+                Some(_) => {
+                    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                    encoder.write_all(body.as_bytes()).expect("brotli");
+                    encoder.into_inner()
+                }
+            };
+            ([(http::header::CONTENT_ENCODING, encoding.unwrap_or_default())], compressed).into_response()
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let url = format!("http://{}/api/generate", listener.local_addr().expect("local addr"));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+#[tokio::test]
+async fn compressed_ollama_responses_are_decoded() {
+    for encoding in ["gzip", "br"] {
+        let url = ollama_endpoint(FEATURE_DESIGN.to_string(), Some(encoding)).await;
+        for stream in [false, true] {
+            let output = LlmClient::new(url.clone())
+                .with_max_attempts(1)
+                .with_streaming(stream)
+                .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+                .await
+                .unwrap_or_else(|e| panic!("{encoding}, stream {stream}: {e}"));
+            assert_eq!(output.components.len(), 5, "{encoding}, stream {stream}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn text_after_the_json_object_is_dropped() {
+    // Braces and an escaped quote inside strings don't end the object early
+    let mut design: serde_json::Value = serde_json::from_str(FEATURE_DESIGN).expect("example is not JSON");
+    design["rationale"] = json!("Serves /items/{id} and \\\"quoted {\" text}");
+    let url = ollama_endpoint(format!("{design}\n\nHope this helps! Ask if you need {{more}}.}}"), None).await;
+
+    for stream in [false, true] {
+        let output = LlmClient::new(url.clone())
+            .with_max_attempts(1)
+            .with_streaming(stream)
+            .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+            .await
+            .unwrap_or_else(|e| panic!("stream {stream}: {e}"));
+        assert_eq!(output.rationale, design["rationale"], "stream {stream}");
+    }
+}
+
+#[tokio::test]
+async fn stream_prose_limit_counts_characters() {
+    // 150 characters but 450 bytes of preamble: under the 200-character
+    // limit, so the stream is read on to the JSON that follows
+    let url = ollama_endpoint(format!("{}{FEATURE_DESIGN}", "—".repeat(150)), None).await;
+
+    let output = LlmClient::new(url)
+        .with_max_attempts(1)
+        .with_streaming(true)
+        .generate_feature_design(&feature_design_input(), &feature_design_output_typedef())
+        .await
+        .expect("the stream was cut off before the JSON");
+    assert_eq!(output.components.len(), 5);
+}
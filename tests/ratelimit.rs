@@ -0,0 +1,53 @@
+//! Library tests of the per-IP rate limiter, on a clock the tests advance
+//! through `RateLimiter::check_at`.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use shape_runner::ratelimit::RateLimiter;
+
+const A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+const B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+const C: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+
+#[test]
+fn a_burst_of_per_minute_passes_and_the_next_call_fails() {
+    let limiter = RateLimiter::new(5);
+    let now = Instant::now();
+    for i in 0..5 {
+        assert!(limiter.check_at(A, now), "call {i} of the burst was refused");
+    }
+    assert!(!limiter.check_at(A, now));
+
+    // One token back every 60 / 5 = 12 seconds
+    assert!(limiter.check_at(A, now + Duration::from_secs(12)));
+    assert!(!limiter.check_at(A, now + Duration::from_secs(12)));
+}
+
+#[test]
+fn each_ip_has_its_own_bucket() {
+    let limiter = RateLimiter::new(1);
+    let now = Instant::now();
+    assert!(limiter.check_at(A, now));
+    assert!(!limiter.check_at(A, now));
+    assert!(limiter.check_at(B, now));
+}
+
+#[test]
+fn full_buckets_are_dropped_after_the_cleanup_interval() {
+    let limiter = RateLimiter::new(60);
+    let start = Instant::now();
+    assert!(limiter.check_at(A, start));
+    // By now A has refilled, but a cleanup interval hasn't passed
+    let drained = start + Duration::from_secs(30);
+    for _ in 0..60 {
+        assert!(limiter.check_at(C, drained));
+    }
+    assert_eq!(limiter.tracked_ips(), 2);
+
+    // A is full and dropped, C is still refilling and kept, B is new
+    assert!(limiter.check_at(B, start + Duration::from_secs(61)));
+    assert_eq!(limiter.tracked_ips(), 2);
+    assert!(limiter.check_at(A, start + Duration::from_secs(61)));
+    assert_eq!(limiter.tracked_ips(), 3);
+}
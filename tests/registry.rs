@@ -0,0 +1,29 @@
+//! Library tests of loading data-driven shapes into the registry.
+
+use std::path::PathBuf;
+
+use shape_runner::registry::ShapeRegistry;
+
+#[test]
+fn a_manifest_cannot_replace_a_registered_shape() {
+    let dir = std::env::temp_dir().join(format!("shape-runner-registry-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("temp dir");
+    let manifest = dir.join("shapes.toml");
+    std::fs::write(
+        &manifest,
+        "[[shapes]]\nshape_id = \"FeatureDesign\"\ninput_schema = \"in.json\"\n\
+         output_schema = \"out.json\"\nprompt_template = \"prompt.txt\"\n",
+    )
+    .expect("write manifest");
+
+    let mut registry = ShapeRegistry::builtin();
+    let err = registry.load_manifest(&manifest).expect_err("FeatureDesign is built in");
+    assert!(err.to_string().contains("FeatureDesign, which is already registered"), "{err}");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // Nor can a second manifest replace a shape from the first
+    let example = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/manifest/shapes.toml");
+    registry.load_manifest(&example).expect("example manifest");
+    let err = registry.load_manifest(&example).expect_err("the example shapes are registered");
+    assert!(err.to_string().contains("which is already registered"), "{err}");
+}
@@ -0,0 +1,125 @@
+//! Self-check of the built-in shapes: each shape's example output in
+//! `examples/<shape>-output.json` must pass its output typedef and read into
+//! its output struct. Catches drift between a typedef and its struct, such as
+//! a struct field added without updating the typedef.
+
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use shape_runner::registry::{ShapeRegistry, ShapeSpec};
+use shape_runner::shape::{
+    ApiDesignOutput, FeatureDesignOutput, FormationGridOutput, FormationOutput, RequirementsOutput,
+    RiskAssessmentOutput,
+};
+use shape_runner::types::{validate, TypeDef};
+
+/// `examples/<shape>-output.json`, with the shape ID in kebab case, e.g.
+/// `formation-grid-output.json` for `FormationGrid`.
+fn example_path(shape_id: &str) -> PathBuf {
+    let mut name = String::new();
+    for (i, c) in shape_id.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            name.push('-');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("examples/{name}-output.json"))
+}
+
+fn load_example(shape_id: &str) -> Value {
+    let path = example_path(shape_id);
+    let text = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{shape_id}: failed to read {}: {e}", path.display()));
+    serde_json::from_str(&text).unwrap_or_else(|e| panic!("{shape_id}: {} is not valid JSON: {e}", path.display()))
+}
+
+/// Object keys in `value` that `ty` doesn't declare, as paths. `validate`
+/// ignores extra keys, so a struct field missing from the typedef only shows
+/// up here.
+fn undeclared_keys(ty: &TypeDef, value: &Value, path: &str, found: &mut Vec<String>) {
+    match (ty, value) {
+        (TypeDef::Object(fields), Value::Object(map)) => {
+            for (key, item) in map {
+                match fields.iter().find(|f| f.name == *key) {
+                    Some(field) => undeclared_keys(&field.ty, item, &format!("{path}.{key}"), found),
+                    None => found.push(format!("{path}.{key}")),
+                }
+            }
+        }
+        (TypeDef::List(inner), Value::Array(items)) => {
+            for (i, item) in items.iter().enumerate() {
+                undeclared_keys(inner, item, &format!("{path}[{i}]"), found);
+            }
+        }
+        (TypeDef::Tuple(types), Value::Array(items)) => {
+            for (i, (ty, item)) in types.iter().zip(items).enumerate() {
+                undeclared_keys(ty, item, &format!("{path}[{i}]"), found);
+            }
+        }
+        (TypeDef::Nullable(inner), value) => undeclared_keys(inner, value, path, found),
+        _ => {}
+    }
+}
+
+/// The example for `shape_id` passes the registered output typedef, declares
+/// no keys the typedef doesn't, reads into `T`, and serializes back unchanged
+/// (so `T` has no fields the example lacks).
+fn check_example<T: DeserializeOwned + Serialize>(registry: &ShapeRegistry, shape_id: &str) {
+    let spec = registry.get(shape_id).unwrap_or_else(|| panic!("{shape_id} is not a built-in shape"));
+    let example = load_example(shape_id);
+
+    if let Err(errors) = validate(&spec.output_typedef, &example) {
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        panic!("{shape_id}: example fails the output typedef:\n{}", errors.join("\n"));
+    }
+
+    let mut undeclared = Vec::new();
+    undeclared_keys(&spec.output_typedef, &example, "$", &mut undeclared);
+    assert!(
+        undeclared.is_empty(),
+        "{shape_id}: example has keys the output typedef doesn't declare: {undeclared:?}"
+    );
+
+    let output: T = serde_json::from_value(example.clone())
+        .unwrap_or_else(|e| panic!("{shape_id}: example doesn't deserialize into its struct: {e}"));
+    let round_trip = serde_json::to_value(&output).expect("output serializes");
+    assert_eq!(round_trip, example, "{shape_id}: example changes when read into its struct and written back");
+}
+
+#[test]
+fn builtin_examples_match_typedefs_and_structs() {
+    let registry = ShapeRegistry::builtin();
+    for shape_id in registry.ids() {
+        match shape_id {
+            "FeatureDesign" => check_example::<FeatureDesignOutput>(&registry, shape_id),
+            "Formation" => check_example::<FormationOutput>(&registry, shape_id),
+            "FormationGrid" => check_example::<FormationGridOutput>(&registry, shape_id),
+            "Requirements" => check_example::<RequirementsOutput>(&registry, shape_id),
+            "ApiDesign" => check_example::<ApiDesignOutput>(&registry, shape_id),
+            "RiskAssessment" => check_example::<RiskAssessmentOutput>(&registry, shape_id),
+            other => panic!("no self-check for built-in shape {other}; add an example output and a case here"),
+        }
+    }
+}
+
+#[test]
+fn shape_without_input_typedef_has_no_input_schema() {
+    let mut registry = ShapeRegistry::builtin();
+    for shape_id in registry.ids() {
+        let (input, _) = registry.schemas_for(shape_id).expect("registered shape");
+        assert!(input.is_some(), "{shape_id}: built-in shapes export an input schema");
+    }
+
+    registry.register(ShapeSpec {
+        id: "Untyped".to_string(),
+        input_typedef: None,
+        output_typedef: TypeDef::Text,
+        prompt_template: Some("Say something.".to_string()),
+    });
+    let (input, output) = registry.schemas_for("Untyped").expect("registered shape");
+    assert!(input.is_none());
+    assert!(matches!(output, TypeDef::Text));
+    assert!(registry.schemas_for("Missing").is_none());
+}
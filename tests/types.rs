@@ -0,0 +1,328 @@
+//! Library tests of the type system: `validate` error messages, error
+//! merging, schema conversion and assignability.
+
+use serde_json::json;
+use shape_runner::types::{check_assignable, coerce, merge_errors, validate, FieldDef, TypeDef, ValidationError};
+
+fn messages(ty: &TypeDef, value: serde_json::Value) -> Vec<String> {
+    match validate(ty, &value) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors.iter().map(ToString::to_string).collect(),
+    }
+}
+
+#[test]
+fn integer_rejects_fractions() {
+    assert!(messages(&TypeDef::Integer, json!(3)).is_empty());
+    assert!(messages(&TypeDef::Integer, json!(-3)).is_empty());
+    assert_eq!(
+        messages(&TypeDef::Integer, json!(3.0)),
+        ["Type mismatch at $: expected integer, found number"]
+    );
+}
+
+#[test]
+fn integer_range_is_validated() {
+    let rating = TypeDef::IntegerRange { min: Some(1), max: Some(5) };
+    assert!(messages(&rating, json!(1)).is_empty());
+    assert!(messages(&rating, json!(5)).is_empty());
+    assert_eq!(
+        messages(&rating, json!(6)),
+        ["Type mismatch at $: expected integer from 1 to 5, found 6"]
+    );
+    assert_eq!(
+        messages(&rating, json!(2.5)),
+        ["Type mismatch at $: expected integer from 1 to 5, found number"]
+    );
+
+    let unsigned = TypeDef::IntegerRange { min: Some(0), max: None };
+    assert!(messages(&unsigned, json!(u64::MAX)).is_empty());
+    assert_eq!(messages(&unsigned, json!(-1)), ["Type mismatch at $: expected integer >= 0, found -1"]);
+}
+
+#[test]
+fn integer_range_round_trips_through_json_schema() {
+    let rating = TypeDef::IntegerRange { min: Some(1), max: Some(5) };
+    let schema = rating.to_json_schema();
+    assert_eq!(schema, json!({ "type": "integer", "minimum": 1, "maximum": 5 }));
+    let back = TypeDef::from_json_schema(&schema).expect("schema does not convert back");
+    assert!(matches!(back, TypeDef::IntegerRange { min: Some(1), max: Some(5) }));
+}
+
+#[test]
+fn nullable_enum_round_trips_through_json_schema() {
+    let risk = TypeDef::Nullable(Box::new(TypeDef::Enum(vec!["high".into(), "low".into()])));
+    let schema = risk.to_json_schema();
+    assert_eq!(schema, json!({ "type": ["string", "null"], "enum": ["high", "low", null] }));
+    let back = TypeDef::from_json_schema(&schema).expect("schema does not convert back");
+    assert!(
+        matches!(&back, TypeDef::Nullable(inner) if matches!(&**inner, TypeDef::Enum(values) if values == &["high", "low"])),
+        "unexpected TypeDef: {back:?}"
+    );
+
+    let twice = TypeDef::Nullable(Box::new(risk));
+    assert_eq!(twice.to_json_schema(), schema);
+}
+
+#[test]
+fn coerce_clamps_into_range() {
+    let rating = TypeDef::IntegerRange { min: Some(1), max: Some(5) };
+    assert_eq!(coerce(&rating, json!(9)), json!(5));
+    assert_eq!(coerce(&rating, json!("high")), json!(1));
+}
+
+#[test]
+fn errors_carry_a_json_pointer() {
+    let ty = TypeDef::Object(vec![FieldDef {
+        name: "a/b".into(),
+        ty: TypeDef::List(Box::new(TypeDef::Object(vec![FieldDef {
+            name: "id".into(),
+            ty: TypeDef::Text,
+        }]))),
+    }]);
+    let errors = validate(&ty, &json!({ "a/b": [{ "id": "x" }, { "id": 7 }] })).expect_err("id 7 should fail");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path(), Some("$.a/b[1].id"));
+    assert_eq!(errors[0].json_pointer().as_deref(), Some("/a~1b/1/id"));
+
+    let errors = validate(&TypeDef::Text, &json!(1)).expect_err("1 is not a string");
+    assert_eq!(errors[0].json_pointer().as_deref(), Some(""));
+}
+
+fn field(name: &'static str, ty: TypeDef) -> FieldDef {
+    FieldDef { name: name.into(), ty }
+}
+
+fn list(ty: TypeDef) -> TypeDef {
+    TypeDef::List(Box::new(ty))
+}
+
+#[test]
+fn tuple_messages() {
+    let ty = TypeDef::Tuple(vec![TypeDef::Number, TypeDef::Number, TypeDef::Text]);
+    assert!(messages(&ty, json!([1, 2.5, "a"])).is_empty());
+    assert_eq!(
+        messages(&ty, json!([1, 2])),
+        ["Type mismatch at $: expected array of 3 items [number, number, string], found array of 2 items"]
+    );
+    assert_eq!(
+        messages(&ty, json!([1, 2, "a", 4])),
+        ["Type mismatch at $: expected array of 3 items [number, number, string], found array of 4 items"]
+    );
+    assert_eq!(
+        messages(&ty, json!([1, "a", 3])),
+        [
+            "Type mismatch at $[1]: expected number, found string",
+            "Type mismatch at $[2]: expected string, found number",
+        ]
+    );
+    assert_eq!(
+        messages(&ty, json!({ "a": 1 })),
+        ["Type mismatch at $: expected array of [number, number, string], found object"]
+    );
+}
+
+#[test]
+fn nullable_messages() {
+    let ty = TypeDef::Object(vec![field("note", TypeDef::Nullable(Box::new(TypeDef::Text)))]);
+    assert!(messages(&ty, json!({ "note": null })).is_empty());
+    assert!(messages(&ty, json!({ "note": "kept" })).is_empty());
+    // Nullable still requires the key
+    assert_eq!(messages(&ty, json!({})), ["Missing required field at path $.note"]);
+    assert_eq!(
+        messages(&ty, json!({ "note": 1 })),
+        ["Type mismatch at $.note: expected string or null, found number"]
+    );
+}
+
+#[test]
+fn nested_list_messages() {
+    let ty = list(list(TypeDef::Integer));
+    assert_eq!(
+        messages(&ty, json!([[1, 2], [3, "x"], 4])),
+        [
+            "Type mismatch at $[1][1]: expected integer, found string",
+            "Type mismatch at $[2]: expected array, found number",
+        ]
+    );
+}
+
+#[test]
+fn missing_field_messages() {
+    let cell = TypeDef::Object(vec![field("id", TypeDef::Text), field("n", TypeDef::Integer)]);
+    let ty = TypeDef::Object(vec![field("rows", list(TypeDef::Object(vec![field("cells", list(cell))])))]);
+    assert_eq!(messages(&ty, json!({})), ["Missing required field at path $.rows"]);
+    assert_eq!(
+        messages(&ty, json!({ "rows": [{ "cells": [{ "id": "a" }, { "n": 1 }] }, {}] })),
+        [
+            "Missing required field at path $.rows[0].cells[0].n",
+            "Missing required field at path $.rows[0].cells[1].id",
+            "Missing required field at path $.rows[1].cells",
+        ]
+    );
+    assert_eq!(messages(&ty, json!("x")), ["Type mismatch at $: expected object, found string"]);
+}
+
+fn missing(path: &str) -> ValidationError {
+    ValidationError::MissingField { path: path.to_string() }
+}
+
+#[test]
+fn merged_indices_become_ranges() {
+    let errors: Vec<_> = [0, 1, 2, 5, 7, 8]
+        .iter()
+        .map(|i| missing(&format!("$.components[{i}].id")))
+        .chain([ValidationError::TypeMismatch {
+            path: "$.name".to_string(),
+            expected: "string".to_string(),
+            found: "number".to_string(),
+        }])
+        .collect();
+    assert_eq!(
+        merge_errors(&errors),
+        [
+            "Missing required fields at paths $.components[0..2,5,7..8].id",
+            "Type mismatch at $.name: expected string, found number",
+        ]
+    );
+}
+
+#[test]
+fn different_details_are_not_merged() {
+    let mismatch = |i: usize, found: &str| ValidationError::TypeMismatch {
+        path: format!("$.items[{i}]"),
+        expected: "string".to_string(),
+        found: found.to_string(),
+    };
+    let errors = [mismatch(0, "number"), mismatch(1, "boolean"), mismatch(2, "number")];
+    assert_eq!(
+        merge_errors(&errors),
+        [
+            "Type mismatches at $.items[0,2]: expected string, found number",
+            "Type mismatch at $.items[1]: expected string, found boolean",
+        ]
+    );
+}
+
+#[test]
+fn constraint_violations_merge_on_the_same_message() {
+    let violation = |i: usize, message: &str| ValidationError::Constraint {
+        path: format!("$.coordinates[{i}].col"),
+        message: message.to_string(),
+    };
+    let errors = [
+        violation(0, "expected integer from 0 to 9, found 10"),
+        violation(1, "expected integer from 0 to 9, found 10"),
+        violation(3, "expected integer from 0 to 9, found 12"),
+    ];
+    assert_eq!(errors[0].path(), Some("$.coordinates[0].col"));
+    assert_eq!(
+        merge_errors(&errors),
+        [
+            "Constraints violated at $.coordinates[0..1].col: expected integer from 0 to 9, found 10",
+            "Constraint violated at $.coordinates[3].col: expected integer from 0 to 9, found 12",
+        ]
+    );
+}
+
+#[test]
+fn nested_lists_collapse_the_varying_index() {
+    // Only the inner index varies: one line
+    let errors = [missing("$.a[1].b[0].c"), missing("$.a[1].b[1].c"), missing("$.a[1].b[2].c")];
+    assert_eq!(merge_errors(&errors), ["Missing required fields at paths $.a[1].b[0..2].c"]);
+
+    // Both vary: the inner index is collapsed per outer index
+    let errors = [missing("$.a[0].b[0].c"), missing("$.a[0].b[1].c"), missing("$.a[3].b[4].c")];
+    assert_eq!(
+        merge_errors(&errors),
+        [
+            "Missing required fields at paths $.a[0].b[0..1].c",
+            "Missing required field at path $.a[3].b[4].c",
+        ]
+    );
+}
+
+#[test]
+fn odd_key_names_keep_their_brackets() {
+    // A key named `x[]` or `y[z]` is text, not an index
+    let errors = [missing("$.x[][0].id"), missing("$.x[][1].id"), missing("$.y[z][2].id")];
+    assert_eq!(
+        merge_errors(&errors),
+        [
+            "Missing required fields at paths $.x[][0..1].id",
+            "Missing required field at path $.y[z][2].id",
+        ]
+    );
+
+    // Not grouped with a real index at the same place
+    let errors = [missing("$.x[].id"), missing("$.x[0].id")];
+    assert_eq!(
+        merge_errors(&errors),
+        ["Missing required field at path $.x[].id", "Missing required field at path $.x[0].id"]
+    );
+}
+
+fn enumeration(values: &[&'static str]) -> TypeDef {
+    TypeDef::Enum(values.iter().map(|v| (*v).into()).collect())
+}
+
+#[test]
+fn nullable_assignability() {
+    let note = TypeDef::Nullable(Box::new(TypeDef::Text));
+    assert_eq!(check_assignable(&note, &TypeDef::Text), Ok(()));
+    assert_eq!(check_assignable(&note, &note), Ok(()));
+    // A null would fail a field that doesn't allow it
+    assert_eq!(
+        check_assignable(&TypeDef::Text, &note),
+        Err("$: expected string, found string or null".to_string())
+    );
+}
+
+#[test]
+fn enum_assignability() {
+    let level = enumeration(&["low", "medium", "high"]);
+    assert_eq!(check_assignable(&TypeDef::Text, &level), Ok(()));
+    assert_eq!(check_assignable(&level, &enumeration(&["low", "high"])), Ok(()));
+    assert_eq!(
+        check_assignable(&enumeration(&["low", "high"]), &level),
+        Err("$: \"medium\" is not one of \"low\", \"high\"".to_string())
+    );
+    // Free text may hold any string, not just the listed ones
+    assert!(check_assignable(&level, &TypeDef::Text).is_err());
+}
+
+#[test]
+fn object_assignability() {
+    let target = TypeDef::Object(vec![field("name", TypeDef::Text), field("tags", list(TypeDef::Text))]);
+    let source = TypeDef::Object(vec![
+        field("name", TypeDef::Markdown),
+        field("tags", list(enumeration(&["a", "b"]))),
+        field("extra", TypeDef::Bool),
+    ]);
+    assert_eq!(check_assignable(&target, &source), Ok(()));
+
+    let missing = TypeDef::Object(vec![field("name", TypeDef::Text)]);
+    assert_eq!(check_assignable(&target, &missing), Err("$.tags: missing".to_string()));
+
+    let mistyped = TypeDef::Object(vec![field("name", TypeDef::Text), field("tags", list(TypeDef::Integer))]);
+    assert_eq!(
+        check_assignable(&target, &mistyped),
+        Err("$.tags[]: expected string, found integer".to_string())
+    );
+}
+
+#[test]
+fn integer_range_assignability() {
+    let rating = TypeDef::IntegerRange { min: Some(1), max: Some(5) };
+    assert_eq!(check_assignable(&TypeDef::Integer, &rating), Ok(()));
+    assert_eq!(check_assignable(&TypeDef::Number, &rating), Ok(()));
+    assert_eq!(check_assignable(&rating, &TypeDef::IntegerRange { min: Some(2), max: Some(4) }), Ok(()));
+    assert_eq!(
+        check_assignable(&rating, &TypeDef::IntegerRange { min: Some(0), max: Some(5) }),
+        Err("$: expected integer from 1 to 5, found integer from 0 to 5".to_string())
+    );
+    assert!(check_assignable(&rating, &TypeDef::IntegerRange { min: Some(1), max: None }).is_err());
+    assert!(check_assignable(&rating, &TypeDef::Integer).is_err());
+    // An unbounded range is any integer
+    assert_eq!(check_assignable(&TypeDef::IntegerRange { min: None, max: None }, &TypeDef::Integer), Ok(()));
+}